@@ -0,0 +1,733 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Asynchronous TCP client
+//!
+//! A non-blocking counterpart to `tcp::TcpClient`, built on tokio, for
+//! callers with many requests in flight. Its requests are futures, to be
+//! awaited inside the caller's own runtime, as are the notifications the
+//! server pushes for subscriptions.
+//!
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use serde::Deserialize;
+use serde_json;
+use serde_json::value::Value;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+use {Notification, Params, Request, Response};
+use error::{Error, malformed_response};
+use super::{ids_match, match_batch};
+use super::tcp::DEFAULT_READ_BUFFER_CAPACITY;
+
+/// Notifications read from a connection and not yet taken, and the tasks
+/// waiting to take them
+#[derive(Default)]
+struct NotificationQueue {
+    notifications: VecDeque<Notification>,
+    waiting: Vec<Waker>,
+}
+
+impl NotificationQueue {
+    fn wait(&mut self, waker: &Waker) {
+        if !self.waiting.iter().any(|w| w.will_wake(waker)) {
+            self.waiting.push(waker.clone());
+        }
+    }
+
+    fn wake(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A connection speaking newline-delimited JSON, with anything read past
+/// the end of the last line
+///
+/// An exchange dropped partway leaves what it still had to do here for the
+/// next one, so that the connection stays in step. Notifications are
+/// queued whenever they are read, whoever reads them.
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // The rest of a line whose write was cut short
+    unwritten: Vec<u8>,
+    // How many lines due before the next exchange's belong to dropped ones
+    unanswered: usize,
+    queue: Arc<Mutex<NotificationQueue>>,
+}
+
+impl Connection {
+    /// Takes the next buffered line answering the current exchange, queueing
+    /// the notifications and skipping the answers to dropped exchanges
+    /// before it
+    fn next_response(&mut self) -> Option<Vec<u8>> {
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..end + 1).collect();
+            if let Some(notification) = as_notification(&line) {
+                let mut queue = self.queue.lock().unwrap();
+                queue.notifications.push_back(notification);
+                queue.wake();
+            } else if self.unanswered == 0 {
+                return Some(line);
+            } else {
+                // The answer to a dropped exchange
+                self.unanswered -= 1;
+            }
+        }
+        None
+    }
+
+    /// Reads what has arrived onto the end of the buffer
+    fn fill(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let mut chunk = vec![0; DEFAULT_READ_BUFFER_CAPACITY];
+        let mut read = ReadBuf::new(&mut chunk);
+        match Pin::new(&mut self.stream).poll_read(cx, &mut read) {
+            Poll::Ready(Ok(())) if read.filled().is_empty() => {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof,
+                                       "server closed the connection");
+                Poll::Ready(Err(Error::Io(e)))
+            }
+            Poll::Ready(Ok(())) => {
+                self.buffer.extend_from_slice(read.filled());
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::Io(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Wakes the tasks waiting for notifications, for one to read them in
+    /// place of a reader which has stopped
+    fn stop_reading(&self) {
+        self.queue.lock().unwrap().wake();
+    }
+}
+
+/// The notification a line holds, if it holds one
+fn as_notification(line: &[u8]) -> Option<Notification> {
+    let message: Value = serde_json::from_slice(line).ok()?;
+    let is_notification = message.get("method").is_some() &&
+                          message.get("id").map(Value::is_null).unwrap_or(true);
+    if !is_notification {
+        return None;
+    }
+    serde_json::value::from_value(message).ok()
+}
+
+type ConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+type LockFuture = Pin<Box<dyn Future<Output = OwnedMutexGuard<Connection>> + Send>>;
+
+/// How far an exchange has got
+enum State {
+    Locking(LockFuture),
+    Writing(OwnedMutexGuard<Connection>, usize),
+    Flushing(OwnedMutexGuard<Connection>),
+    Reading(OwnedMutexGuard<Connection>),
+    Failed(Error),
+    Done,
+}
+
+/// Writing one line to a connection, once no other exchange holds it, then
+/// reading one line back, queueing any notifications ahead of it
+///
+/// Dropping an exchange at any point is safe: the next exchange over the
+/// connection finishes writing any line left half written, and skips the
+/// responses to lines whose exchanges were dropped before reading them.
+struct Exchange {
+    line: Vec<u8>,
+    // How much of `line`, from its start, was left by a dropped exchange
+    inherited: usize,
+    state: State,
+}
+
+impl Exchange {
+    fn new(connection: &Arc<AsyncMutex<Connection>>, mut line: Vec<u8>) -> Exchange {
+        line.push(b'\n');
+        Exchange {
+            line,
+            inherited: 0,
+            state: State::Locking(Box::pin(connection.clone().lock_owned())),
+        }
+    }
+
+    fn failed(error: Error) -> Exchange {
+        Exchange {
+            line: vec![],
+            inherited: 0,
+            state: State::Failed(error),
+        }
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        match mem::replace(&mut self.state, State::Done) {
+            // Only what was inherited is left if this line was not started
+            State::Writing(mut guard, written) if written <= self.inherited => {
+                guard.unwritten = self.line[written..self.inherited].to_vec();
+            }
+            State::Writing(mut guard, written) => {
+                guard.unwritten = self.line[written..].to_vec();
+                guard.unanswered += 1;
+            }
+            State::Flushing(mut guard) => guard.unanswered += 1,
+            State::Reading(mut guard) => {
+                guard.unanswered += 1;
+                guard.stop_reading();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Future for Exchange {
+    type Output = Result<Vec<u8>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<u8>, Error>> {
+        let this = self.get_mut();
+        loop {
+            this.state = match mem::replace(&mut this.state, State::Done) {
+                State::Locking(mut lock) => {
+                    match lock.as_mut().poll(cx) {
+                        Poll::Ready(mut guard) => {
+                            // A line left half written goes out first
+                            let mut line = mem::take(&mut guard.unwritten);
+                            this.inherited = line.len();
+                            line.append(&mut this.line);
+                            this.line = line;
+                            State::Writing(guard, 0)
+                        }
+                        Poll::Pending => {
+                            this.state = State::Locking(lock);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                State::Writing(guard, written) if written == this.line.len() => {
+                    State::Flushing(guard)
+                }
+                State::Writing(mut guard, written) => {
+                    match Pin::new(&mut guard.stream).poll_write(cx, &this.line[written..]) {
+                        Poll::Ready(Ok(0)) => {
+                            let e = io::Error::new(io::ErrorKind::WriteZero,
+                                                   "failed to write request");
+                            return Poll::Ready(Err(Error::Io(e)));
+                        }
+                        Poll::Ready(Ok(n)) => State::Writing(guard, written + n),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::Io(e))),
+                        Poll::Pending => {
+                            this.state = State::Writing(guard, written);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                State::Flushing(mut guard) => {
+                    match Pin::new(&mut guard.stream).poll_flush(cx) {
+                        Poll::Ready(Ok(())) => State::Reading(guard),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::Io(e))),
+                        Poll::Pending => {
+                            this.state = State::Flushing(guard);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                State::Reading(mut guard) => {
+                    if let Some(line) = guard.next_response() {
+                        guard.stop_reading();
+                        return Poll::Ready(Ok(line));
+                    }
+                    match guard.fill(cx) {
+                        Poll::Ready(Ok(())) => State::Reading(guard),
+                        Poll::Ready(Err(e)) => {
+                            guard.stop_reading();
+                            return Poll::Ready(Err(e));
+                        }
+                        Poll::Pending => {
+                            this.state = State::Reading(guard);
+                            return Poll::Pending;
+                        }
+                    }
+                }
+                State::Failed(e) => return Poll::Ready(Err(e)),
+                State::Done => panic!("exchange polled after completion"),
+            }
+        }
+    }
+}
+
+/// A connection in progress, from `AsyncClient::connect`
+pub struct Connect {
+    addr: String,
+    stream: ConnectFuture,
+}
+
+impl Future for Connect {
+    type Output = Result<AsyncClient, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<AsyncClient, Error>> {
+        let this = self.get_mut();
+        match this.stream.as_mut().poll(cx) {
+            Poll::Ready(Ok(stream)) => {
+                let queue = Arc::new(Mutex::new(NotificationQueue::default()));
+                let connection = Connection {
+                    stream,
+                    buffer: vec![],
+                    unwritten: vec![],
+                    unanswered: 0,
+                    queue: queue.clone(),
+                };
+                Poll::Ready(Ok(AsyncClient {
+                    addr: this.addr.clone(),
+                    connection: Arc::new(AsyncMutex::new(connection)),
+                    queue,
+                    nonce: Arc::new(AtomicU64::new(0)),
+                }))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::Io(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Parses a line read from the server, keeping it in the error if it is
+/// not JSON of the expected shape
+fn parse<T: Deserialize>(line: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(line)
+        .map_err(|e| malformed_response(e, String::from_utf8_lossy(line).trim_end()))
+}
+
+/// A request in progress, from `AsyncClient::send_request`
+pub struct SendRequest {
+    exchange: Exchange,
+    id: Value,
+}
+
+impl Future for SendRequest {
+    type Output = Result<Response, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Response, Error>> {
+        let this = self.get_mut();
+        let line = match Pin::new(&mut this.exchange).poll(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => return Poll::Pending,
+        };
+        let response: Response = parse(&line)?;
+        if !ids_match(&this.id, &response.id) {
+            return Poll::Ready(Err(Error::NonceMismatch));
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+/// A batch in progress, from `AsyncClient::send_batch`
+pub struct SendBatch {
+    // None for an empty batch, which is answered without a round trip
+    exchange: Option<Exchange>,
+    requests: Vec<Request>,
+}
+
+impl Future for SendBatch {
+    type Output = Result<Vec<Response>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<Response>, Error>> {
+        let this = self.get_mut();
+        let exchange = match this.exchange {
+            Some(ref mut exchange) => exchange,
+            None => return Poll::Ready(Ok(vec![])),
+        };
+        let line = match Pin::new(exchange).poll(cx) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(match_batch(&this.requests, parse(&line)?))
+    }
+}
+
+/// Notifications pushed by the server of an `AsyncClient`, from
+/// `AsyncClient::notifications`
+///
+/// Notifications come in the order they arrived, whichever subscription
+/// they are for; subscribe by sending the subscription request as any
+/// other. They are read while requests wait for their responses as well as
+/// by `recv`, and kept until taken. Handles from one client share these,
+/// each notification going to whichever handle takes it first.
+pub struct Notifications {
+    connection: Arc<AsyncMutex<Connection>>,
+    queue: Arc<Mutex<NotificationQueue>>,
+}
+
+impl Notifications {
+    /// Waits for the next notification
+    ///
+    /// Waiting does not hold up the client's requests. The wait may be
+    /// dropped before it completes, such as by losing a `select!` against a
+    /// shutdown signal, without losing anything: a line it had read part of
+    /// stays buffered for the next wait to finish.
+    pub fn recv(&mut self) -> NextNotification<'_> {
+        NextNotification { notifications: self }
+    }
+}
+
+/// A wait for a notification, from `Notifications::recv`
+pub struct NextNotification<'a> {
+    notifications: &'a mut Notifications,
+}
+
+impl<'a> Future for NextNotification<'a> {
+    type Output = Result<Notification, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Notification, Error>> {
+        let notifications = &mut *self.get_mut().notifications;
+        loop {
+            {
+                let mut queue = notifications.queue.lock().unwrap();
+                if let Some(notification) = queue.notifications.pop_front() {
+                    return Poll::Ready(Ok(notification));
+                }
+                queue.wait(cx.waker());
+            }
+            // A request reading the connection wakes this when it has
+            // queued a notification or stops reading
+            let mut connection = match notifications.connection.try_lock() {
+                Ok(connection) => connection,
+                Err(_) => return Poll::Pending,
+            };
+            // Requests are answered in turn, so no response can be due to
+            // one still waiting, and any read here is stale
+            while connection.next_response().is_some() {}
+            if !notifications.queue.lock().unwrap().notifications.is_empty() {
+                continue;
+            }
+            match connection.fill(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<'a> Drop for NextNotification<'a> {
+    fn drop(&mut self) {
+        // Another wait may need to read in place of this one
+        self.notifications.queue.lock().unwrap().wake();
+    }
+}
+
+/// A non-blocking handle to a remote JSONRPC server over TCP
+///
+/// Requests may be started from many tasks at once; they share one
+/// connection and are exchanged over it one at a time, in the order they
+/// are first polled. A request may be dropped before it completes, such as
+/// by losing a `select!`: the next request finishes sending it if need be,
+/// and skips its response.
+pub struct AsyncClient {
+    addr: String,
+    connection: Arc<AsyncMutex<Connection>>,
+    queue: Arc<Mutex<NotificationQueue>>,
+    nonce: Arc<AtomicU64>,
+}
+
+impl AsyncClient {
+    /// Connects to the server at `addr`, given as `host:port`
+    ///
+    /// The connection must be made, and the client used, inside a tokio
+    /// runtime with IO enabled.
+    pub fn connect(addr: &str) -> Connect {
+        Connect {
+            addr: addr.to_owned(),
+            stream: Box::pin(TcpStream::connect(addr.to_owned())),
+        }
+    }
+
+    /// Sends a request to the server, resolving to the line it answers with
+    ///
+    /// As with `tcp::TcpClient`, a response whose id is not the request's
+    /// gives `Error::NonceMismatch`.
+    pub fn send_request(&self, request: &Request) -> SendRequest {
+        let exchange = match serde_json::to_vec(request) {
+            Ok(line) => Exchange::new(&self.connection, line),
+            Err(e) => Exchange::failed(Error::from(e)),
+        };
+        SendRequest {
+            exchange,
+            id: request.id.clone(),
+        }
+    }
+
+    /// Sends several requests as one JSONRPC batch, resolving to their
+    /// responses in the order of `requests`
+    ///
+    /// This behaves like `Client::send_batch`: each request needs its own
+    /// id, so building the batch advances the nonce once per request, and
+    /// an empty batch resolves immediately without contacting the server.
+    pub fn send_batch(&self, requests: &[Request]) -> SendBatch {
+        let exchange = if requests.is_empty() {
+            None
+        } else {
+            Some(match serde_json::to_vec(requests) {
+                Ok(line) => Exchange::new(&self.connection, line),
+                Err(e) => Exchange::failed(Error::from(e)),
+            })
+        };
+        SendBatch {
+            exchange,
+            requests: requests.to_vec(),
+        }
+    }
+
+    /// A handle for awaiting the notifications the server pushes
+    pub fn notifications(&self) -> Notifications {
+        Notifications {
+            connection: self.connection.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+
+    /// Builds a request
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        let nonce = self.nonce.fetch_add(1, Ordering::SeqCst) + 1;
+        Request {
+            method: name,
+            params: Params::Positional(params),
+            id: From::from(nonce),
+            jsonrpc: None,
+        }
+    }
+
+    /// Accessor for the last-used nonce
+    pub fn last_nonce(&self) -> u64 {
+        self.nonce.load(Ordering::SeqCst)
+    }
+
+    /// Accessor for the address the client is connected to
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::{Future, poll_fn};
+    use std::io::{BufRead, BufReader, Write};
+    use std::pin::Pin;
+    use std::task::Poll;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json;
+    use serde_json::Value;
+
+    use tokio::runtime::{Builder, Runtime};
+
+    use {Notification, Request, Response};
+    use error::Error;
+    use test_utils::{line_server, reply, tcp_server};
+    use super::AsyncClient;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_io().build().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let addr = line_server(|req| reply(req.id, Value::String(req.method)));
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+
+        for method in &["server.ping", "server.banner"] {
+            let req = client.build_request(method.to_string(), vec![]);
+            let response = runtime.block_on(client.send_request(&req)).unwrap();
+            assert_eq!(response.id, req.id);
+            assert_eq!(response.into_result::<String>().unwrap(), *method);
+        }
+        assert_eq!(client.last_nonce(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        let addr = line_server(|req| reply(req.id.clone(), req.id));
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+
+        let handles: Vec<_> = (0..50)
+            .map(|_| {
+                let req = client.build_request("server.ping".to_owned(), vec![]);
+                (req.id.clone(), runtime.spawn(client.send_request(&req)))
+            })
+            .collect();
+        for (id, handle) in handles {
+            let response = runtime.block_on(handle).unwrap().unwrap();
+            assert_eq!(response.result, Some(id));
+        }
+    }
+
+    #[test]
+    fn test_nonce_mismatch() {
+        let addr = line_server(|req| {
+            reply(Value::from(req.id.as_u64().unwrap() + 10), Value::Null)
+        });
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match runtime.block_on(client.send_request(&req)) {
+            Err(Error::NonceMismatch) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_batch() {
+        // Answers each batch in reverse order
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let batch: Vec<Request> = serde_json::from_str(&line.unwrap()).unwrap();
+                let responses: Vec<Response> = batch.into_iter()
+                    .rev()
+                    .map(|req| reply(req.id, Value::String(req.method)))
+                    .collect();
+                writeln!(writer, "{}", serde_json::to_string(&responses).unwrap()).unwrap();
+            }
+        });
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+        let requests: Vec<_> = ["server.ping", "server.banner"]
+            .iter()
+            .map(|m| client.build_request(m.to_string(), vec![]))
+            .collect();
+
+        let responses = runtime.block_on(client.send_batch(&requests)).unwrap();
+        let results: Vec<String> = responses.into_iter()
+            .map(|r| r.into_result().unwrap())
+            .collect();
+        assert_eq!(results, ["server.ping", "server.banner"]);
+        assert!(runtime.block_on(client.send_batch(&[])).unwrap().is_empty());
+    }
+
+    /// Polls `future` once, as a `select!` would before another branch won
+    fn poll_once<F: Future + Unpin>(runtime: &Runtime, future: &mut F) -> Poll<F::Output> {
+        runtime.block_on(poll_fn(|cx| Poll::Ready(Pin::new(&mut *future).poll(cx))))
+    }
+
+    #[test]
+    fn test_dropped_requests() {
+        // Reads nothing for a while, then answers each request in turn
+        let addr = tcp_server(|stream| {
+            thread::sleep(Duration::from_millis(200));
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Request = serde_json::from_str(&line.unwrap()).unwrap();
+                let response = reply(request.id, Value::String(request.method));
+                writeln!(writer, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+            }
+        });
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+
+        // Too large to be written before the server reads, so dropped
+        // partway through writing
+        let big = "ab".repeat(16 * 1024 * 1024);
+        let req = client.build_request("big".to_owned(), vec![Value::String(big)]);
+        let mut request = client.send_request(&req);
+        assert!(poll_once(&runtime, &mut request).is_pending());
+        drop(request);
+
+        // Dropped while finishing the write, or waiting for its response
+        let req = client.build_request("dropped".to_owned(), vec![]);
+        let mut request = client.send_request(&req);
+        assert!(poll_once(&runtime, &mut request).is_pending());
+        drop(request);
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let response = runtime.block_on(client.send_request(&req)).unwrap();
+        assert_eq!(response.id, req.id);
+        assert_eq!(response.into_result::<String>().unwrap(), "server.ping");
+    }
+
+    #[test]
+    fn test_notifications() {
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let status = |status: &str| {
+                format!("{}\n",
+                        json!({"method": "blockchain.scripthash.subscribe",
+                               "params": ["abcd", status]}))
+            };
+
+            // A notification arrives ahead of the subscription's response
+            let request: Request = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            writer.write_all(status("first").as_bytes()).unwrap();
+            let response = reply(request.id, json!("first"));
+            writeln!(writer, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+
+            // The next is written in two parts, the first before the wait
+            // reading it is dropped
+            let second = status("second");
+            let (start, end) = second.split_at(20);
+            writer.write_all(start.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            writer.write_all(end.as_bytes()).unwrap();
+            writer.write_all(status("third").as_bytes()).unwrap();
+
+            let request: Request = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            let response = reply(request.id, Value::String(request.method));
+            writeln!(writer, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+        });
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+        let mut notifications = client.notifications();
+
+        let req = client.build_request("blockchain.scripthash.subscribe".to_owned(),
+                                       vec![Value::from("abcd")]);
+        let response = runtime.block_on(client.send_request(&req)).unwrap();
+        assert_eq!(response.into_result::<String>().unwrap(), "first");
+        let status = |notification: Notification| notification.params[1].clone();
+        assert_eq!(status(runtime.block_on(notifications.recv()).unwrap()), json!("first"));
+
+        thread::sleep(Duration::from_millis(50));
+        let mut next = notifications.recv();
+        assert!(poll_once(&runtime, &mut next).is_pending());
+        drop(next);
+
+        assert_eq!(status(runtime.block_on(notifications.recv()).unwrap()), json!("second"));
+        assert_eq!(status(runtime.block_on(notifications.recv()).unwrap()), json!("third"));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let response = runtime.block_on(client.send_request(&req)).unwrap();
+        assert_eq!(response.into_result::<String>().unwrap(), "server.ping");
+    }
+
+    #[test]
+    fn test_server_closes() {
+        let addr = tcp_server(|_| {});
+        let runtime = runtime();
+        let client = runtime.block_on(AsyncClient::connect(&addr)).unwrap();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(runtime.block_on(client.send_request(&req)).is_err());
+    }
+}
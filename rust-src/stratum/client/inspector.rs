@@ -0,0 +1,49 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Inspectors
+//!
+//! Hooks seeing the exact JSON a `Client` sends and receives, for
+//! diagnosing protocol problems
+//!
+
+/// Sees each request body a client sends and each response body it
+/// receives; see `Client::set_inspector` and `TcpClient::with_inspector`
+///
+/// The hooks are called on the thread sending the request, once per
+/// attempt, so a resent request is seen again. A response body is seen
+/// before it is parsed, so a malformed one is seen too.
+pub trait Inspector: Send + Sync {
+    /// Called with a request body just before it is sent
+    fn on_request(&self, json: &str);
+
+    /// Called with a response body just after it is received
+    fn on_response(&self, json: &str);
+}
+
+/// An inspector logging each body with the `log` crate, at trace level
+#[cfg(feature = "logging")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogInspector;
+
+#[cfg(feature = "logging")]
+impl Inspector for LogInspector {
+    fn on_request(&self, json: &str) {
+        trace!("request: {}", json);
+    }
+
+    fn on_response(&self, json: &str) {
+        trace!("response: {}", json);
+    }
+}
@@ -0,0 +1,2526 @@
+// Rust JSON-RPC Library
+// Written in 2015 by
+//     Andrew Poelstra <apoelstra@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Client support
+//!
+//! Support for connecting to JSONRPC servers over HTTP, sending requests,
+//! and parsing responses. Servers speaking newline-delimited JSON over raw
+//! TCP are reached with `tcp::TcpClient` instead, or over TLS with
+//! `tls::TlsClient` when the `tls` feature is enabled. With the `async`
+//! feature, `async_tcp::AsyncClient` reaches them without blocking. A
+//! `Client` can also be given another `transport::Transport` in place of
+//! HTTP, such as `transport::MockTransport` for tests, and an
+//! `inspector::Inspector` to see the JSON it sends and receives.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use hyper;
+use hyper::client::Client as HyperClient;
+use hyper::client::pool::Pool;
+use hyper::header::{Headers, Authorization, Basic};
+use hyper::net::{HttpStream, NetworkConnector};
+use hyper::status::StatusCode;
+
+use serde::Deserialize;
+use serde::de::{self, DeserializeSeed, Deserializer, SeqVisitor, Visitor};
+use serde_json;
+use serde_json::Map;
+use serde_json::value::Value;
+
+use super::{Params, Request, Response};
+use clock::{Clock, SystemClock};
+use electrum::{ProtocolDowngrade, ProtocolVersion, ServerLimits};
+use error::{Error, RpcError, StandardError, malformed_response};
+use metrics::{MetricsSink, RequestMetric};
+use retry::{RetryBudget, RetryPolicy, is_connection_error};
+
+#[cfg(feature = "async")]
+pub mod async_tcp;
+pub mod inspector;
+mod socks5;
+pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+
+use self::inspector::Inspector;
+use self::transport::Transport;
+
+/// The method refused by read-only clients
+const BROADCAST_METHOD: &str = "blockchain.transaction.broadcast";
+
+/// The byte order mark some proxies put at the start of a response body
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// The read timeout used by clients created with `Client::with_defaults`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A check run on each response before it is accepted, which rejects the
+/// response by returning an error; see `Client::with_response_validator`
+pub type ResponseValidator = Box<dyn Fn(&Response) -> Result<(), Error> + Send + Sync>;
+
+/// Called with the method and duration of each request slower than a
+/// client's threshold; see `Client::with_slow_request_threshold`
+pub type SlowRequestCallback = Box<dyn Fn(&str, Duration) + Send + Sync>;
+
+/// Called with each downgrade made by a client with adaptive downgrade
+/// enabled; see `Client::with_adaptive_downgrade`
+pub type DowngradeListener = Box<dyn Fn(&ProtocolDowngrade) + Send + Sync>;
+
+/// When an adaptive client downgrades, and how close it is to doing so
+struct AdaptiveDowngrade {
+    threshold: usize,
+    misses: Mutex<usize>,
+    listener: DowngradeListener,
+}
+
+/// How many requests a client lets be in flight at once, and how many are
+struct ConcurrencyLimit {
+    max: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+}
+
+impl ConcurrencyLimit {
+    /// Waits until fewer than `max` requests are in flight, then counts one
+    /// more until the returned permit is dropped
+    fn acquire(&self) -> Permit<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= self.max {
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        Permit(self)
+    }
+}
+
+/// A request counted against a `ConcurrencyLimit`
+struct Permit<'a>(&'a ConcurrencyLimit);
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.0.in_flight.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// What a dry-run client answers with, and what it was asked
+#[derive(Clone)]
+struct DryRun {
+    results: HashMap<String, Value>,
+    // Shared between clones, which record into one list
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+/// Puts the responses to a batch in the order of its requests, giving
+/// `Error::UnexpectedBatchId` for any response matching no request or one
+/// already answered
+pub(crate) fn match_batch(requests: &[Request],
+                          responses: Vec<Response>)
+                          -> Result<Vec<Response>, Error> {
+    let mut slots: Vec<Option<Response>> = requests.iter().map(|_| None).collect();
+    for response in responses {
+        let slot = requests.iter()
+            .position(|r| ids_match(&r.id, &response.id))
+            .map(|i| &mut slots[i])
+            .filter(|slot| slot.is_none())
+            .ok_or_else(|| Error::UnexpectedBatchId(response.id.clone()))?;
+        *slot = Some(response);
+    }
+    Ok(slots.into_iter().flatten().collect())
+}
+
+/// Whether a response with `response_id` answers a request with
+/// `request_id`
+///
+/// Ids match if they are equal, or if one is an integer and the other a
+/// string holding exactly its decimal form, as some proxies rewrite ids to
+/// strings: `3` matches `"3"`, but not `"03"`, `"3.0"` or `" 3"`. No other
+/// coercion is made, so a response still cannot answer a request whose
+/// nonce it does not carry.
+pub fn ids_match(request_id: &Value, response_id: &Value) -> bool {
+    match (request_id, response_id) {
+        (Value::Number(n), Value::String(s)) | (Value::String(s), Value::Number(n)) => {
+            (n.is_u64() || n.is_i64()) && n.to_string() == *s
+        }
+        _ => request_id == response_id,
+    }
+}
+
+/// The ids of those of `requests` which none of `responses` answers
+///
+/// Servers may truncate a batch reply, which `Client::send_batch` does not
+/// treat as an error; this finds what was left out, in request order.
+pub fn missing_ids(requests: &[Request], responses: &[Response]) -> Vec<Value> {
+    requests.iter()
+        .filter(|request| !responses.iter().any(|response| ids_match(&request.id, &response.id)))
+        .map(|request| request.id.clone())
+        .collect()
+}
+
+/// Builds a `Client` from the options most clients set up front
+///
+/// Anything else is set on the built client with its `with_` methods.
+pub struct ClientBuilder {
+    url: String,
+    user: Option<String>,
+    pass: Option<String>,
+    timeout: Option<Duration>,
+    proxy: Option<SocketAddr>,
+    retry_policy: RetryPolicy,
+}
+
+impl ClientBuilder {
+    /// Starts building a client of the server at `url`, with no
+    /// authentication, timeout or proxy, and the default retry policy
+    pub fn new(url: String) -> ClientBuilder {
+        ClientBuilder {
+            url,
+            user: None,
+            pass: None,
+            timeout: None,
+            proxy: None,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Replaces the URL of the server
+    pub fn url(mut self, url: String) -> ClientBuilder {
+        self.url = url;
+        self
+    }
+
+    /// Authenticates with HTTP basic authentication as `user`
+    pub fn basic_auth(mut self, user: String, pass: Option<String>) -> ClientBuilder {
+        self.user = Some(user);
+        self.pass = pass;
+        self
+    }
+
+    /// Sets the timeout, as with `Client::with_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Connects through a SOCKS5 proxy, as with `Client::with_socks5_proxy`
+    pub fn proxy(mut self, proxy: SocketAddr) -> ClientBuilder {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the retry policy, as with `Client::with_retry_policy`
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> ClientBuilder {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Builds the client
+    pub fn build(self) -> Client {
+        Client {
+            url: self.url,
+            user: self.user,
+            pass: self.pass,
+            client: Arc::new(hyper_client(self.timeout, self.proxy)),
+            nonce: Arc::new(AtomicU64::new(0)),
+            nonce_modulus: None,
+            timeout: self.timeout,
+            first_byte_timeout: None,
+            read_timeout: None,
+            proxy: self.proxy,
+            method_timeouts: HashMap::new(),
+            metrics: None,
+            slow_requests: None,
+            validators: vec![],
+            read_only: false,
+            concurrency_limit: None,
+            retry_policy: self.retry_policy,
+            jsonrpc_version: None,
+            protocol_version: Arc::new(Mutex::new(None)),
+            adaptive_downgrade: None,
+            server_limits: ServerLimits::default(),
+            dry_run: None,
+            clock: Arc::new(SystemClock),
+            transport: None,
+            inspector: None,
+        }
+    }
+}
+
+/// A handle to a remote JSONRPC server
+///
+/// Clones are cheap and share what the original has going with the server:
+/// the nonce counter, so that requests built from any clone get distinct
+/// nonces, the pool of HTTP connections, the concurrency limit and the
+/// recorded protocol version. Settings changed on a clone apply to it alone.
+#[derive(Clone)]
+pub struct Client {
+    url: String,
+    user: Option<String>,
+    pass: Option<String>,
+    client: Arc<HyperClient>,
+    nonce: Arc<AtomicU64>,
+    nonce_modulus: Option<u64>,
+    timeout: Option<Duration>,
+    first_byte_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    proxy: Option<SocketAddr>,
+    // Hyper only takes timeouts per client, so each per-method timeout
+    // override gets its own client
+    method_timeouts: HashMap<String, (Duration, Arc<HyperClient>)>,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    slow_requests: Option<(Duration, Arc<SlowRequestCallback>)>,
+    validators: Vec<Arc<ResponseValidator>>,
+    read_only: bool,
+    concurrency_limit: Option<Arc<ConcurrencyLimit>>,
+    retry_policy: RetryPolicy,
+    jsonrpc_version: Option<String>,
+    // Behind a lock since adaptive downgrade changes it mid-session
+    protocol_version: Arc<Mutex<Option<ProtocolVersion>>>,
+    adaptive_downgrade: Option<Arc<AdaptiveDowngrade>>,
+    server_limits: ServerLimits,
+    dry_run: Option<DryRun>,
+    clock: Arc<dyn Clock>,
+    transport: Option<Arc<dyn Transport>>,
+    inspector: Option<Arc<dyn Inspector>>,
+}
+
+impl Client {
+    /// Creates a new client
+    ///
+    /// The client has no timeout, so a server which never replies will block
+    /// `send_request` forever; use `with_defaults` or `with_timeout` to avoid this.
+    /// `ClientBuilder` gives the same client more readably.
+    pub fn new(url: String, user: Option<String>, pass: Option<String>) -> Client {
+        // Check that if we have a password, we have a username; other way around is ok
+        debug_assert!(pass.is_none() || user.is_some());
+
+        let builder = ClientBuilder::new(url);
+        match user {
+            Some(user) => builder.basic_auth(user, pass),
+            None => builder,
+        }.build()
+    }
+
+    /// Creates a new client using the default profile, which applies
+    /// `DEFAULT_TIMEOUT` unless overridden with `with_timeout`
+    pub fn with_defaults(url: String, user: Option<String>, pass: Option<String>) -> Client {
+        Client::new(url, user, pass).with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    /// Creates a client which sends its requests through `transport`
+    /// rather than over HTTP, such as a `transport::MockTransport` in tests
+    ///
+    /// Everything above the transport still applies, from request ids and
+    /// batching to retries and validators; settings which only concern HTTP,
+    /// such as timeouts and proxies, have no effect.
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Client {
+        let mut client = ClientBuilder::new(String::new()).build();
+        client.transport = Some(Arc::new(transport));
+        client
+    }
+
+    /// Sets the timeout used for requests, which bounds connecting to the
+    /// server as well as each read and write
+    ///
+    /// Requests which time out fail with `Error::Timeout`, unless a more
+    /// specific timeout set by `with_first_byte_timeout` or
+    /// `with_read_timeout` applies.
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = Some(timeout);
+        self.rebuild_client();
+        self
+    }
+
+    /// Connects to the server through the SOCKS5 proxy at `proxy`, such as
+    /// a local Tor daemon, which resolves the server's host itself
+    ///
+    /// Gives `Error::Proxy` for requests the proxy cannot pass on.
+    pub fn with_socks5_proxy(mut self, proxy: SocketAddr) -> Client {
+        self.proxy = Some(proxy);
+        self.rebuild_client();
+        self
+    }
+
+    /// Replaces the hyper clients, the main one and those for methods with
+    /// their own timeouts, with ones built from the current timeouts and
+    /// proxy
+    fn rebuild_client(&mut self) {
+        self.client = Arc::new(self.build_hyper_client(self.timeout));
+        let method_clients: Vec<(String, Duration)> = self.method_timeouts
+            .iter()
+            .map(|(method, &(timeout, _))| (method.clone(), timeout))
+            .collect();
+        for (method, timeout) in method_clients {
+            let client = Arc::new(self.build_hyper_client(Some(timeout)));
+            self.method_timeouts.insert(method, (timeout, client));
+        }
+    }
+
+    /// A hyper client with `timeout` and the current first byte timeout and
+    /// proxy
+    fn build_hyper_client(&self, timeout: Option<Duration>) -> HyperClient {
+        let mut client = hyper_client(timeout, self.proxy);
+        if let Some(first_byte_timeout) = self.first_byte_timeout {
+            client.set_read_timeout(Some(first_byte_timeout));
+        }
+        client
+    }
+
+    /// Accessor for the URL requests are posted to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Accessor for the SOCKS5 proxy, if one is set
+    pub fn socks5_proxy(&self) -> Option<SocketAddr> {
+        self.proxy
+    }
+
+    /// Sets how long to wait for the server to start responding, after which
+    /// requests fail with `Error::FirstByteTimeout`
+    ///
+    /// This also bounds each individual read of the response body, and
+    /// replaces the read part of any timeout set by `with_timeout`.
+    pub fn with_first_byte_timeout(mut self, timeout: Duration) -> Client {
+        self.first_byte_timeout = Some(timeout);
+        self.rebuild_client();
+        self
+    }
+
+    /// Sets how long reading a response body may take in total, after which
+    /// requests fail with `Error::ReadTimeout`
+    ///
+    /// This distinguishes a server sending a large response slowly from one
+    /// which never starts responding. The deadline is checked between reads,
+    /// so a single stalled read is only bounded by the first byte timeout.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Client {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Overrides the timeout for requests to `method`, e.g. to allow a slow
+    /// method more time than the timeout set by `with_timeout`
+    ///
+    /// A first byte timeout set by `with_first_byte_timeout` still applies.
+    pub fn with_method_timeout(mut self, method: &str, timeout: Duration) -> Client {
+        let client = Arc::new(self.build_hyper_client(Some(timeout)));
+        self.method_timeouts.insert(method.to_owned(), (timeout, client));
+        self
+    }
+
+    /// Reports a metric for every request to `sink`
+    pub fn with_metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Client {
+        self.metrics = Some(sink);
+        self
+    }
+
+    /// Shows `inspector` the body of every request just before it is sent,
+    /// and of every response just after it is received
+    ///
+    /// Responses to `send_request_streaming` are read whole rather than
+    /// parsed as they arrive while an inspector is set, so that it sees them.
+    pub fn set_inspector(&mut self, inspector: Arc<dyn Inspector>) {
+        self.inspector = Some(inspector);
+    }
+
+    /// Calls `callback` with the method and duration of every request which
+    /// takes at least `threshold`
+    ///
+    /// The callback runs once the request is over, whether it succeeded,
+    /// failed or timed out, on the thread that sent it; like a metrics sink,
+    /// it should be cheap. Each request of a batch is reported with the
+    /// duration of the whole batch.
+    pub fn with_slow_request_threshold<F>(mut self, threshold: Duration, callback: F) -> Client
+        where F: Fn(&str, Duration) + Send + Sync + 'static
+    {
+        self.slow_requests = Some((threshold, Arc::new(Box::new(callback))));
+        self
+    }
+
+    /// Adds a check which every response must pass before `send_request`
+    /// returns it
+    ///
+    /// Validators run in the order they were added, on responses with an
+    /// error as well as on those with a result, and the first error one
+    /// returns is returned in place of the response. `Error::ResponseRejected`
+    /// suits validators with no more specific error to give. Dry-run
+    /// responses are not validated.
+    pub fn with_response_validator<F>(mut self, validator: F) -> Client
+        where F: Fn(&Response) -> Result<(), Error> + Send + Sync + 'static
+    {
+        self.validators.push(Arc::new(Box::new(validator)));
+        self
+    }
+
+    /// Makes the client refuse to broadcast transactions, failing such
+    /// requests with `Error::ReadOnly` before anything is sent
+    ///
+    /// This is a safety rail for watch-only wallets and monitoring tools.
+    pub fn with_read_only(mut self, read_only: bool) -> Client {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether the client refuses to broadcast transactions
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Puts the client in dry-run mode, in which requests are recorded
+    /// rather than sent and answered with a null result
+    ///
+    /// The recorded requests are available from `dry_run_requests`. This is
+    /// meant for unit testing code which builds requests.
+    pub fn with_dry_run(mut self) -> Client {
+        if self.dry_run.is_none() {
+            self.dry_run = Some(DryRun {
+                results: HashMap::new(),
+                requests: Arc::new(Mutex::new(vec![])),
+            });
+        }
+        self
+    }
+
+    /// Puts the client in dry-run mode, answering requests to `method` with
+    /// `result`
+    pub fn with_dry_run_result(self, method: &str, result: Value) -> Client {
+        let mut client = self.with_dry_run();
+        if let Some(ref mut dry_run) = client.dry_run {
+            dry_run.results.insert(method.to_owned(), result);
+        }
+        client
+    }
+
+    /// The requests a dry-run client would have sent, in order
+    pub fn dry_run_requests(&self) -> Vec<Request> {
+        match self.dry_run {
+            Some(ref dry_run) => dry_run.requests.lock().unwrap().clone(),
+            None => vec![],
+        }
+    }
+
+    /// Records the protocol version negotiated with the server
+    ///
+    /// With the `logging` feature, calls to methods this version deprecates
+    /// log a warning; they are still sent, since the server may support them.
+    pub fn with_protocol_version(mut self, version: ProtocolVersion) -> Client {
+        self.protocol_version = Arc::new(Mutex::new(Some(version)));
+        self
+    }
+
+    /// Accessor for the negotiated protocol version, if one was recorded
+    ///
+    /// With adaptive downgrade enabled this may be older than the version
+    /// originally recorded.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        *self.protocol_version.lock().unwrap()
+    }
+
+    /// Makes the client downgrade its recorded protocol version by one minor
+    /// version after `threshold` responses rejecting methods as not found,
+    /// calling `listener` with each downgrade
+    ///
+    /// Some servers claim a protocol version without supporting all of its
+    /// methods; with this enabled, helpers which shape requests by protocol
+    /// version, such as `block_header`, fall back to older methods instead
+    /// of failing the same way forever. Methods the recorded version
+    /// deprecates are expected to be missing and do not count, nor does
+    /// anything while no version is recorded. The count restarts after each
+    /// downgrade, and the version never goes below `x.0`.
+    pub fn with_adaptive_downgrade<F>(mut self, threshold: usize, listener: F) -> Client
+        where F: Fn(&ProtocolDowngrade) + Send + Sync + 'static
+    {
+        assert!(threshold > 0, "downgrade threshold must be nonzero");
+        self.adaptive_downgrade = Some(Arc::new(AdaptiveDowngrade {
+            threshold,
+            misses: Mutex::new(0),
+            listener: Box::new(listener),
+        }));
+        self
+    }
+
+    /// Sets the clock used by helpers which wait or compare times, such as
+    /// `broadcast_and_confirm`
+    ///
+    /// Socket timeouts always use real time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Client {
+        self.clock = clock;
+        self
+    }
+
+    /// Accessor for the client's clock
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// Records the limits the server advertises, which helpers sending many
+    /// requests then check before sending anything
+    pub fn with_server_limits(mut self, limits: ServerLimits) -> Client {
+        self.server_limits = limits;
+        self
+    }
+
+    /// Accessor for the recorded server limits
+    pub fn server_limits(&self) -> ServerLimits {
+        self.server_limits
+    }
+
+    /// Lets at most `max` requests be in flight at once, across all threads
+    /// sharing the client, with further requests waiting their turn
+    ///
+    /// A batch counts as one request. Time spent waiting is not included in
+    /// the durations reported to the metrics sink.
+    pub fn with_concurrency_limit(mut self, max: usize) -> Client {
+        assert!(max > 0, "concurrency limit must be nonzero");
+        self.concurrency_limit = Some(Arc::new(ConcurrencyLimit {
+            max,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+        }));
+        self
+    }
+
+    /// Accessor for the concurrency limit, if one is set
+    pub fn concurrency_limit(&self) -> Option<usize> {
+        self.concurrency_limit.as_ref().map(|limit| limit.max)
+    }
+
+    /// Sets how requests are resent when their connection drops; see
+    /// `RetryPolicy`
+    ///
+    /// Backoff waits go through the client's clock.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Client {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Accessor for the retry policy
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    /// Sends `version`, e.g. "2.0", as the `jsonrpc` member of every
+    /// request, as strict JSONRPC 2.0 servers require
+    ///
+    /// This applies to requests built by the client and to those sent
+    /// through it with no version of their own.
+    pub fn with_jsonrpc_version(mut self, version: &str) -> Client {
+        self.jsonrpc_version = Some(version.to_owned());
+        self
+    }
+
+    /// Accessor for the JSONRPC version sent with requests, if any
+    pub fn jsonrpc_version(&self) -> Option<&str> {
+        self.jsonrpc_version.as_deref()
+    }
+
+    /// Makes the nonce wrap to zero when it reaches `modulus`, so that
+    /// request ids stay within `0..modulus`
+    ///
+    /// Ids are only unique while fewer than `modulus` requests are in flight;
+    /// the modulus must be large enough that an id is never reused before
+    /// the response to its previous use has arrived.
+    pub fn with_nonce_modulus(mut self, modulus: u64) -> Client {
+        assert!(modulus > 0, "nonce modulus must be nonzero");
+        self.nonce_modulus = Some(modulus);
+        self
+    }
+
+    /// Accessor for the timeout, if one is set
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The timeout which applies to requests to `method`, if any
+    pub fn method_timeout(&self, method: &str) -> Option<Duration> {
+        match self.method_timeouts.get(method) {
+            Some(&(timeout, _)) => Some(timeout),
+            None => self.timeout,
+        }
+    }
+
+    /// Builds a request for `method` with the next nonce, sends it and
+    /// deserializes its result
+    ///
+    /// An error response gives `Error::Rpc`, and a response with neither
+    /// error nor result `Error::NoErrorOrResult`, as with
+    /// `Response::into_result`.
+    pub fn call<T: Deserialize>(&self, method: &str, params: Vec<Value>) -> Result<T, Error> {
+        let request = self.build_request(method.to_owned(), params);
+        self.send_request(&request)?.into_result()
+    }
+
+    /// Sends a request to a client
+    pub fn send_request(&self, request: &Request) -> Result<Response, Error> {
+        self.send_request_timed(request).map(|(response, _)| response)
+    }
+
+    /// Sends a request like `send_request`, also returning how long the
+    /// server took to answer it
+    ///
+    /// The latency runs from just before the request is posted to just
+    /// after the response is parsed, so it covers opening or reusing a
+    /// connection, any resends after a dropped connection and reading the
+    /// body, but not serializing the request. A dry run takes no time.
+    pub fn send_request_timed(&self, request: &Request) -> Result<(Response, Duration), Error> {
+        if self.read_only && request.method == BROADCAST_METHOD {
+            return Err(Error::ReadOnly);
+        }
+        if let Some(response) = self.dry_run_response(request) {
+            return Ok((response, Duration::from_secs(0)));
+        }
+        self.warn_if_deprecated(request);
+
+        let _permit = self.concurrency_limit.as_ref().map(|limit| limit.acquire());
+        let started = Instant::now();
+        let result = self.round_trip(request)
+            .and_then(|(response, latency)| Ok((self.validate(response)?, latency)));
+        self.report(request,
+                    started,
+                    result.as_ref().map(|(response, _)| succeeded(response)).unwrap_or(false));
+        if let Ok((ref response, _)) = result {
+            self.observe_for_downgrade(request, response);
+        }
+        result
+    }
+
+    /// Sends a request like `send_request`, deserializing its result
+    /// straight from the response body as it is read
+    ///
+    /// The body is never held whole, so this suits large results such as
+    /// long runs of headers. An error response gives `Error::Rpc`, as with
+    /// `Response::into_result`. There is no `Response` for the validators to
+    /// check, so they are skipped, and since a malformed body was never
+    /// buffered, `Error::MalformedResponse` and `Error::Http` come without it.
+    pub fn send_request_streaming<T: Deserialize>(&self, request: &Request) -> Result<T, Error> {
+        if self.read_only && request.method == BROADCAST_METHOD {
+            return Err(Error::ReadOnly);
+        }
+        if let Some(response) = self.dry_run_response(request) {
+            return response.into_result();
+        }
+        self.warn_if_deprecated(request);
+
+        let _permit = self.concurrency_limit.as_ref().map(|limit| limit.acquire());
+        let started = Instant::now();
+        let result = self.streaming_round_trip(request);
+        self.report(request, started, result.is_ok());
+        result
+    }
+
+    /// Sends several requests as one JSONRPC batch, returning the responses
+    /// in the order of `requests` whatever order the server sent them in
+    ///
+    /// The requests' ids must be distinct, so each request in the batch needs
+    /// its own nonce: building the batch with `build_request` advances the
+    /// nonce counter once per request, not once per batch. A response whose id
+    /// matches no request, or a request already answered, gives
+    /// `Error::UnexpectedBatchId`; requests the server left unanswered are
+    /// simply absent, as `missing_ids` shows and `send_batch_complete`
+    /// checks. An empty batch returns no responses
+    /// without contacting the server. The checks `send_request` makes, such
+    /// as refusing broadcasts on a read-only client, apply to each request.
+    pub fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, Error> {
+        if requests.is_empty() {
+            return Ok(vec![]);
+        }
+        if self.read_only && requests.iter().any(|r| r.method == BROADCAST_METHOD) {
+            return Err(Error::ReadOnly);
+        }
+        if self.dry_run.is_some() {
+            return Ok(requests.iter().filter_map(|r| self.dry_run_response(r)).collect());
+        }
+        for request in requests {
+            self.warn_if_deprecated(request);
+        }
+
+        let _permit = self.concurrency_limit.as_ref().map(|limit| limit.acquire());
+        let started = Instant::now();
+        let result = self.batch_round_trip(requests).and_then(|responses| {
+            responses.into_iter().map(|r| self.validate(r)).collect::<Result<Vec<_>, _>>()
+        });
+        for request in requests {
+            let response = result.as_ref()
+                .ok()
+                .and_then(|rs| rs.iter().find(|r| ids_match(&request.id, &r.id)));
+            self.report(request, started, response.map(succeeded).unwrap_or(false));
+            if let Some(response) = response {
+                self.observe_for_downgrade(request, response);
+            }
+        }
+        result
+    }
+
+    /// Sends a batch like `send_batch`, but gives `Error::IncompleteBatch`
+    /// if any request is left without a response
+    pub fn send_batch_complete(&self, requests: &[Request]) -> Result<Vec<Response>, Error> {
+        let responses = self.send_batch(requests)?;
+        let missing = missing_ids(requests, &responses);
+        if !missing.is_empty() {
+            return Err(Error::IncompleteBatch { missing });
+        }
+        Ok(responses)
+    }
+
+    /// Sends a batch like `send_batch`, passing each response to
+    /// `on_response` as it is parsed from the body rather than collecting
+    /// them, so that memory stays bounded however large the batch
+    ///
+    /// Responses come in the order the server sent them. Each is matched to
+    /// its request and validated as with `send_batch`, and the first which
+    /// fails ends the batch with that error, once those before it have
+    /// been passed on. The batch is only resent if the connection drops
+    /// before any of the response is read. With a transport or an inspector
+    /// the body is read whole first, as with `send_request_streaming`.
+    pub fn send_batch_streaming<F>(&self, requests: &[Request], mut on_response: F)
+                                   -> Result<(), Error>
+        where F: FnMut(Response)
+    {
+        if requests.is_empty() {
+            return Ok(());
+        }
+        if self.read_only && requests.iter().any(|r| r.method == BROADCAST_METHOD) {
+            return Err(Error::ReadOnly);
+        }
+        if self.dry_run.is_some() {
+            requests.iter().filter_map(|r| self.dry_run_response(r)).for_each(on_response);
+            return Ok(());
+        }
+        for request in requests {
+            self.warn_if_deprecated(request);
+        }
+
+        let _permit = self.concurrency_limit.as_ref().map(|limit| limit.acquire());
+        let started = Instant::now();
+        // Whether each request has been answered, and if so successfully
+        let mut answered: Vec<Option<bool>> = requests.iter().map(|_| None).collect();
+        let result = self.streaming_batch_round_trip(requests, |response| {
+            let index = requests.iter()
+                .position(|r| ids_match(&r.id, &response.id))
+                .filter(|&i| answered[i].is_none())
+                .ok_or_else(|| Error::UnexpectedBatchId(response.id.clone()))?;
+            let response = self.validate(response)?;
+            answered[index] = Some(succeeded(&response));
+            self.observe_for_downgrade(&requests[index], &response);
+            on_response(response);
+            Ok(())
+        });
+        for (request, success) in requests.iter().zip(answered) {
+            self.report(request, started, success.unwrap_or(false));
+        }
+        result
+    }
+
+    /// The canned answer to `request`, if this is a dry-run client
+    fn dry_run_response(&self, request: &Request) -> Option<Response> {
+        let dry_run = self.dry_run.as_ref()?;
+        dry_run.requests.lock().unwrap().push(request.clone());
+        let result = dry_run.results.get(&request.method).cloned().unwrap_or(Value::Null);
+        Some(Response {
+            result: Some(result),
+            error: None,
+            id: request.id.clone(),
+            jsonrpc: None,
+        })
+    }
+
+    #[cfg(feature = "logging")]
+    fn warn_if_deprecated(&self, request: &Request) {
+        if let Some(version) = self.protocol_version() {
+            if let Some(warning) = ::electrum::deprecation_warning(&request.method, &version) {
+                warn!("{}", warning);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "logging"))]
+    fn warn_if_deprecated(&self, _request: &Request) {}
+
+    /// Counts `response` towards an adaptive downgrade if it rejects a method
+    /// the recorded protocol version should support, downgrading once the
+    /// threshold is reached
+    fn observe_for_downgrade(&self, request: &Request, response: &Response) {
+        let adaptive = match self.adaptive_downgrade {
+            Some(ref adaptive) => adaptive,
+            None => return,
+        };
+        match response.error {
+            Some(ref e) if e.as_standard() == Some(StandardError::MethodNotFound) => {}
+            _ => return,
+        }
+
+        let mut version = self.protocol_version.lock().unwrap();
+        let from = match *version {
+            Some(from) => from,
+            None => return,
+        };
+        if ::electrum::deprecated_methods(&from).contains(&request.method.as_str()) {
+            return;
+        }
+        let mut misses = adaptive.misses.lock().unwrap();
+        *misses += 1;
+        if *misses < adaptive.threshold {
+            return;
+        }
+        if let Some(to) = from.previous_minor() {
+            *misses = 0;
+            *version = Some(to);
+            drop(misses);
+            drop(version);
+            (adaptive.listener)(&ProtocolDowngrade {
+                from,
+                to,
+                method: request.method.clone(),
+            });
+        }
+    }
+
+    /// Runs the response validators over `response`
+    fn validate(&self, response: Response) -> Result<Response, Error> {
+        for validator in &self.validators {
+            validator(&response)?;
+        }
+        Ok(response)
+    }
+
+    /// Reports a finished request to the slow request callback and the
+    /// metrics sink, as having succeeded or not
+    fn report(&self, request: &Request, started: Instant, success: bool) {
+        let duration = started.elapsed();
+        if let Some((threshold, ref callback)) = self.slow_requests {
+            if duration >= threshold {
+                callback(&request.method, duration);
+            }
+        }
+        if let Some(ref sink) = self.metrics {
+            sink.record(&RequestMetric {
+                method: request.method.clone(),
+                duration,
+                success,
+            });
+        }
+    }
+
+    /// Sends a request like `send_request`, retrying transport failures
+    /// while `budget` allows
+    ///
+    /// Multi-request operations pass the same budget to each request, so
+    /// that the budget bounds the retries of the whole operation.
+    pub fn send_request_with_budget(&self,
+                                    request: &Request,
+                                    budget: &RetryBudget)
+                                    -> Result<Response, Error> {
+        loop {
+            match self.send_request(request) {
+                Ok(response) => return Ok(response),
+                Err(e) => budget.retry(e)?,
+            }
+        }
+    }
+
+    /// Sends a request like `send_request`, but treats an RPC error with one
+    /// of `codes` as success, giving a response with a null result
+    ///
+    /// This suits idempotent requests such as rebroadcasts, where an error
+    /// like "transaction already in mempool" means the work is done.
+    pub fn send_request_tolerating(&self,
+                                   request: &Request,
+                                   codes: &[i32])
+                                   -> Result<Response, Error> {
+        let mut response = self.send_request(request)?;
+        if response.error.as_ref().is_some_and(|e| codes.contains(&e.code)) {
+            response.error = None;
+            response.result = Some(Value::Null);
+        }
+        Ok(response)
+    }
+
+    /// `request` with the client's JSONRPC version, if it has one and the
+    /// request does not
+    fn versioned(&self, request: &Request) -> Request {
+        Request {
+            jsonrpc: request.jsonrpc.clone().or_else(|| self.jsonrpc_version.clone()),
+            ..request.clone()
+        }
+    }
+
+    /// The hyper client for requests to `method`, which has its timeout
+    fn method_client(&self, method: &str) -> &HyperClient {
+        match self.method_timeouts.get(method) {
+            Some((_, client)) => client,
+            None => &self.client,
+        }
+    }
+
+    /// Posts `request`, returning the response with its latency
+    fn round_trip(&self, request: &Request) -> Result<(Response, Duration), Error> {
+        let request_json = serde_json::to_string(&self.versioned(request))?;
+        let client = self.method_client(&request.method);
+        let started = Instant::now();
+        let response: Response = self.post(client, &request_json)?;
+        let latency = started.elapsed();
+        if !ids_match(&request.id, &response.id) {
+            return Err(Error::NonceMismatch);
+        }
+
+        Ok((response, latency))
+    }
+
+    fn streaming_round_trip<T: Deserialize>(&self, request: &Request) -> Result<T, Error> {
+        let request_json = serde_json::to_string(&self.versioned(request))?;
+        let client = self.method_client(&request.method);
+        let response: StreamedResponse<T> =
+            self.retrying(|| self.post_streaming_once(client, &request_json))?;
+        if !ids_match(&request.id, &response.id) {
+            return Err(Error::NonceMismatch);
+        }
+
+        if let Some(e) = response.error {
+            return Err(Error::Rpc(e));
+        }
+        response.result.ok_or(Error::NoErrorOrResult)
+    }
+
+    fn batch_round_trip(&self, requests: &[Request]) -> Result<Vec<Response>, Error> {
+        let versioned: Vec<Request> = requests.iter().map(|r| self.versioned(r)).collect();
+        let request_json = serde_json::to_string(&versioned)?;
+        match_batch(requests, self.post(self.batch_client(requests), &request_json)?)
+    }
+
+    /// The client to send a batch with, which has the most generous of its
+    /// methods' timeouts
+    fn batch_client(&self, requests: &[Request]) -> &HyperClient {
+        requests.iter()
+            .filter_map(|r| self.method_timeouts.get(&r.method))
+            .max_by_key(|&&(timeout, _)| timeout)
+            .map_or(&self.client, |(_, client)| client)
+    }
+
+    /// Posts a batch, passing each response to `each` as it is parsed
+    fn streaming_batch_round_trip<F>(&self, requests: &[Request], mut each: F) -> Result<(), Error>
+        where F: FnMut(Response) -> Result<(), Error>
+    {
+        let versioned: Vec<Request> = requests.iter().map(|r| self.versioned(r)).collect();
+        let request_json = serde_json::to_string(&versioned)?;
+        let client = self.batch_client(requests);
+        // Transports and inspectors deal in whole bodies
+        if self.transport.is_some() || self.inspector.is_some() {
+            let responses: Vec<Response> = self.post(client, &request_json)?;
+            return responses.into_iter().try_for_each(each);
+        }
+
+        let mut stream = self.retrying(|| self.http_send(client, &request_json))?;
+        let status = stream.status;
+        let mut reader = BufReader::new(Deadline::new(&mut stream, self.read_timeout));
+        if reader.fill_buf().map(|buf| buf.starts_with(BOM)).unwrap_or(false) {
+            reader.consume(BOM.len());
+        }
+        let mut rejected = None;
+        let parsed = {
+            let mut deserializer = serde_json::Deserializer::from_reader(&mut reader);
+            let seed = EachResponse {
+                each: &mut each,
+                rejected: &mut rejected,
+            };
+            seed.deserialize(&mut deserializer).and_then(|()| deserializer.end())
+        };
+        match parsed {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if let Some(rejected) = rejected {
+                    return Err(rejected);
+                }
+                match reader.get_mut().failure.take() {
+                    Some(failure) => Err(self.read_error(failure)),
+                    None if !status.is_success() => {
+                        Err(Error::Http {
+                            status: status.to_u16(),
+                            body: String::new(),
+                        })
+                    }
+                    None => Err(malformed_response(e, "")),
+                }
+            }
+        }
+    }
+
+    /// Posts `request_json` with `client`, parsing the response body, and
+    /// resending it as the retry policy allows if the connection drops
+    fn post<T: Deserialize>(&self, client: &HyperClient, request_json: &str) -> Result<T, Error> {
+        self.retrying(|| self.post_once(client, request_json))
+    }
+
+    /// Makes `attempt` until it succeeds or fails other than by the
+    /// connection dropping, or the retry policy allows no more retries
+    fn retrying<T, F: FnMut() -> Result<T, Error>>(&self, mut attempt: F) -> Result<T, Error> {
+        // Hyper maintains a pool of TCP connections to its various clients,
+        // and when one drops it cannot tell until it tries sending. In this
+        // case the appropriate thing is to re-send, which will cause hyper
+        // to open a new connection. Jonathan Reem explained this to me on
+        // IRC, citing vague technical reasons that the library itself cannot
+        // do the retry transparently.
+        let mut retries = 0;
+        loop {
+            match attempt() {
+                Err(ref e) if is_connection_error(e) &&
+                              retries < self.retry_policy.max_retries => {
+                    retries += 1;
+                    self.clock.sleep(self.retry_policy.backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn post_once<T: Deserialize>(&self,
+                                 client: &HyperClient,
+                                 request_json: &str)
+                                 -> Result<T, Error> {
+        if let Some(ref inspector) = self.inspector {
+            inspector.on_request(request_json);
+        }
+        let (status, response_str) = match self.transport {
+            Some(ref transport) => (StatusCode::Ok, transport.round_trip(request_json)?),
+            None => self.http_round_trip(client, request_json)?,
+        };
+        if let Some(ref inspector) = self.inspector {
+            inspector.on_response(&response_str);
+        }
+
+        // The body of an error status usually still holds a JSONRPC
+        // response describing the error, so the status only matters when it
+        // does not, as with a proxy's error page
+        match serde_json::from_str(&response_str) {
+            Ok(parsed) => Ok(parsed),
+            Err(_) if !status.is_success() => {
+                Err(Error::Http {
+                    status: status.to_u16(),
+                    body: response_str,
+                })
+            }
+            Err(e) => Err(malformed_response(e, &response_str)),
+        }
+    }
+
+    /// Posts `request_json` with `client`, parsing the response body as it
+    /// is read
+    fn post_streaming_once<T: Deserialize>(&self,
+                                           client: &HyperClient,
+                                           request_json: &str)
+                                           -> Result<T, Error> {
+        // Transports and inspectors deal in whole bodies
+        if self.transport.is_some() || self.inspector.is_some() {
+            return self.post_once(client, request_json);
+        }
+
+        let mut stream = self.http_send(client, request_json)?;
+        let status = stream.status;
+        let mut reader = BufReader::new(Deadline::new(&mut stream, self.read_timeout));
+        if reader.fill_buf().map(|buf| buf.starts_with(BOM)).unwrap_or(false) {
+            reader.consume(BOM.len());
+        }
+        // Parsing goes on to the end of the body, leaving the connection
+        // ready for reuse
+        match serde_json::from_reader(&mut reader) {
+            Ok(parsed) => Ok(parsed),
+            Err(e) => {
+                match reader.get_mut().failure.take() {
+                    Some(failure) => Err(self.read_error(failure)),
+                    None if !status.is_success() => {
+                        Err(Error::Http {
+                            status: status.to_u16(),
+                            body: String::new(),
+                        })
+                    }
+                    None => Err(malformed_response(e, "")),
+                }
+            }
+        }
+    }
+
+    /// Posts `request_json` with `client`, returning the status and body of
+    /// the response
+    ///
+    /// A byte order mark at the start of the body is dropped, and a body
+    /// which is not UTF-8 gives `Error::InvalidUtf8`.
+    fn http_round_trip(&self,
+                       client: &HyperClient,
+                       request_json: &str)
+                       -> Result<(StatusCode, String), Error> {
+        let mut stream = self.http_send(client, request_json)?;
+        let mut body = vec![];
+        Deadline::new(&mut stream, self.read_timeout)
+            .read_to_end(&mut body)
+            .map_err(|e| self.read_error(e))?;
+        if body.starts_with(BOM) {
+            body.drain(..BOM.len());
+        }
+        let response_str = String::from_utf8(body).map_err(Error::InvalidUtf8)?;
+        Ok((stream.status, response_str))
+    }
+
+    /// Posts `request_json` with `client`, returning the response once its
+    /// head is read
+    fn http_send(&self,
+                 client: &HyperClient,
+                 request_json: &str)
+                 -> Result<hyper::client::Response, Error> {
+        // Setup connection
+        let mut headers = Headers::new();
+        if let Some(ref user) = self.user {
+            headers.set(Authorization(Basic {
+                username: user.clone(),
+                password: self.pass.clone(),
+            }));
+        }
+
+        // Send request
+        let hyper_request = client.post(&self.url).headers(headers).body(request_json);
+        match hyper_request.send() {
+            Ok(s) => Ok(s),
+            Err(hyper::error::Error::Io(ref e)) if is_timeout(e) => {
+                Err(if self.first_byte_timeout.is_some() {
+                    Error::FirstByteTimeout
+                } else {
+                    Error::Timeout
+                })
+            }
+            Err(hyper::error::Error::Io(e)) => Err(connect_error(e)),
+            Err(e) => Err(Error::Hyper(e)),
+        }
+    }
+
+    /// Gives the error for a failure reading a response body
+    fn read_error(&self, e: io::Error) -> Error {
+        if !is_timeout(&e) {
+            Error::Io(e)
+        } else if self.read_timeout.is_some() || self.first_byte_timeout.is_some() {
+            Error::ReadTimeout
+        } else {
+            Error::Timeout
+        }
+    }
+
+    /// Builds a request
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        Request {
+            method: name,
+            params: Params::Positional(params),
+            id: self.next_id(),
+            jsonrpc: self.jsonrpc_version.clone(),
+        }
+    }
+
+    /// Builds a request with parameters given by name, for servers which
+    /// want them as a JSON object
+    pub fn build_request_named(&self, name: String, params: Map<String, Value>) -> Request {
+        Request {
+            method: name,
+            params: Params::Named(params),
+            id: self.next_id(),
+            jsonrpc: self.jsonrpc_version.clone(),
+        }
+    }
+
+    /// Builds a request with the given `id` rather than the next nonce,
+    /// e.g. a string id
+    ///
+    /// The nonce is left alone, so the caller is responsible for keeping
+    /// ids unique.
+    pub fn build_request_with_id(&self, name: String, params: Vec<Value>, id: Value) -> Request {
+        Request {
+            method: name,
+            params: Params::Positional(params),
+            id,
+            jsonrpc: self.jsonrpc_version.clone(),
+        }
+    }
+
+    /// Advances the nonce, returning it as a request id
+    pub(crate) fn next_id(&self) -> Value {
+        let nonce = match self.nonce_modulus {
+            Some(modulus) => {
+                let advance = |nonce| Some((nonce + 1) % modulus);
+                // The closure never fails, so neither does the update
+                let previous = self.nonce
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, advance)
+                    .unwrap();
+                (previous + 1) % modulus
+            }
+            None => self.nonce.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        From::from(nonce)
+    }
+
+    /// Accessor for the last-used nonce
+    pub fn last_nonce(&self) -> u64 {
+        self.nonce.load(Ordering::SeqCst)
+    }
+}
+
+/// A hyper client whose connections, reads and writes give up after
+/// `timeout`, if one is given, and which connects through `proxy`, if one
+/// is given
+fn hyper_client(timeout: Option<Duration>, proxy: Option<SocketAddr>) -> HyperClient {
+    if timeout.is_none() && proxy.is_none() {
+        return HyperClient::new();
+    }
+    let connector = Connector { timeout, proxy };
+    let mut client = HyperClient::with_connector(Pool::with_connector(Default::default(),
+                                                                      connector));
+    client.set_read_timeout(timeout);
+    client.set_write_timeout(timeout);
+    client
+}
+
+/// Opens HTTP connections for hyper, which otherwise waits on a connection
+/// for as long as the operating system does, and cannot use a SOCKS proxy
+struct Connector {
+    timeout: Option<Duration>,
+    proxy: Option<SocketAddr>,
+}
+
+impl NetworkConnector for Connector {
+    type Stream = HttpStream;
+
+    fn connect(&self, host: &str, port: u16, scheme: &str) -> hyper::Result<HttpStream> {
+        if scheme != "http" {
+            return Err(hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "Invalid scheme for Http")));
+        }
+        let stream = match (self.proxy, self.timeout) {
+            (Some(ref proxy), timeout) => {
+                socks5::connect(proxy, host, port, timeout).map_err(to_io_error)?
+            }
+            (None, Some(timeout)) => tcp::connect_timeout(&(host, port), timeout)?,
+            (None, None) => TcpStream::connect((host, port))?,
+        };
+        Ok(HttpStream(stream))
+    }
+}
+
+impl Transport for Client {
+    // Posts as `send_request` does, with the retry policy, inspector and
+    // validators, which check each response of a batch
+    fn round_trip(&self, request_json: &str) -> Result<String, Error> {
+        let response: Value = self.post(&self.client, request_json)?;
+        let responses: Vec<Response> = match response {
+            Value::Array(_) => serde_json::value::from_value(response.clone())?,
+            _ => vec![serde_json::value::from_value(response.clone())?],
+        };
+        for response in responses {
+            self.validate(response)?;
+        }
+        Ok(serde_json::to_string(&response)?)
+    }
+}
+
+/// Wraps an error as an IO error, so that `Connector` can pass it through
+/// hyper for `connect_error` to unwrap
+fn to_io_error(error: Error) -> io::Error {
+    match error {
+        Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Recovers an error `Connector` passed through hyper as an IO error
+fn connect_error(e: io::Error) -> Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+    }
+    Error::Hyper(hyper::Error::Io(e))
+}
+
+/// A response whose result is deserialized straight into `T`
+#[derive(Deserialize)]
+struct StreamedResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+    id: Value,
+}
+
+/// Parses a batch response, passing each response to `each` as soon as it
+/// is parsed, and keeping the error of one `each` rejects in `rejected`
+struct EachResponse<'a, F: 'a> {
+    each: &'a mut F,
+    rejected: &'a mut Option<Error>,
+}
+
+impl<'a, F> DeserializeSeed for EachResponse<'a, F>
+    where F: FnMut(Response) -> Result<(), Error>
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'a, F> Visitor for EachResponse<'a, F>
+    where F: FnMut(Response) -> Result<(), Error>
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of responses")
+    }
+
+    fn visit_seq<V: SeqVisitor>(self, mut visitor: V) -> Result<(), V::Error> {
+        while let Some(response) = visitor.visit::<Response>()? {
+            if let Err(e) = (self.each)(response) {
+                let message = e.to_string();
+                *self.rejected = Some(e);
+                return Err(de::Error::custom(message));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `response` reports success rather than an error
+fn succeeded(response: &Response) -> bool {
+    response.error.is_none()
+}
+
+/// Whether an IO error is a socket timeout
+fn is_timeout(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// A reader which fails with `io::ErrorKind::TimedOut` once a deadline passes
+///
+/// The last error is kept, since parsing from the reader replaces it with
+/// a parse error which cannot be unwrapped.
+struct Deadline<R> {
+    inner: R,
+    deadline: Option<Instant>,
+    failure: Option<io::Error>,
+}
+
+impl<R: Read> Deadline<R> {
+    fn new(inner: R, timeout: Option<Duration>) -> Deadline<R> {
+        Deadline {
+            inner,
+            deadline: timeout.map(|t| Instant::now() + t),
+            failure: None,
+        }
+    }
+}
+
+impl<R: Read> Read for Deadline<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"))
+            }
+            _ => self.inner.read(buf),
+        };
+        result.map_err(|e| {
+            let copy = io::Error::new(e.kind(), e.to_string());
+            self.failure = Some(e);
+            copy
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use serde_json;
+    use serde_json::{Map, Value};
+
+    use {Params, Response};
+    use clock::MockClock;
+    use electrum::requests;
+    use error::Error;
+    use metrics::{MetricsSink, RequestMetric};
+    use retry::RetryPolicy;
+    use test_utils::{MockServer, raw_server, reply, reply_error, socks5_proxy};
+    use super::{Client, ClientBuilder, DEFAULT_TIMEOUT, ids_match, missing_ids};
+    use super::inspector::Inspector;
+    use super::transport::MockTransport;
+
+    #[test]
+    fn test_nonce_increments() {
+        let client = Client::new("http://localhost".to_owned(), None, None);
+        for expected in 1..5 {
+            let req = client.build_request("server.ping".to_owned(), vec![]);
+            assert_eq!(req.id, Value::from(expected));
+            assert_eq!(client.last_nonce(), expected);
+        }
+    }
+
+    #[test]
+    fn test_nonces_unique_across_threads() {
+        for client in [Client::new("http://localhost".to_owned(), None, None),
+                       Client::new("http://localhost".to_owned(), None, None)
+                           .with_nonce_modulus(1_000_000)] {
+            let client = Arc::new(client);
+            let threads: Vec<_> = (0..16)
+                .map(|_| {
+                    let client = client.clone();
+                    thread::spawn(move || {
+                        (0..1000)
+                            .map(|_| client.build_request("server.ping".to_owned(), vec![]).id)
+                            .collect::<Vec<Value>>()
+                    })
+                })
+                .collect();
+            let mut ids: Vec<u64> = threads.into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .map(|id| id.as_u64().unwrap())
+                .collect();
+            ids.sort();
+            ids.dedup();
+            assert_eq!(ids.len(), 16_000);
+            assert_eq!(client.last_nonce(), 16_000);
+        }
+    }
+
+    #[test]
+    fn test_ids_match() {
+        assert!(ids_match(&Value::from(3), &Value::from(3)));
+        assert!(ids_match(&Value::from(3), &Value::String("3".to_owned())));
+        assert!(ids_match(&Value::String("-3".to_owned()), &Value::from(-3)));
+        assert!(ids_match(&Value::String("a".to_owned()), &Value::String("a".to_owned())));
+        for other in &["03", "3.0", " 3", "4"] {
+            assert!(!ids_match(&Value::from(3), &Value::String(other.to_string())));
+        }
+        assert!(!ids_match(&Value::from(3.0), &Value::String("3".to_owned())));
+        assert!(!ids_match(&Value::from(3), &Value::Null));
+    }
+
+    #[test]
+    fn test_string_ids() {
+        // Answers with the id as a string, as some proxies do
+        let server = MockServer::new(|req| {
+            let id = match req.id {
+                Value::Number(n) => Value::String(n.to_string()),
+                id => id,
+            };
+            reply(id, Value::Null)
+        });
+        let client = server.client();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+
+        let req = client.build_request_with_id("server.ping".to_owned(),
+                                               vec![],
+                                               Value::String("ping".to_owned()));
+        assert_eq!(client.send_request(&req).unwrap().id, Value::String("ping".to_owned()));
+        assert_eq!(client.last_nonce(), 1);
+
+        let batch = vec![client.build_request("server.ping".to_owned(), vec![]),
+                         client.build_request("server.ping".to_owned(), vec![])];
+        assert_eq!(client.send_batch_complete(&batch).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_jsonrpc_version() {
+        let versions = Arc::new(Mutex::new(vec![]));
+        let seen = versions.clone();
+        let server = MockServer::new(move |req| {
+            seen.lock().unwrap().push(req.jsonrpc.clone());
+            let mut response = reply(req.id, Value::Null);
+            response.jsonrpc = req.jsonrpc;
+            response
+        });
+        let client = server.client().with_jsonrpc_version("2.0");
+        assert_eq!(client.jsonrpc_version(), Some("2.0"));
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(req.jsonrpc, Some("2.0".to_owned()));
+        let response = client.send_request(&req).unwrap();
+        assert_eq!(response.jsonrpc, Some("2.0".to_owned()));
+        // Requests built elsewhere get the version when sent
+        client.send_request(&requests::server_ping(client.next_id())).unwrap();
+        server.client().send_request(&requests::server_ping(Value::from(1))).unwrap();
+        assert_eq!(*versions.lock().unwrap(),
+                   vec![Some("2.0".to_owned()), Some("2.0".to_owned()), None]);
+    }
+
+    #[test]
+    fn test_call() {
+        let transport = MockTransport::new()
+            .with_result("blockchain.estimatefee", json!(0.0001))
+            .with_result("server.ping", Value::Null);
+        let client = Client::with_transport(transport);
+
+        let fee: f64 = client.call("blockchain.estimatefee", vec![json!(6)]).unwrap();
+        assert_eq!(fee, 0.0001);
+        assert_eq!(client.last_nonce(), 1);
+        match client.call::<Value>("server.banner", vec![]) {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32601),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match client.call::<Value>("server.ping", vec![]) {
+            Err(Error::NoErrorOrResult) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clone() {
+        let server = MockServer::new(|req| reply(req.id, Value::Null));
+        let client = server.client();
+        let clones: Vec<Client> = (0..4).map(|_| client.clone()).collect();
+
+        let handles: Vec<_> = clones.into_iter()
+            .map(|clone| {
+                thread::spawn(move || {
+                    (0..5)
+                        .map(|_| {
+                            let req = clone.build_request("server.ping".to_owned(), vec![]);
+                            clone.send_request(&req).unwrap();
+                            req.id.as_u64().unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut nonces: Vec<u64> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+        nonces.sort();
+        assert_eq!(nonces, (1..21).collect::<Vec<_>>());
+        assert_eq!(client.last_nonce(), 20);
+    }
+
+    #[test]
+    fn test_build_request_named() {
+        let server = MockServer::new(|req| match req.params {
+            Params::Named(ref params) => reply(req.id.clone(), params["number"].clone()),
+            Params::Positional(_) => reply_error(req.id.clone(), -32602, "want named params"),
+        });
+        let client = server.client();
+
+        let mut params = Map::new();
+        params.insert("number".to_owned(), json!(6));
+        let req = client.build_request_named("blockchain.estimatefee".to_owned(), params);
+        assert_eq!(serde_json::to_value(&req).unwrap()["params"], json!({"number": 6}));
+        assert_eq!(client.send_request(&req).unwrap().into_result::<u64>().unwrap(), 6);
+
+        let req = client.build_request("blockchain.estimatefee".to_owned(), vec![json!(6)]);
+        assert_eq!(serde_json::to_value(&req).unwrap()["params"], json!([6]));
+        assert!(client.send_request(&req).unwrap().check_error().is_err());
+    }
+
+    #[test]
+    fn test_builder() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(5));
+        let client = ClientBuilder::new("http://localhost:1".to_owned())
+            .url("http://localhost:2".to_owned())
+            .basic_auth("user".to_owned(), None)
+            .timeout(Duration::from_secs(5))
+            .retry_policy(policy)
+            .build();
+        assert_eq!(client.timeout(), Some(Duration::from_secs(5)));
+        assert_eq!(client.retry_policy(), policy);
+        assert_eq!(client.socks5_proxy(), None);
+
+        let server = MockServer::new(|req| reply(req.id, Value::Null));
+        let client = ClientBuilder::new(server.url()).build();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+    }
+
+    #[test]
+    fn test_socks5_proxy() {
+        let server = MockServer::new(|req| reply(req.id, Value::String(req.method)));
+        let proxy = socks5_proxy();
+        let client = ClientBuilder::new(server.url())
+            .proxy(proxy)
+            .timeout(Duration::from_secs(5))
+            .build();
+        assert_eq!(client.socks5_proxy(), Some(proxy));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let result: String = client.send_request(&req).unwrap().into_result().unwrap();
+        assert_eq!(result, "server.ping");
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_socks5_proxy_refused() {
+        // Nothing listens on the port the listener was bound to
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let client = Client::new(url, None, None).with_socks5_proxy(socks5_proxy());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Proxy(ref m)) => assert_eq!(m, "connection refused"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_nonce_modulus_wraps() {
+        let client = Client::new("http://localhost".to_owned(), None, None).with_nonce_modulus(3);
+        let ids: Vec<_> = (0..7)
+            .map(|_| client.build_request("server.ping".to_owned(), vec![]).id)
+            .collect();
+        let expected: Vec<Value> = [1u64, 2, 0, 1, 2, 0, 1].iter().map(|&n| From::from(n)).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(client.last_nonce(), 1);
+    }
+
+    #[test]
+    fn test_method_timeout_overrides_global() {
+        let client = Client::new("http://localhost".to_owned(), None, None)
+            .with_timeout(Duration::from_secs(1))
+            .with_method_timeout("blockchain.block.header", Duration::from_secs(10));
+        assert_eq!(client.method_timeout("blockchain.block.header"),
+                   Some(Duration::from_secs(10)));
+        assert_eq!(client.method_timeout("server.ping"), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_method_timeout_applies() {
+        let server = MockServer::new(|req| {
+            if req.method == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            reply(req.id, Value::Null)
+        });
+        let client = Client::new(server.url(), None, None)
+            .with_timeout(Duration::from_millis(100))
+            .with_method_timeout("slow", Duration::from_secs(5));
+
+        let req = client.build_request("slow".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_ok());
+
+        let client = Client::new(server.url(), None, None).with_timeout(Duration::from_millis(100));
+        let req = client.build_request("slow".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+    }
+
+    #[test]
+    fn test_first_byte_timeout() {
+        let url = raw_server(|mut stream| {
+            thread::sleep(Duration::from_millis(500));
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        });
+        let client = Client::new(url, None, None)
+            .with_first_byte_timeout(Duration::from_millis(100))
+            .with_read_timeout(Duration::from_secs(5));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::FirstByteTimeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_first_byte_timeout_with_method_timeout() {
+        let url = raw_server(|mut stream| {
+            thread::sleep(Duration::from_millis(500));
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        });
+        let before = Client::new(url.clone(), None, None)
+            .with_first_byte_timeout(Duration::from_millis(100))
+            .with_method_timeout("blockchain.block.headers", Duration::from_secs(5));
+        let after = Client::new(url, None, None)
+            .with_method_timeout("blockchain.block.headers", Duration::from_secs(5))
+            .with_first_byte_timeout(Duration::from_millis(100));
+        for client in &[before, after] {
+            let req = client.build_request("blockchain.block.headers".to_owned(), vec![]);
+            match client.send_request(&req) {
+                Err(Error::FirstByteTimeout) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Accepts the request but never replies
+        let url = raw_server(|_stream| thread::sleep(Duration::from_secs(5)));
+        let client = Client::new(url, None, None).with_timeout(Duration::from_millis(100));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let started = Instant::now();
+        match client.send_request(&req) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    /// A server answering every request with `body`, whatever its id
+    fn body_server(body: &'static [u8]) -> String {
+        raw_server(move |mut stream| {
+            let _ = write!(stream,
+                           "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+                           body.len());
+            let _ = stream.write_all(body);
+        })
+    }
+
+    #[test]
+    fn test_byte_order_mark() {
+        let url = body_server(b"\xef\xbb\xbf{\"result\":\"Welcome\",\"error\":null,\"id\":1}");
+        let client = Client::new(url, None, None);
+        let req = client.build_request("server.banner".to_owned(), vec![]);
+        assert_eq!(client.send_request(&req).unwrap().result, Some(json!("Welcome")));
+
+        let req = client.build_request_with_id("server.banner".to_owned(), vec![], json!(1));
+        assert_eq!(client.send_request_streaming::<String>(&req).unwrap(), "Welcome");
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let url = body_server(b"{\"result\":\"caf\xe9\",\"error\":null,\"id\":1}");
+        let client = Client::new(url, None, None);
+        let req = client.build_request("server.banner".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::InvalidUtf8(ref e)) => assert_eq!(e.utf8_error().valid_up_to(), 14),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_http_error_status() {
+        let url = raw_server(|mut stream| {
+            let body = "<html><body>502 Bad Gateway</body></html>";
+            let _ = write!(stream,
+                           "HTTP/1.1 502 Bad Gateway\r\nContent-Length: {}\r\n\r\n{}",
+                           body.len(),
+                           body);
+        });
+        let client = Client::new(url, None, None);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Http { status, ref body }) => {
+                assert_eq!(status, 502);
+                assert!(body.contains("Bad Gateway"));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_http_error_status_with_response() {
+        let url = raw_server(|mut stream| {
+            let body = r#"{"result":null,"error":{"code":-32603,"message":"down"},"id":1}"#;
+            let _ = write!(stream,
+                           "HTTP/1.1 500 Internal Server Error\r\nContent-Length: {}\r\n\r\n{}",
+                           body.len(),
+                           body);
+        });
+        let client = Client::new(url, None, None);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req).unwrap().check_error() {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.message, "down"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_response() {
+        let url = raw_server(|mut stream| {
+            let body = r#"{"result": "abc", "id": 1"#;
+            let _ = write!(stream,
+                           "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                           body.len(),
+                           body);
+        });
+        let client = Client::new(url, None, None);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::MalformedResponse { ref body, .. }) => {
+                assert_eq!(body, r#"{"result": "abc", "id": 1"#)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        // Responds promptly, but trickles out the body
+        let url = raw_server(|mut stream| {
+            let body = br#"{"result":null,"error":null,"id":1}"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            for byte in body.iter() {
+                thread::sleep(Duration::from_millis(20));
+                if stream.write_all(&[*byte]).is_err() {
+                    return;
+                }
+            }
+        });
+        let client = Client::new(url, None, None)
+            .with_first_byte_timeout(Duration::from_secs(5))
+            .with_read_timeout(Duration::from_millis(200));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::ReadTimeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_request_streaming() {
+        let headers = "00".repeat(1024 * 1024);
+        let result = headers.clone();
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.block.headers" => reply(req.id, json!({"count": 2016, "hex": result})),
+            _ => reply_error(req.id, -32601, "unknown method"),
+        });
+        let client = server.client();
+
+        let req = client.build_request("blockchain.block.headers".to_owned(),
+                                       vec![json!(0), json!(2016)]);
+        let chunk: Value = client.send_request_streaming(&req).unwrap();
+        assert_eq!(chunk["hex"], Value::String(headers));
+        let req = client.build_request("server.banner".to_owned(), vec![]);
+        match client.send_request_streaming::<String>(&req) {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32601),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_connection_reused_after_large_response() {
+        let big = "ab".repeat(512 * 1024);
+        let result = big.clone();
+        let server = MockServer::new(move |req| reply(req.id, Value::String(result.clone())));
+        let client = server.client();
+
+        for _ in 0..3 {
+            let req = client.build_request("blockchain.transaction.get".to_owned(), vec![]);
+            assert_eq!(client.send_request(&req).unwrap().into_result::<String>().unwrap(), big);
+        }
+        let req = client.build_request("blockchain.transaction.get".to_owned(), vec![]);
+        assert_eq!(client.send_request_streaming::<String>(&req).unwrap(), big);
+        assert_eq!(server.hits(), 4);
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn test_send_request_streaming_read_timeout() {
+        let url = raw_server(|mut stream| {
+            let body = br#"{"result":"abc","error":null,"id":1}"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            for byte in body.iter() {
+                thread::sleep(Duration::from_millis(20));
+                if stream.write_all(&[*byte]).is_err() {
+                    return;
+                }
+            }
+        });
+        let client = Client::new(url, None, None)
+            .with_first_byte_timeout(Duration::from_secs(5))
+            .with_read_timeout(Duration::from_millis(200));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request_streaming::<String>(&req) {
+            Err(Error::ReadTimeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_slow_body_within_read_timeout() {
+        let url = raw_server(|mut stream| {
+            let body = br#"{"result":null,"error":null,"id":1}"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            thread::sleep(Duration::from_millis(50));
+            let _ = stream.write_all(body);
+        });
+        let client = Client::new(url, None, None)
+            .with_first_byte_timeout(Duration::from_secs(5))
+            .with_read_timeout(Duration::from_secs(5));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_send_batch() {
+        let server = MockServer::new(|req| reply(req.id.clone(), Value::String(req.method)));
+        let client = server.client();
+        let requests: Vec<_> = ["server.ping", "server.banner", "server.version"]
+            .iter()
+            .map(|m| client.build_request(m.to_string(), vec![]))
+            .collect();
+        assert_eq!(client.last_nonce(), 3);
+
+        let responses = client.send_batch(&requests).unwrap();
+        let results: Vec<String> = responses.into_iter()
+            .map(|r| r.into_result().unwrap())
+            .collect();
+        assert_eq!(results, ["server.ping", "server.banner", "server.version"]);
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_send_batch_reorders_responses() {
+        let url = raw_server(|mut stream| {
+            let body = br#"[{"result":"b","error":null,"id":2},{"result":"a","error":null,"id":1}]"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        });
+        let client = Client::new(url, None, None);
+        let requests = vec![client.build_request("server.ping".to_owned(), vec![]),
+                            client.build_request("server.ping".to_owned(), vec![])];
+        let responses = client.send_batch(&requests).unwrap();
+        let ids: Vec<Value> = responses.iter().map(|r| r.id.clone()).collect();
+        assert_eq!(ids, vec![Value::from(1), Value::from(2)]);
+        assert_eq!(responses[0].result, Some(Value::String("a".to_owned())));
+    }
+
+    #[test]
+    fn test_send_batch_unexpected_id() {
+        let url = raw_server(|mut stream| {
+            let body = br#"[{"result":null,"error":null,"id":1},{"result":null,"error":null,"id":7}]"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        });
+        let client = Client::new(url, None, None);
+        let requests = vec![client.build_request("server.ping".to_owned(), vec![]),
+                            client.build_request("server.ping".to_owned(), vec![])];
+        match client.send_batch(&requests) {
+            Err(Error::UnexpectedBatchId(ref id)) => assert_eq!(*id, Value::from(7)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_batch_incomplete() {
+        // Leaves the second request of the batch unanswered
+        let url = raw_server(|mut stream| {
+            let body = br#"[{"result":null,"error":null,"id":1},{"result":null,"error":null,"id":3}]"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        });
+        let client = Client::new(url, None, None);
+        let requests: Vec<_> = (0..3)
+            .map(|_| client.build_request("server.ping".to_owned(), vec![]))
+            .collect();
+
+        let responses = client.send_batch(&requests).unwrap();
+        assert_eq!(missing_ids(&requests, &responses), vec![Value::from(2)]);
+        match client.send_batch_complete(&requests) {
+            Err(Error::IncompleteBatch { ref missing }) => {
+                assert_eq!(*missing, vec![Value::from(2)])
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(missing_ids(&requests[..1], &responses).is_empty());
+    }
+
+    #[test]
+    fn test_send_batch_empty() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        assert!(server.client().send_batch(&[]).unwrap().is_empty());
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[test]
+    fn test_send_batch_streaming() {
+        let server = MockServer::new(|req| reply(req.id.clone(), req.params[0].clone()));
+        let client = server.client();
+        let requests: Vec<_> = (0..10_000)
+            .map(|i| client.build_request("echo".to_owned(), vec![json!(i)]))
+            .collect();
+
+        let mut received = 0;
+        client.send_batch_streaming(&requests, |response| {
+                assert_eq!(response.result, Some(json!(received)));
+                received += 1;
+            })
+            .unwrap();
+        assert_eq!(received, requests.len());
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_send_batch_streaming_incremental() {
+        // The second half of the body arrives well after the first
+        let url = raw_server(|mut stream| {
+            let body = br#"[{"result":"a","error":null,"id":1},{"result":"b","error":null,"id":2}]"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&body[..body.len() / 2 + 1]);
+            let _ = stream.flush();
+            thread::sleep(Duration::from_millis(200));
+            let _ = stream.write_all(&body[body.len() / 2 + 1..]);
+        });
+        let client = Client::new(url, None, None);
+        let requests = vec![client.build_request("server.ping".to_owned(), vec![]),
+                            client.build_request("server.ping".to_owned(), vec![])];
+
+        let mut first = None;
+        let mut results = vec![];
+        client.send_batch_streaming(&requests, |response| {
+                first.get_or_insert_with(Instant::now);
+                results.push(response.result.unwrap());
+            })
+            .unwrap();
+        assert_eq!(results, vec![json!("a"), json!("b")]);
+        assert!(first.unwrap().elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_send_batch_streaming_unexpected_id() {
+        let url = raw_server(|mut stream| {
+            let body = br#"[{"result":null,"error":null,"id":1},{"result":null,"error":null,"id":7}]"#;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(body);
+        });
+        let client = Client::new(url, None, None);
+        let requests = vec![client.build_request("server.ping".to_owned(), vec![]),
+                            client.build_request("server.ping".to_owned(), vec![])];
+        let mut received = 0;
+        match client.send_batch_streaming(&requests, |_| received += 1) {
+            Err(Error::UnexpectedBatchId(ref id)) => assert_eq!(*id, Value::from(7)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(received, 1);
+    }
+
+    #[test]
+    fn test_send_batch_read_only() {
+        let client = Client::new("http://localhost".to_owned(), None, None).with_read_only(true);
+        let requests = vec![client.build_request("server.ping".to_owned(), vec![]),
+                            requests::transaction_broadcast("0100", client.next_id())];
+        match client.send_batch(&requests) {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let current = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (server_current, server_peak) = (current.clone(), peak.clone());
+        let server = MockServer::new(move |req| {
+            let now = server_current.fetch_add(1, Ordering::SeqCst) + 1;
+            server_peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            server_current.fetch_sub(1, Ordering::SeqCst);
+            reply(req.id, Value::Null)
+        });
+        let client = Arc::new(server.client().with_concurrency_limit(1));
+        assert_eq!(client.concurrency_limit(), Some(1));
+
+        let started = Instant::now();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let req = client.build_request("server.ping".to_owned(), vec![]);
+                    client.send_request(&req).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    /// A server which drops the connection on the first `drops` requests
+    fn dropping_server(drops: usize) -> MockServer {
+        let count = AtomicUsize::new(0);
+        MockServer::flaky(move |req| if count.fetch_add(1, Ordering::SeqCst) < drops {
+            None
+        } else {
+            Some(reply(req.id, Value::Null))
+        })
+    }
+
+    #[test]
+    fn test_retry_policy_default() {
+        let server = dropping_server(1);
+        let client = server.client();
+        assert_eq!(client.retry_policy(), RetryPolicy::default());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let server = dropping_server(3);
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = server.client()
+            .with_clock(clock.clone())
+            .with_retry_policy(RetryPolicy::new(3, Duration::from_secs(1)));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_retry_policy_never() {
+        let server = dropping_server(1);
+        let client = server.client().with_retry_policy(RetryPolicy::never());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+        assert_eq!(server.hits(), 0);
+    }
+
+    /// An inspector recording what it sees, prefixed with its direction
+    #[derive(Default)]
+    struct Wiretap(Mutex<Vec<String>>);
+
+    impl Inspector for Wiretap {
+        fn on_request(&self, json: &str) {
+            self.0.lock().unwrap().push(format!("> {}", json));
+        }
+
+        fn on_response(&self, json: &str) {
+            self.0.lock().unwrap().push(format!("< {}", json));
+        }
+    }
+
+    #[test]
+    fn test_inspector() {
+        let server = dropping_server(1);
+        let wiretap = Arc::new(Wiretap::default());
+        let mut client = server.client();
+        client.set_inspector(wiretap.clone());
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        let seen = wiretap.0.lock().unwrap().clone();
+        // The dropped attempt is seen going out, but nothing comes back
+        assert_eq!(seen.len(), 3);
+        let request_json = serde_json::to_string(&req).unwrap();
+        assert_eq!(seen[0], format!("> {}", request_json));
+        assert_eq!(seen[1], seen[0]);
+        let response: Response = serde_json::from_str(&seen[2][2..]).unwrap();
+        assert_eq!(response.id, req.id);
+    }
+
+    #[test]
+    fn test_client_as_transport() {
+        let server = dropping_server(1);
+        let wiretap = Arc::new(Wiretap::default());
+        let mut inner = server.client()
+            .with_response_validator(|response| if response.id == json!(2) {
+                Err(Error::ResponseRejected("second".to_owned()))
+            } else {
+                Ok(())
+            });
+        inner.set_inspector(wiretap.clone());
+        let client = Client::with_transport(inner);
+
+        // The dropped connection is retried, and the inspector sees both tries
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(client.send_request(&req).unwrap().id, req.id);
+        assert_eq!(wiretap.0.lock().unwrap().len(), 3);
+
+        let batch = vec![client.build_request("server.ping".to_owned(), vec![])];
+        match client.send_batch(&batch) {
+            Err(Error::ResponseRejected(ref m)) => assert_eq!(m, "second"),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let url = raw_server(|mut stream| {
+            let body = "<html><body>502 Bad Gateway</body></html>";
+            let _ = write!(stream,
+                           "HTTP/1.1 502 Bad Gateway\r\nContent-Length: {}\r\n\r\n{}",
+                           body.len(),
+                           body);
+        });
+        let client = Client::with_transport(Client::new(url, None, None));
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Http { status, .. }) => assert_eq!(status, 502),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_inspector_streaming() {
+        let server = MockServer::new(|req| reply(req.id, json!("Welcome")));
+        let wiretap = Arc::new(Wiretap::default());
+        let mut client = server.client();
+        client.set_inspector(wiretap.clone());
+
+        let req = client.build_request("server.banner".to_owned(), vec![]);
+        assert_eq!(client.send_request_streaming::<String>(&req).unwrap(), "Welcome");
+        let seen = wiretap.0.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen[1].starts_with("< ") && seen[1].contains("\"Welcome\""));
+    }
+
+    #[test]
+    fn test_rpc_error_not_retried() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let seen = count.clone();
+        let server = MockServer::new(move |req| {
+            seen.fetch_add(1, Ordering::SeqCst);
+            reply_error(req.id, -32603, "internal error")
+        });
+        let req = server.client().build_request("server.ping".to_owned(), vec![]);
+        assert!(server.client().send_request(&req).unwrap().check_error().is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_slow_request_threshold() {
+        let server = MockServer::new(|req| {
+            if req.method == "blockchain.block.headers" {
+                thread::sleep(Duration::from_millis(150));
+            }
+            reply(req.id, Value::Null)
+        });
+        let slow = Arc::new(Mutex::new(vec![]));
+        let seen = slow.clone();
+        let client = server.client()
+            .with_slow_request_threshold(Duration::from_millis(100), move |method, duration| {
+                seen.lock().unwrap().push((method.to_owned(), duration))
+            });
+
+        for method in &["server.ping", "blockchain.block.headers", "server.ping"] {
+            let req = client.build_request(method.to_string(), vec![]);
+            client.send_request(&req).unwrap();
+        }
+        let slow = slow.lock().unwrap();
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].0, "blockchain.block.headers");
+        assert!(slow[0].1 >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_send_request_timed() {
+        let server = MockServer::new(|req| {
+            thread::sleep(Duration::from_millis(100));
+            reply(req.id, Value::Null)
+        });
+        let client = server.client();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let (response, latency) = client.send_request_timed(&req).unwrap();
+        assert_eq!(response.id, req.id);
+        assert!(latency >= Duration::from_millis(100));
+        assert!(latency < Duration::from_secs(5));
+
+        let client = server.client().with_dry_run();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(client.send_request_timed(&req).unwrap().1, Duration::from_secs(0));
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_slow_request_timed_out() {
+        let url = raw_server(|_stream| thread::sleep(Duration::from_secs(5)));
+        let slow = Arc::new(Mutex::new(vec![]));
+        let seen = slow.clone();
+        let client = Client::new(url, None, None)
+            .with_timeout(Duration::from_millis(100))
+            .with_slow_request_threshold(Duration::from_millis(50), move |method, _| {
+                seen.lock().unwrap().push(method.to_owned())
+            });
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+        assert_eq!(*slow.lock().unwrap(), ["server.ping"]);
+    }
+
+    struct Recorder(Mutex<Vec<RequestMetric>>);
+
+    impl MetricsSink for Recorder {
+        fn record(&self, metric: &RequestMetric) {
+            self.0.lock().unwrap().push(metric.clone());
+        }
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        let server = MockServer::new(|req| if req.method == "server.ping" {
+            reply(req.id, Value::Null)
+        } else {
+            reply_error(req.id, -32601, "Method not found")
+        });
+        let recorder = Arc::new(Recorder(Mutex::new(vec![])));
+        let client = server.client().with_metrics_sink(recorder.clone());
+
+        for method in &["server.ping", "server.nonsense"] {
+            let req = client.build_request(method.to_string(), vec![]);
+            client.send_request(&req).unwrap();
+        }
+
+        let metrics = recorder.0.lock().unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].method, "server.ping");
+        assert!(metrics[0].success);
+        assert_eq!(metrics[1].method, "server.nonsense");
+        assert!(!metrics[1].success);
+    }
+
+    #[test]
+    fn test_metrics_sink_transport_failure() {
+        let recorder = Arc::new(Recorder(Mutex::new(vec![])));
+        let client = Client::new("http://127.0.0.1:1".to_owned(), None, None)
+            .with_metrics_sink(recorder.clone());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+
+        let metrics = recorder.0.lock().unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert!(!metrics[0].success);
+    }
+
+    #[test]
+    fn test_response_validator() {
+        let server = MockServer::new(|req| {
+            reply(req.id, json!({"confirmed": -5, "unconfirmed": 0}))
+        });
+        let client = server.client().with_response_validator(|response| {
+            match response.result.as_ref().and_then(|r| r.get("confirmed")) {
+                Some(confirmed) if confirmed.as_u64().is_none() => {
+                    Err(Error::ResponseRejected(format!("negative balance {}", confirmed)))
+                }
+                _ => Ok(()),
+            }
+        });
+
+        let req = client.build_request("blockchain.scripthash.get_balance".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::ResponseRejected(ref m)) => assert_eq!(m, "negative balance -5"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_response_validators_in_order() {
+        let server = MockServer::new(|req| reply(req.id, Value::Null));
+        let client = server.client()
+            .with_response_validator(|_| Ok(()))
+            .with_response_validator(|_| Err(Error::ResponseRejected("second".to_owned())))
+            .with_response_validator(|_| Err(Error::ResponseRejected("third".to_owned())));
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::ResponseRejected(ref m)) => assert_eq!(m, "second"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_request_tolerating() {
+        let server = MockServer::new(|req| {
+            reply_error(req.id, -27, "transaction already in block chain")
+        });
+        let client = server.client();
+
+        let req = client.build_request("blockchain.transaction.broadcast".to_owned(),
+                                       vec![Value::String("0100".to_owned())]);
+        let response = client.send_request_tolerating(&req, &[-26, -27]).unwrap();
+        assert!(response.error.is_none());
+        assert_eq!(response.into_result::<Value>().unwrap(), Value::Null);
+
+        let req = client.build_request("blockchain.transaction.broadcast".to_owned(),
+                                       vec![Value::String("0100".to_owned())]);
+        let response = client.send_request_tolerating(&req, &[-26]).unwrap();
+        assert_eq!(response.error.unwrap().code, -27);
+    }
+
+    #[test]
+    fn test_read_only_rejects_broadcast() {
+        let server = MockServer::new(|req| reply(req.id, Value::Null));
+        let client = server.client().with_read_only(true);
+
+        let req = client.build_request("blockchain.transaction.broadcast".to_owned(),
+                                       vec![Value::String("0100".to_owned())]);
+        match client.send_request(&req) {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match client.broadcast("0100") {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 0);
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_ok());
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_dry_run_records_requests() {
+        let scripthash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let client = server.client()
+            .with_dry_run_result("blockchain.scripthash.get_balance",
+                                 json!({"confirmed": 3, "unconfirmed": 0}));
+
+        let balance = client.scripthash_get_balance(scripthash).unwrap();
+        assert_eq!(balance.confirmed, 3);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(client.send_request(&req).unwrap().result, Some(Value::Null));
+
+        let recorded = client.dry_run_requests();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0], requests::scripthash_get_balance(scripthash, Value::from(1)));
+        assert_eq!(recorded[1], req);
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[test]
+    fn test_dry_run_still_read_only() {
+        let client = Client::new("http://localhost".to_owned(), None, None)
+            .with_dry_run()
+            .with_read_only(true);
+        match client.broadcast("0100") {
+            Err(Error::ReadOnly) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(client.dry_run_requests().is_empty());
+    }
+
+    #[test]
+    fn test_no_timeout_by_default() {
+        let client = Client::new("http://localhost".to_owned(), None, None);
+        assert_eq!(client.timeout(), None);
+    }
+
+    #[test]
+    fn test_default_profile_timeout() {
+        let client = Client::with_defaults("http://localhost".to_owned(), None, None);
+        assert_eq!(client.timeout(), Some(DEFAULT_TIMEOUT));
+    }
+
+    #[test]
+    fn test_default_profile_timeout_override() {
+        let client = Client::with_defaults("http://localhost".to_owned(), None, None)
+            .with_timeout(Duration::from_secs(5));
+        assert_eq!(client.timeout(), Some(Duration::from_secs(5)));
+    }
+}
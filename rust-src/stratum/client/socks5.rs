@@ -0,0 +1,175 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # SOCKS5
+//!
+//! A minimal SOCKS5 CONNECT handshake, enough to reach Electrum servers,
+//! including .onion ones, through a local Tor daemon
+//!
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use error::Error;
+use super::tcp::connect_timeout;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// Connects to `host` and `port` through the SOCKS5 proxy at `proxy`
+///
+/// The host is passed to the proxy by name, so that it is resolved there
+/// rather than locally, as .onion addresses must be. As with
+/// `connect_timeout`, `timeout` bounds connecting to the proxy; the
+/// handshake is bounded by it too, and it is left set on the stream.
+pub(crate) fn connect(proxy: &SocketAddr,
+                      host: &str,
+                      port: u16,
+                      timeout: Option<Duration>)
+                      -> Result<TcpStream, Error> {
+    if host.is_empty() || host.len() > 255 {
+        return Err(Error::Proxy(format!("host name {:?} cannot be sent to the proxy", host)));
+    }
+    let mut stream = match timeout {
+        Some(timeout) => connect_timeout(proxy, timeout)?,
+        None => TcpStream::connect(proxy)?,
+    };
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(Error::Proxy("proxy does not speak SOCKS5".to_owned()));
+    }
+    if reply[1] != NO_AUTHENTICATION {
+        return Err(Error::Proxy("proxy requires authentication".to_owned()));
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0, ADDRESS_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+        return Err(Error::Proxy("proxy does not speak SOCKS5".to_owned()));
+    }
+    if reply[1] != 0 {
+        return Err(Error::Proxy(reply_message(reply[1]).to_owned()));
+    }
+    // Skip the address the proxy bound, and its port
+    let bound_len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => return Err(Error::Proxy(format!("unknown address type {}", atyp))),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(stream)
+}
+
+/// Splits an address given as `host:port`, allowing for a bracketed IPv6
+/// host
+pub(crate) fn split_host_port(addr: &str) -> Result<(&str, u16), Error> {
+    let invalid = || Error::Proxy(format!("address {:?} is not host:port", addr));
+    let colon = addr.rfind(':').ok_or_else(invalid)?;
+    let port = addr[colon + 1..].parse().map_err(|_| invalid())?;
+    let host = addr[..colon].trim_start_matches('[').trim_end_matches(']');
+    Ok((host, port))
+}
+
+/// The meaning of a SOCKS5 reply code, from RFC 1928
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown failure",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+
+    use error::Error;
+    use test_utils::tcp_server;
+    use super::{connect, split_host_port};
+
+    /// A proxy which reads the handshake, answers with `code` and echoes
+    /// the requested host and port back down the connection
+    fn proxy(code: u8) -> SocketAddr {
+        tcp_server(move |mut stream| {
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[5, 1, 0, 3]);
+            let mut target = vec![0; request[4] as usize + 2];
+            stream.read_exact(&mut target).unwrap();
+            stream.write_all(&[5, code, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+            if code == 0 {
+                stream.write_all(&target).unwrap();
+            }
+        }).parse().unwrap()
+    }
+
+    #[test]
+    fn test_connect() {
+        let mut stream = connect(&proxy(0), "example.onion", 50001, None).unwrap();
+        let mut target = vec![0; 15];
+        stream.read_exact(&mut target).unwrap();
+        assert_eq!(&target[..13], b"example.onion");
+        assert_eq!(&target[13..], &[0xc3, 0x51]);
+    }
+
+    #[test]
+    fn test_refused() {
+        match connect(&proxy(5), "example.onion", 50001, None) {
+            Err(Error::Proxy(ref m)) => assert_eq!(m, "connection refused"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("example.onion:50001").unwrap(), ("example.onion", 50001));
+        assert_eq!(split_host_port("[::1]:50001").unwrap(), ("::1", 50001));
+        assert!(split_host_port("example.onion").is_err());
+    }
+}
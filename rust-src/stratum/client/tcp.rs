@@ -0,0 +1,1777 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # TCP transport
+//!
+//! Support for servers which speak newline-delimited JSON over a raw TCP
+//! connection, as most Electrum servers do, including the notifications
+//! such servers push for subscriptions
+//!
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::cmp::Reverse;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json;
+use serde_json::value::Value;
+
+use {Notification, Params, Request, Response};
+use clock::{Clock, SystemClock};
+use error::{Error, malformed_response};
+use retry::{RetryPolicy, is_connection_error};
+use super::{ids_match, socks5};
+use super::inspector::Inspector;
+
+/// The read buffer capacity of clients not given another with
+/// `TcpClient::with_read_buffer_capacity`
+pub const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+
+/// A handler for control notifications, as set up with
+/// `TcpClient::with_control_notifications`
+pub type ControlHandler = Box<dyn Fn(&Notification) + Send + Sync>;
+
+/// Connects to the first address `addr` resolves to which accepts within
+/// `timeout`, giving the last error if none does
+pub(crate) fn connect_timeout<A: ToSocketAddrs>(addr: &A,
+                                                timeout: Duration)
+                                                -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput,
+                                        "address resolved to nothing");
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Connects to `addr`, given as `host:port`, directly or through a SOCKS5
+/// proxy, applying `timeout` to connecting and to each read and write
+fn connect_stream(addr: &str,
+                  timeout: Option<Duration>,
+                  proxy: Option<SocketAddr>)
+                  -> Result<TcpStream, Error> {
+    if let Some(proxy) = proxy {
+        let (host, port) = socks5::split_host_port(addr)?;
+        return socks5::connect(&proxy, host, port, timeout);
+    }
+    connect_direct(&addr, timeout)
+}
+
+/// Connects to `addr` without a proxy, applying `timeout` to connecting
+/// and to each read and write
+pub(crate) fn connect_direct<A: ToSocketAddrs>(addr: &A,
+                                               timeout: Option<Duration>)
+                                               -> Result<TcpStream, Error> {
+    match timeout {
+        Some(timeout) => {
+            let stream = connect_timeout(addr, timeout).map_err(timeout_error)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            Ok(stream)
+        }
+        None => Ok(TcpStream::connect(addr)?),
+    }
+}
+
+/// Gives `Error::Timeout` for an IO error from a socket timeout
+pub(crate) fn timeout_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+        _ => Error::Io(e),
+    }
+}
+
+/// Whether `stream` is still open at the other end, found without blocking
+/// by peeking at what has arrived on it
+pub(crate) fn is_open(stream: &TcpStream) -> bool {
+    if stream.peer_addr().is_err() || stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0];
+    let open = match stream.peek(&mut byte) {
+        // A read of nothing is the server's end of the stream
+        Ok(n) => n > 0,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Where notifications for one subscription go
+struct Subscription {
+    method: String,
+    params: Vec<Value>,
+    // The request's own parameters, to subscribe with again on reconnecting
+    subscribed: Params,
+    sender: Sender<Notification>,
+}
+
+impl Subscription {
+    /// Whether `notification` is about this subscription: its method is the
+    /// one subscribed to and its parameters start with the subscription's,
+    /// as a scripthash status update starts with the scripthash
+    fn matches(&self, notification: &Notification) -> bool {
+        notification.method == self.method && notification.params.starts_with(&self.params)
+    }
+}
+
+/// Control notification methods, and the handler they go to
+struct ControlRoute {
+    methods: Vec<String>,
+    handler: ControlHandler,
+}
+
+/// A connection speaking newline-delimited JSON
+///
+/// The read buffer is kept for the life of the connection, so that bytes
+/// read past the end of one line stay buffered for the next. Lines without
+/// an id are notifications, which are passed to any matching subscriptions
+/// whenever they are read, or to the control handler if their method is a
+/// control one.
+pub(crate) struct LineStream<S: Read + Write> {
+    reader: BufReader<S>,
+    capacity: usize,
+    // The start of a line whose read was interrupted, e.g. by a timeout
+    partial: String,
+    subscriptions: Vec<Subscription>,
+    control: Option<ControlRoute>,
+    inspector: Option<Arc<dyn Inspector>>,
+    // When a request last went out, for keep-alive pings to wait on
+    last_sent: Instant,
+    // When the connection last went back to a pool
+    last_used: Instant,
+    clock: Arc<dyn Clock>,
+    // Set when a keep-alive ping fails, a request times out or gets another's
+    // response, or the server closes the connection, so the next request
+    // reconnects
+    pub(crate) broken: bool,
+}
+
+impl<S: Read + Write> LineStream<S> {
+    /// Wraps a connected stream, buffering reads with `capacity`
+    pub(crate) fn new(stream: S, capacity: usize) -> LineStream<S> {
+        LineStream {
+            reader: BufReader::with_capacity(capacity, stream),
+            capacity,
+            partial: String::new(),
+            subscriptions: vec![],
+            control: None,
+            inspector: None,
+            last_sent: Instant::now(),
+            last_used: Instant::now(),
+            clock: Arc::new(SystemClock),
+            broken: false,
+        }
+    }
+
+    /// Rebuffers the stream with `capacity`, discarding anything buffered
+    pub(crate) fn with_capacity(self, capacity: usize) -> LineStream<S> {
+        LineStream {
+            subscriptions: self.subscriptions,
+            control: self.control,
+            inspector: self.inspector,
+            clock: self.clock,
+            ..LineStream::new(self.reader.into_inner(), capacity)
+        }
+    }
+
+    /// Shows `inspector` each line written and read, notifications included
+    pub(crate) fn with_inspector(mut self,
+                                 inspector: Option<Arc<dyn Inspector>>)
+                                 -> LineStream<S> {
+        self.inspector = inspector;
+        self
+    }
+
+    /// Times requests, and the keep-alive pings waiting on them, by `clock`
+    pub(crate) fn with_clock(mut self, clock: Arc<dyn Clock>) -> LineStream<S> {
+        self.last_sent = clock.now();
+        self.last_used = self.last_sent;
+        self.clock = clock;
+        self
+    }
+
+    /// Accessor for the read buffer capacity
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Accessor for the underlying stream
+    pub(crate) fn get_ref(&self) -> &S {
+        self.reader.get_ref()
+    }
+
+    /// Writes a request as one line, then reads lines until the response,
+    /// dispatching any notifications read on the way
+    ///
+    /// A socket timeout gives `Error::Timeout`, and a response to another
+    /// request `Error::NonceMismatch`. After either, the response still due
+    /// would be read as the next request's, so the connection is marked
+    /// broken for the next request to replace it.
+    pub(crate) fn round_trip(&mut self, request: &Request) -> Result<Response, Error> {
+        let result = self.exchange(request);
+        if let Err(Error::Timeout) | Err(Error::NonceMismatch) = result {
+            self.broken = true;
+        }
+        result
+    }
+
+    fn exchange(&mut self, request: &Request) -> Result<Response, Error> {
+        let mut request_json = serde_json::to_string(request)?;
+        if let Some(ref inspector) = self.inspector {
+            inspector.on_request(&request_json);
+        }
+        request_json.push('\n');
+        self.last_sent = self.clock.now();
+        // Writes go straight to the stream, past the read buffer
+        self.reader.get_mut().write_all(request_json.as_bytes()).map_err(timeout_error)?;
+        self.reader.get_mut().flush().map_err(timeout_error)?;
+
+        loop {
+            let message = match self.read_message() {
+                Err(Error::Io(e)) => return Err(timeout_error(e)),
+                message => message?,
+            };
+            if let Some(response) = message {
+                if !ids_match(&request.id, &response.id) {
+                    return Err(Error::NonceMismatch);
+                }
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Sends a subscription request, registering for its notifications
+    /// before any can arrive
+    ///
+    /// The registration is dropped again if the server answers with an
+    /// error, leaving the receiver disconnected.
+    pub(crate) fn subscribe(&mut self,
+                            request: &Request)
+                            -> Result<(Response, Receiver<Notification>), Error> {
+        let (sender, receiver) = channel();
+        // Notifications are positional, so named parameters match any
+        self.subscriptions.push(Subscription {
+            method: request.method.clone(),
+            params: request.params.as_positional().map(<[Value]>::to_vec).unwrap_or_default(),
+            subscribed: request.params.clone(),
+            sender,
+        });
+        let response = self.round_trip(request);
+        if response.as_ref().map(|r| r.error.is_some()).unwrap_or(true) {
+            self.subscriptions.pop();
+        }
+        Ok((response?, receiver))
+    }
+
+    /// Sends the subscription requests of this stream's subscriptions again,
+    /// as after reconnecting, so their receivers keep receiving
+    ///
+    /// Each answer is passed on as a notification of the subscription's
+    /// parameters followed by the result, which is the form Electrum servers
+    /// push updates in, so a change missed while disconnected is still seen.
+    /// A subscription the server refuses ends. If the connection fails, the
+    /// subscriptions not yet sent again are kept for the next reconnect.
+    pub(crate) fn resubscribe(&mut self, nonce: &AtomicU64) -> Result<(), Error> {
+        let mut pending = mem::take(&mut self.subscriptions).into_iter();
+        while let Some(subscription) = pending.next() {
+            let request = Request {
+                method: subscription.method.clone(),
+                params: subscription.subscribed.clone(),
+                id: From::from(nonce.fetch_add(1, Ordering::SeqCst) + 1),
+                jsonrpc: None,
+            };
+            self.subscriptions.push(subscription);
+            match self.round_trip(&request) {
+                Ok(Response { error: None, result, .. }) => {
+                    let subscription = self.subscriptions.last().unwrap();
+                    let mut params = subscription.params.clone();
+                    params.push(result.unwrap_or(Value::Null));
+                    let update = Notification { method: request.method, params };
+                    if subscription.sender.send(update).is_err() {
+                        self.subscriptions.pop();
+                    }
+                }
+                Ok(_) => {
+                    self.subscriptions.pop();
+                }
+                Err(e) => {
+                    self.subscriptions.extend(pending);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and dispatches notifications until a read times out, returning
+    /// how many were read
+    ///
+    /// This is for reading notifications while no request is in flight, so
+    /// the stream must have a read timeout. Any response read is stale,
+    /// since requests are answered in turn, and is discarded.
+    pub(crate) fn poll_notifications(&mut self) -> Result<usize, Error> {
+        let mut count = 0;
+        loop {
+            match self.read_message() {
+                Ok(Some(_)) => {}
+                Ok(None) => count += 1,
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock ||
+                                         e.kind() == io::ErrorKind::TimedOut => {
+                    return Ok(count);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads one line, returning it if it is a response and dispatching it
+    /// if it is a notification
+    fn read_message(&mut self) -> Result<Option<Response>, Error> {
+        if self.partial.capacity() == 0 {
+            self.partial.reserve(self.capacity);
+        }
+        if self.reader.read_line(&mut self.partial)? == 0 {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                "server closed the connection")));
+        }
+        let line = mem::take(&mut self.partial);
+        // A line cut short by the end of the stream may still be whole, as
+        // from a server closing the connection after each response
+        if !line.ends_with('\n') {
+            self.broken = true;
+        }
+        if let Some(ref inspector) = self.inspector {
+            inspector.on_response(line.trim_end());
+        }
+
+        let message: Value = serde_json::from_str(&line)
+            .map_err(|e| malformed_response(e, line.trim_end()))?;
+        let is_notification = message.get("method").is_some() &&
+                              message.get("id").map(Value::is_null).unwrap_or(true);
+        if is_notification {
+            self.dispatch(serde_json::value::from_value(message)?);
+            Ok(None)
+        } else {
+            Ok(Some(serde_json::value::from_value(message)?))
+        }
+    }
+
+    /// Passes a notification to the control handler if its method is a
+    /// control one, and otherwise to each matching subscription, forgetting
+    /// those whose receivers have gone
+    fn dispatch(&mut self, notification: Notification) {
+        if let Some(ref control) = self.control {
+            if control.methods.contains(&notification.method) {
+                (control.handler)(&notification);
+                return;
+            }
+        }
+        self.subscriptions.retain(|subscription| {
+            !subscription.matches(&notification) ||
+            subscription.sender.send(notification.clone()).is_ok()
+        });
+    }
+}
+
+/// Connections to a `TcpClient`'s server besides its main one, for
+/// requests which find the main connection busy
+///
+/// Each connection is opened when first needed, keeps its own read buffer
+/// and is used by one request at a time, going back to the pool once the
+/// request is answered. One left idle past the client's idle timeout is
+/// replaced when next taken.
+struct TcpPool {
+    // How many connections may be open besides the main one
+    extra: usize,
+    capacity: usize,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<LineStream<TcpStream>>,
+    open: usize,
+    // Requests waiting for a connection, by priority and then ticket, so
+    // that equal priorities are served in the order they came
+    waiting: Vec<(Priority, u64)>,
+    next_ticket: u64,
+}
+
+impl PoolState {
+    /// Whether the waiter holding `ticket` is the one to serve next
+    fn is_next(&self, ticket: u64) -> bool {
+        self.waiting
+            .iter()
+            .min_by_key(|&&(priority, ticket)| (Reverse(priority), ticket))
+            .is_some_and(|&(_, next)| next == ticket)
+    }
+}
+
+impl TcpPool {
+    fn new(extra: usize, capacity: usize) -> TcpPool {
+        TcpPool {
+            extra,
+            capacity,
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+                waiting: vec![],
+                next_ticket: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes the main connection or an idle one, or opens one if there is
+    /// room, waiting while every connection is in use or a waiter of higher
+    /// priority is ahead, and giving `None` if a new one cannot be opened
+    fn take<'a>(&self, client: &'a TcpClient, priority: Priority) -> Option<Checkout<'a>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiting.push((priority, ticket));
+            let main = loop {
+                if state.is_next(ticket) {
+                    if let Ok(main) = client.connection.try_lock() {
+                        break Some(main);
+                    }
+                    if !state.idle.is_empty() || state.open < self.extra {
+                        break None;
+                    }
+                } else if !state.idle.is_empty() || state.open < self.extra ||
+                          client.connection.try_lock().is_ok() {
+                    // The main connection is freed by sequences without a
+                    // wake up, so the waiter ahead may still be asleep
+                    self.released.notify_all();
+                }
+                state = self.released.wait(state).unwrap();
+            };
+            state.waiting.retain(|&(_, waiter)| waiter != ticket);
+            // The waiter now next may find a connection free too
+            self.released.notify_all();
+            if let Some(main) = main {
+                return Some(Checkout::Main(main));
+            }
+            match state.idle.pop() {
+                // A connection idle for that long has likely been dropped
+                // by the server, so it is closed and a fresh one opened in
+                // its place
+                Some(ref connection) if client.is_stale(connection) => {}
+                Some(connection) => return Some(Checkout::Pooled(connection)),
+                None => state.open += 1,
+            }
+        }
+        match connect_stream(&client.addr, client.timeout, client.proxy) {
+            Ok(stream) => {
+                Some(Checkout::Pooled(LineStream::new(stream, self.capacity)
+                    .with_clock(client.clock.clone())
+                    .with_inspector(client.inspector.clone())))
+            }
+            Err(_) => {
+                self.state.lock().unwrap().open -= 1;
+                self.released.notify_all();
+                None
+            }
+        }
+    }
+
+    /// Returns a connection taken with `take`
+    fn put(&self, mut connection: LineStream<TcpStream>) {
+        connection.last_used = connection.clock.now();
+        self.state.lock().unwrap().idle.push(connection);
+        self.released.notify_all();
+    }
+
+    /// Wakes waiters once the main connection is unlocked, taking the pool
+    /// lock first so that none misses it between checking and waiting
+    fn release_main(&self) {
+        drop(self.state.lock().unwrap());
+        self.released.notify_all();
+    }
+}
+
+/// A connection taken from a `TcpPool`
+enum Checkout<'a> {
+    Main(MutexGuard<'a, LineStream<TcpStream>>),
+    Pooled(LineStream<TcpStream>),
+}
+
+/// How urgently a request sent with `TcpClient::send_request_with_priority`
+/// should be given a connection when they are all in use
+///
+/// Requests waiting for a pooled connection take the next one to come free
+/// highest priority first, and in the order they came among the same
+/// priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Background work, such as syncing history
+    Low,
+    /// What `send_request` uses
+    Normal,
+    /// Requests a user is waiting on, such as a refresh
+    High,
+}
+
+/// A handle to a remote JSONRPC server over TCP
+///
+/// The connection is held open and reused for every request. Requests
+/// from several threads are serialized by a lock around it, so each waits
+/// for the previous response before being written, unless the client is
+/// given more connections with `with_pool_size`.
+pub struct TcpClient {
+    addr: String,
+    // Shared with the keep-alive thread, if there is one
+    connection: Arc<Mutex<LineStream<TcpStream>>>,
+    nonce: Arc<AtomicU64>,
+    timeout: Option<Duration>,
+    proxy: Option<SocketAddr>,
+    retry_policy: RetryPolicy,
+    keepalive: Option<Duration>,
+    // Shared with the keep-alive thread, which skips pings while it is set
+    keepalive_paused: Arc<AtomicBool>,
+    clock: Arc<dyn Clock>,
+    inspector: Option<Arc<dyn Inspector>>,
+    pool: Option<TcpPool>,
+    pool_idle_timeout: Option<Duration>,
+}
+
+impl TcpClient {
+    /// Connects to the server at `addr`, given as `host:port`
+    ///
+    /// The client has no timeout, so a server which never replies will block
+    /// `send_request` forever; use `connect_timeout` to avoid this.
+    pub fn new(addr: &str) -> Result<TcpClient, Error> {
+        TcpClient::open(addr, None, None)
+    }
+
+    /// Connects to the server at `addr`, given as `host:port`, giving up
+    /// after `timeout`
+    ///
+    /// The timeout also applies to each read and write on the connection,
+    /// and requests which exceed it fail with `Error::Timeout`, as does
+    /// connecting.
+    pub fn connect_timeout(addr: &str, timeout: Duration) -> Result<TcpClient, Error> {
+        TcpClient::open(addr, Some(timeout), None)
+    }
+
+    /// Connects to the server at `addr`, given as `host:port`, through the
+    /// SOCKS5 proxy at `proxy`, such as a local Tor daemon
+    ///
+    /// The host is resolved by the proxy, so it may be a .onion address.
+    /// Gives `Error::Proxy` if the proxy cannot reach the server.
+    pub fn connect_via_socks5(addr: &str, proxy: SocketAddr) -> Result<TcpClient, Error> {
+        TcpClient::open(addr, None, Some(proxy))
+    }
+
+    fn open(addr: &str,
+            timeout: Option<Duration>,
+            proxy: Option<SocketAddr>)
+            -> Result<TcpClient, Error> {
+        let stream = connect_stream(addr, timeout, proxy)?;
+        Ok(TcpClient {
+            addr: addr.to_owned(),
+            connection: Arc::new(Mutex::new(LineStream::new(stream,
+                                                            DEFAULT_READ_BUFFER_CAPACITY))),
+            nonce: Arc::new(AtomicU64::new(0)),
+            timeout,
+            proxy,
+            retry_policy: RetryPolicy::default(),
+            keepalive: None,
+            keepalive_paused: Arc::new(AtomicBool::new(false)),
+            clock: Arc::new(SystemClock),
+            inspector: None,
+            pool: None,
+            pool_idle_timeout: None,
+        })
+    }
+
+    /// Sets how requests are resent when the connection turns out to have
+    /// dropped, reconnecting first; see `RetryPolicy`
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> TcpClient {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Accessor for the retry policy
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy
+    }
+
+    /// Replaces the connection with a fresh one to the same server, as
+    /// connected to originally
+    ///
+    /// Anything buffered on the old connection is discarded, and its
+    /// subscriptions end, disconnecting their receivers; subscribe again on
+    /// the new connection to keep receiving notifications.
+    pub fn reconnect(&self) -> Result<(), Error> {
+        let mut connection = self.connection.lock().unwrap();
+        self.reconnect_locked(&mut connection)?;
+        connection.subscriptions.clear();
+        Ok(())
+    }
+
+    /// Replaces the connection with a fresh one, carrying its subscriptions
+    /// over without sending them again
+    fn reconnect_locked(&self, connection: &mut LineStream<TcpStream>) -> Result<(), Error> {
+        let stream = connect_stream(&self.addr, self.timeout, self.proxy)?;
+        let mut fresh = LineStream::new(stream, connection.capacity())
+            .with_clock(self.clock.clone())
+            .with_inspector(self.inspector.clone());
+        fresh.control = connection.control.take();
+        fresh.subscriptions = mem::take(&mut connection.subscriptions);
+        *connection = fresh;
+        Ok(())
+    }
+
+    /// Replaces a dropped connection, subscribing again to what it was
+    /// subscribed to
+    fn restore_locked(&self, connection: &mut LineStream<TcpStream>) -> Result<(), Error> {
+        self.reconnect_locked(connection)?;
+        connection.resubscribe(&self.nonce)
+    }
+
+    /// Sets the initial capacity of the buffers responses are read into
+    ///
+    /// Each response line starts out with this much room, so clients
+    /// fetching large results, such as long histories, can avoid repeatedly
+    /// growing it. This should be set before any request is sent, since
+    /// anything already buffered is discarded.
+    pub fn with_read_buffer_capacity(self, capacity: usize) -> TcpClient {
+        let connection = unshare(self.connection);
+        let client = TcpClient {
+            connection: Arc::new(Mutex::new(connection.with_capacity(capacity))),
+            pool: self.pool.map(|pool| TcpPool::new(pool.extra, capacity)),
+            ..self
+        };
+        if let Some(interval) = client.keepalive {
+            client.spawn_keepalive(interval);
+        }
+        client
+    }
+
+    /// Accessor for the read buffer capacity
+    pub fn read_buffer_capacity(&self) -> usize {
+        self.connection.lock().unwrap().capacity()
+    }
+
+    /// Passes notifications with any of `methods` to `handler` instead of
+    /// the subscriptions, so that protocol housekeeping, such as a server
+    /// announcing a protocol change or deprecation, stays out of the way of
+    /// application notifications
+    ///
+    /// The handler runs on whichever thread reads the notification, while
+    /// the connection is locked, so it should not send requests on this
+    /// client. Unlike subscriptions, it is kept across reconnects.
+    pub fn with_control_notifications<F>(self, methods: Vec<String>, handler: F) -> TcpClient
+        where F: Fn(&Notification) + Send + Sync + 'static
+    {
+        self.connection.lock().unwrap().control = Some(ControlRoute {
+            methods,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Shows `inspector` each request line just before it is sent, and each
+    /// line the server sends just before it is parsed, so that
+    /// notifications pushed for subscriptions are seen as well as responses
+    ///
+    /// Lines the server sends are passed to `Inspector::on_response`, on
+    /// whichever thread reads them, and are kept across reconnects.
+    pub fn with_inspector(mut self, inspector: Arc<dyn Inspector>) -> TcpClient {
+        self.connection.lock().unwrap().inspector = Some(inspector.clone());
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// Sends `server.ping` whenever the connection has been idle for
+    /// `interval`, so that the server does not drop it
+    ///
+    /// The pings come from a background thread, which takes the connection
+    /// lock like any other request and stops once the client is dropped.
+    /// Each uses up a nonce. A ping which fails leaves the connection to be
+    /// replaced before the next request. Clients do not ping unless this is
+    /// called.
+    pub fn with_keepalive(mut self, interval: Duration) -> TcpClient {
+        if self.keepalive.is_none() {
+            self.spawn_keepalive(interval);
+        }
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Lets up to `size` connections to the server be open at once, so that
+    /// requests from several threads are sent in parallel
+    ///
+    /// Requests go over the main connection when it is free, and otherwise
+    /// over another, opened as needed and then kept for later requests.
+    /// Once all `size` are busy, requests wait for the next to come free,
+    /// served by `Priority`. Subscriptions, sequences, control
+    /// notifications and keep-alive pings stay on the main connection, and
+    /// notifications arriving on the others are dropped. The default size
+    /// is 1, the main connection alone.
+    pub fn with_pool_size(mut self, size: usize) -> TcpClient {
+        let capacity = self.read_buffer_capacity();
+        self.pool = if size > 1 {
+            Some(TcpPool::new(size - 1, capacity))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Accessor for the pool size, the most connections open at once
+    pub fn pool_size(&self) -> usize {
+        self.pool.as_ref().map_or(1, |pool| pool.extra + 1)
+    }
+
+    /// Replaces a pooled connection, rather than reusing it, once it has
+    /// been idle for longer than `timeout` by the client's clock, since
+    /// servers tend to drop idle connections and the next request on it
+    /// would fail
+    ///
+    /// This only applies to the connections `with_pool_size` adds, not the
+    /// main one. By default pooled connections are kept however long they
+    /// are idle.
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> TcpClient {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Accessor for the pool idle timeout, if one is set
+    pub fn pool_idle_timeout(&self) -> Option<Duration> {
+        self.pool_idle_timeout
+    }
+
+    fn is_stale(&self, connection: &LineStream<TcpStream>) -> bool {
+        self.pool_idle_timeout
+            .is_some_and(|timeout| self.clock.now().duration_since(connection.last_used) > timeout)
+    }
+
+    /// Accessor for the keep-alive interval, if pings are sent
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Stops keep-alive pings until `resume_keepalive` is called, such as
+    /// while the application knows it will be idle and would rather let
+    /// the server drop the connection
+    ///
+    /// A ping already being sent is still answered. This does nothing if
+    /// the client does not ping.
+    pub fn pause_keepalive(&self) {
+        self.keepalive_paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Sends keep-alive pings again after `pause_keepalive`, the next once
+    /// the connection has been idle for the interval
+    pub fn resume_keepalive(&self) {
+        self.keepalive_paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Sets the clock keep-alive pings wait on and measure idleness by, and
+    /// retries back off by
+    ///
+    /// Socket timeouts always use real time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> TcpClient {
+        {
+            let mut connection = self.connection.lock().unwrap();
+            connection.last_sent = clock.now();
+            connection.clock = clock.clone();
+        }
+        self.clock = clock;
+        self
+    }
+
+    fn spawn_keepalive(&self, interval: Duration) {
+        let connection = Arc::downgrade(&self.connection);
+        let nonce = self.nonce.clone();
+        let paused = self.keepalive_paused.clone();
+        thread::spawn(move || keep_alive(&connection, &nonce, &paused, interval));
+    }
+
+    /// Sends a request to the server, returning the line it answers with
+    ///
+    /// Requests on one client are sent one at a time, each waiting for its
+    /// response before the next is written, unless `with_pool_size` gives
+    /// the client more connections. If the connection turns out to
+    /// have been closed, the client reconnects and resends the request as
+    /// its retry policy allows, so a request the server saw before dropping
+    /// the connection may reach it twice. A connection the server closed
+    /// after answering, as some do after every response, is replaced before
+    /// sending, whatever the retry policy, as is one left with a response
+    /// still due by a request which timed out. Subscriptions are sent again
+    /// on each replacement connection, as described at `subscribe`.
+    pub fn send_request(&self, request: &Request) -> Result<Response, Error> {
+        self.send_request_with_priority(request, Priority::Normal)
+    }
+
+    /// Sends a request as `send_request` does, ahead of requests of lower
+    /// `priority` in waiting for a connection
+    ///
+    /// Priorities only matter with `with_pool_size`, as a client with one
+    /// connection sends requests in whichever order its lock is taken.
+    pub fn send_request_with_priority(&self,
+                                      request: &Request,
+                                      priority: Priority)
+                                      -> Result<Response, Error> {
+        if let Some(ref pool) = self.pool {
+            match pool.take(self, priority) {
+                Some(Checkout::Main(mut main)) => {
+                    let result = self.send_locked(&mut main, request);
+                    drop(main);
+                    pool.release_main();
+                    return result;
+                }
+                Some(Checkout::Pooled(mut connection)) => {
+                    let result = self.send_locked(&mut connection, request);
+                    if let Err(ref e) = result {
+                        connection.broken |= is_connection_error(e);
+                    }
+                    pool.put(connection);
+                    return result;
+                }
+                None => {}
+            }
+        }
+        self.send_locked(&mut self.connection.lock().unwrap(), request)
+    }
+
+    /// Runs `f` with the connection to itself, so that the requests it
+    /// sends through the given `Sequence` go out in order, with no request
+    /// from another thread between them
+    ///
+    /// Other threads' requests wait until `f` returns. This suits exchanges
+    /// such as subscribing and then immediately querying what was
+    /// subscribed to.
+    pub fn sequence<F, T>(&self, f: F) -> T
+        where F: FnOnce(&mut Sequence) -> T
+    {
+        let mut sequence = Sequence {
+            client: self,
+            connection: self.connection.lock().unwrap(),
+        };
+        f(&mut sequence)
+    }
+
+    fn send_locked(&self,
+                   connection: &mut LineStream<TcpStream>,
+                   request: &Request)
+                   -> Result<Response, Error> {
+        // Nothing has been sent yet, so a connection the server has closed
+        // since the last response is replaced whatever the retry policy
+        if connection.broken || !is_open(connection.get_ref()) {
+            self.restore_locked(connection)?;
+        }
+        let mut retries = 0;
+        loop {
+            match connection.round_trip(request) {
+                Err(ref e) if is_connection_error(e) &&
+                              retries < self.retry_policy.max_retries => {
+                    retries += 1;
+                    self.clock.sleep(self.retry_policy.backoff);
+                    self.restore_locked(connection)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sends a subscription request, such as `blockchain.scripthash.subscribe`,
+    /// returning its response along with a receiver for the notifications
+    /// the server then pushes for it
+    ///
+    /// A notification is passed on if its method is the request's and its
+    /// parameters start with the request's, so subscribing to one scripthash
+    /// only receives that scripthash's updates. Notifications are read as
+    /// they arrive ahead of responses to later requests, or by
+    /// `poll_notifications` while the client is otherwise idle. If the
+    /// server answers with an error, the receiver is already disconnected.
+    ///
+    /// When a request finds the connection dropped and reconnects, it first
+    /// subscribes again, and the server's answer reaches the receiver as a
+    /// notification, so an update missed meanwhile is not lost. Only
+    /// `reconnect` ends subscriptions.
+    pub fn subscribe(&self,
+                     request: &Request)
+                     -> Result<(Response, Receiver<Notification>), Error> {
+        self.connection.lock().unwrap().subscribe(request)
+    }
+
+    /// Reads notifications the server sends within `timeout`, passing them
+    /// to their subscriptions, and returns how many were read
+    ///
+    /// Notifications are otherwise only read while waiting for a response.
+    pub fn poll_notifications(&self, timeout: Duration) -> Result<usize, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        connection.get_ref().set_read_timeout(Some(timeout))?;
+        let result = connection.poll_notifications();
+        connection.get_ref().set_read_timeout(self.timeout)?;
+        result
+    }
+
+    /// Builds a request
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        let nonce = self.nonce.fetch_add(1, Ordering::SeqCst) + 1;
+        Request {
+            method: name,
+            params: Params::Positional(params),
+            id: From::from(nonce),
+            jsonrpc: None,
+        }
+    }
+
+    /// Accessor for the last-used nonce
+    pub fn last_nonce(&self) -> u64 {
+        self.nonce.load(Ordering::SeqCst)
+    }
+
+    /// Whether the main connection is established, checked locally without
+    /// sending anything, such as for showing connection status
+    ///
+    /// This is false once the server has closed the connection or a request
+    /// or keep-alive ping has found it dropped, until the next request
+    /// reconnects. A connection busy with a request counts as established.
+    pub fn is_connected(&self) -> bool {
+        match self.connection.try_lock() {
+            Ok(connection) => !connection.broken && is_open(connection.get_ref()),
+            Err(_) => true,
+        }
+    }
+
+    /// Accessor for the timeout, if one is set
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Accessor for the address the client is connected to
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+}
+
+/// Takes a connection back from the keep-alive thread sharing it
+///
+/// The thread only holds the connection while pinging, so waiting for it
+/// gets the connection back; the thread then stops, and is started afresh
+/// by the caller if it is still wanted.
+pub(crate) fn unshare<S: Read + Write>(mut shared: Arc<Mutex<LineStream<S>>>) -> LineStream<S> {
+    loop {
+        match Arc::try_unwrap(shared) {
+            Ok(connection) => return connection.into_inner().unwrap(),
+            Err(still_shared) => {
+                shared = still_shared;
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+/// Pings the server over `connection` whenever it has been idle for
+/// `interval`, by the connection's clock, and `paused` is not set, until
+/// the client holding it is dropped
+pub(crate) fn keep_alive<S: Read + Write>(connection: &Weak<Mutex<LineStream<S>>>,
+                                          nonce: &AtomicU64,
+                                          paused: &AtomicBool,
+                                          interval: Duration) {
+    let mut wait = interval;
+    loop {
+        // The clock is looked up each time, as the client may replace it
+        let clock = match connection.upgrade() {
+            Some(connection) => connection.lock().unwrap().clock.clone(),
+            None => return,
+        };
+        clock.sleep(wait);
+        let connection = match connection.upgrade() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut connection = connection.lock().unwrap();
+        let idle = connection.clock.now().duration_since(connection.last_sent);
+        if idle < interval {
+            wait = interval - idle;
+            continue;
+        }
+        wait = interval;
+        // A broken connection is left for the next request to replace
+        if !connection.broken && !paused.load(Ordering::SeqCst) {
+            let ping = Request {
+                method: "server.ping".to_owned(),
+                params: Params::default(),
+                id: From::from(nonce.fetch_add(1, Ordering::SeqCst) + 1),
+                jsonrpc: None,
+            };
+            connection.broken = connection.round_trip(&ping).is_err();
+        }
+    }
+}
+
+/// Exclusive use of a `TcpClient`'s connection, from `TcpClient::sequence`
+pub struct Sequence<'a> {
+    client: &'a TcpClient,
+    connection: MutexGuard<'a, LineStream<TcpStream>>,
+}
+
+impl<'a> Sequence<'a> {
+    /// Sends a request, as with `TcpClient::send_request`
+    pub fn send_request(&mut self, request: &Request) -> Result<Response, Error> {
+        self.client.send_locked(&mut self.connection, request)
+    }
+
+    /// Sends a subscription request, as with `TcpClient::subscribe`
+    pub fn subscribe(&mut self,
+                     request: &Request)
+                     -> Result<(Response, Receiver<Notification>), Error> {
+        self.connection.subscribe(request)
+    }
+
+    /// Builds a request, as with `TcpClient::build_request`
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        self.client.build_request(name, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use serde_json;
+    use serde_json::Value;
+
+    use Request;
+    use clock::MockClock;
+    use error::Error;
+    use retry::RetryPolicy;
+    use test_utils::{line_server, reply, reply_error, tcp_server};
+    use super::{DEFAULT_READ_BUFFER_CAPACITY, Priority, TcpClient};
+    use super::super::inspector::Inspector;
+
+    #[test]
+    fn test_round_trip() {
+        let addr = line_server(|req| reply(req.id, Value::String(req.method)));
+        let client = TcpClient::new(&addr).unwrap();
+
+        for method in &["server.ping", "server.banner"] {
+            let req = client.build_request(method.to_string(), vec![]);
+            let response = client.send_request(&req).unwrap();
+            assert_eq!(response.id, req.id);
+            assert_eq!(response.into_result::<String>().unwrap(), *method);
+        }
+        assert_eq!(client.last_nonce(), 2);
+    }
+
+    #[test]
+    fn test_read_buffer_capacity() {
+        let big = "ab".repeat(100_000);
+        let result = big.clone();
+        let addr = line_server(move |req| reply(req.id, Value::String(result.clone())));
+
+        for &capacity in &[16, DEFAULT_READ_BUFFER_CAPACITY, 256 * 1024] {
+            let client = TcpClient::new(&addr).unwrap().with_read_buffer_capacity(capacity);
+            assert_eq!(client.read_buffer_capacity(), capacity);
+            let req = client.build_request("blockchain.transaction.get".to_owned(), vec![]);
+            assert_eq!(client.send_request(&req).unwrap().into_result::<String>().unwrap(), big);
+        }
+        assert_eq!(TcpClient::new(&addr).unwrap().read_buffer_capacity(),
+                   DEFAULT_READ_BUFFER_CAPACITY);
+    }
+
+    #[test]
+    fn test_nonce_mismatch() {
+        let addr = line_server(|req| {
+            reply(Value::from(req.id.as_u64().unwrap() + 10), Value::Null)
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::NonceMismatch) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout_recovers() {
+        let addr = line_server(|req| {
+            if req.method == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            reply(req.id, Value::String(req.method))
+        });
+        let client = TcpClient::connect_timeout(&addr, Duration::from_millis(100)).unwrap();
+
+        let req = client.build_request("slow".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The late reply is left behind with the old connection
+        for _ in 0..3 {
+            let req = client.build_request("server.ping".to_owned(), vec![]);
+            let response = client.send_request(&req).unwrap();
+            assert_eq!(response.into_result::<String>().unwrap(), "server.ping");
+        }
+    }
+
+    #[test]
+    fn test_partial_reads() {
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            for piece in &[r#"{"result": "pa"#, r#"rtial", "error": null,"#, " \"id\": 1}\n"] {
+                writer.write_all(piece.as_bytes()).unwrap();
+                writer.flush().unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let response = client.send_request(&req).unwrap();
+        assert_eq!(response.into_result::<String>().unwrap(), "partial");
+    }
+
+    #[test]
+    fn test_several_lines_in_one_packet() {
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(b"{\"result\": \"first\", \"error\": null, \"id\": 1}\n\
+                               {\"result\": \"second\", \"error\": null, \"id\": 2}\n")
+                .unwrap();
+            // Wait for the second request before closing
+            reader.read_line(&mut line).unwrap();
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let first = client.build_request("server.ping".to_owned(), vec![]);
+        let second = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(client.send_request(&first).unwrap().into_result::<String>().unwrap(),
+                   "first");
+        assert_eq!(client.send_request(&second).unwrap().into_result::<String>().unwrap(),
+                   "second");
+    }
+
+    fn notification(method: &str, params: Value) -> String {
+        format!("{}\n", json!({"jsonrpc": "2.0", "method": method, "params": params}))
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+
+            // A notification arrives before the subscription's own response
+            reader.read_line(&mut line).unwrap();
+            let status = |status: &str| notification("blockchain.scripthash.subscribe",
+                                                     json!(["abcd", status]));
+            writer.write_all(status("first").as_bytes()).unwrap();
+            writer.write_all(b"{\"result\": \"first\", \"error\": null, \"id\": 1}\n").unwrap();
+
+            // Others arrive ahead of a later response, or while idle
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(notification("blockchain.scripthash.subscribe",
+                                          json!(["other", "x"]))
+                    .as_bytes())
+                .unwrap();
+            writer.write_all(status("second").as_bytes()).unwrap();
+            writer.write_all(b"{\"result\": null, \"error\": null, \"id\": 2}\n").unwrap();
+            writer.write_all(status("third").as_bytes()).unwrap();
+            reader.read_line(&mut line).unwrap();
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("blockchain.scripthash.subscribe".to_owned(),
+                                       vec![Value::from("abcd")]);
+        let (response, notifications) = client.subscribe(&req).unwrap();
+        assert_eq!(response.into_result::<String>().unwrap(), "first");
+
+        let ping = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&ping).is_ok());
+        assert_eq!(client.poll_notifications(Duration::from_millis(200)).unwrap(), 1);
+
+        let statuses: Vec<Value> = notifications.try_iter().map(|n| n.params[1].clone()).collect();
+        assert_eq!(statuses, vec![json!("first"), json!("second"), json!("third")]);
+    }
+
+    #[test]
+    fn test_resubscribe() {
+        let served = Arc::new(Mutex::new(vec![]));
+        let log = served.clone();
+        let connections = AtomicUsize::new(0);
+        let addr = tcp_server(move |stream| {
+            let connection = connections.fetch_add(1, Ordering::SeqCst);
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Request = serde_json::from_str(&line.unwrap()).unwrap();
+                log.lock().unwrap().push(request.method.clone());
+                let status = if connection == 0 { "first" } else { "second" };
+                let response = serde_json::to_string(&reply(request.id, json!(status))).unwrap();
+                writer.write_all(format!("{}\n", response).as_bytes()).unwrap();
+                // The first connection drops after the subscription
+                if connection == 0 {
+                    return;
+                }
+            }
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("blockchain.scripthash.subscribe".to_owned(),
+                                       vec![Value::from("abcd")]);
+        let (_, notifications) = client.subscribe(&req).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let ping = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&ping).unwrap();
+
+        // The status answering the repeated subscription is passed on
+        assert_eq!(*served.lock().unwrap(),
+                   vec!["blockchain.scripthash.subscribe",
+                        "blockchain.scripthash.subscribe",
+                        "server.ping"]);
+        let updates: Vec<_> = notifications.try_iter().map(|n| n.params).collect();
+        assert_eq!(updates, vec![vec![json!("abcd"), json!("second")]]);
+
+        // Reconnecting explicitly ends the subscription
+        client.reconnect().unwrap();
+        assert!(notifications.recv().is_err());
+    }
+
+    #[test]
+    fn test_control_notifications() {
+        let addr = tcp_server(|stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(b"{\"result\": null, \"error\": null, \"id\": 1}\n").unwrap();
+            writer.write_all(notification("server.protocol_deprecated", json!(["1.2"]))
+                    .as_bytes())
+                .unwrap();
+            writer.write_all(notification("blockchain.headers.subscribe", json!([{"height": 5}]))
+                    .as_bytes())
+                .unwrap();
+            reader.read_line(&mut line).unwrap();
+        });
+        let (sender, control) = channel();
+        let sender = Mutex::new(sender);
+        let client = TcpClient::new(&addr)
+            .unwrap()
+            .with_control_notifications(vec!["server.protocol_deprecated".to_owned()],
+                                        move |n| sender.lock().unwrap().send(n.clone()).unwrap());
+
+        let req = client.build_request("blockchain.headers.subscribe".to_owned(), vec![]);
+        let (_, notifications) = client.subscribe(&req).unwrap();
+        assert_eq!(client.poll_notifications(Duration::from_millis(200)).unwrap(), 2);
+
+        let control: Vec<_> = control.try_iter().map(|n| n.method).collect();
+        assert_eq!(control, vec!["server.protocol_deprecated"]);
+        let general: Vec<_> = notifications.try_iter().map(|n| n.params).collect();
+        assert_eq!(general, vec![vec![json!({"height": 5})]]);
+    }
+
+    /// An inspector recording what it sees, prefixed with its direction
+    #[derive(Default)]
+    struct Wiretap(Mutex<Vec<String>>);
+
+    impl Inspector for Wiretap {
+        fn on_request(&self, json: &str) {
+            self.0.lock().unwrap().push(format!("> {}", json));
+        }
+
+        fn on_response(&self, json: &str) {
+            self.0.lock().unwrap().push(format!("< {}", json));
+        }
+    }
+
+    #[test]
+    fn test_inspector() {
+        let pushed = notification("blockchain.headers.subscribe", json!([{"height": 2}]));
+        let sent = pushed.clone();
+        let addr = tcp_server(move |stream| {
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            writer.write_all(b"{\"result\": {\"height\": 1}, \"error\": null, \"id\": 1}\n")
+                .unwrap();
+            writer.write_all(sent.as_bytes()).unwrap();
+            reader.read_line(&mut line).unwrap();
+        });
+        let wiretap = Arc::new(Wiretap::default());
+        let client = TcpClient::new(&addr).unwrap().with_inspector(wiretap.clone());
+
+        let req = client.build_request("blockchain.headers.subscribe".to_owned(), vec![]);
+        let (_, notifications) = client.subscribe(&req).unwrap();
+        assert_eq!(client.poll_notifications(Duration::from_millis(200)).unwrap(), 1);
+        assert_eq!(notifications.try_iter().count(), 1);
+
+        // The pushed notification is seen just as it arrived
+        assert_eq!(*wiretap.0.lock().unwrap(),
+                   vec![format!("> {}", serde_json::to_string(&req).unwrap()),
+                        "< {\"result\": {\"height\": 1}, \"error\": null, \"id\": 1}".to_owned(),
+                        format!("< {}", pushed.trim_end())]);
+    }
+
+    #[test]
+    fn test_subscribe_error() {
+        let addr = line_server(|req| reply_error(req.id, -32601, "unknown method"));
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("blockchain.headers.subscribe".to_owned(), vec![]);
+        let (response, notifications) = client.subscribe(&req).unwrap();
+        assert!(response.check_error().is_err());
+        assert!(notifications.recv().is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Accepts the connection but never replies
+        let addr = tcp_server(|_stream| thread::sleep(Duration::from_secs(5)));
+        let client = TcpClient::connect_timeout(&addr, Duration::from_millis(100)).unwrap();
+        assert_eq!(client.timeout(), Some(Duration::from_millis(100)));
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// A line server which counts the connections made to it, and answers
+    /// `per_connection` requests on each, closing it on reading the next
+    fn counting_server(per_connection: usize) -> (String, Arc<AtomicUsize>) {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let addr = tcp_server(move |stream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut writer = stream.try_clone().unwrap();
+            for (answered, line) in BufReader::new(stream).lines().enumerate() {
+                if answered == per_connection {
+                    return;
+                }
+                let request: Request = serde_json::from_str(&line.unwrap()).unwrap();
+                let response = serde_json::to_string(&reply(request.id, Value::Null)).unwrap();
+                writeln!(writer, "{}", response).unwrap();
+            }
+        });
+        (addr, connections)
+    }
+
+    /// A line server which counts the connections made to it, and closes
+    /// each as soon as it has answered one request, ending the response
+    /// with a newline if `newline` is set
+    fn closing_server(newline: bool) -> (String, Arc<AtomicUsize>) {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let addr = tcp_server(move |stream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut writer = stream.try_clone().unwrap();
+            if let Some(Ok(line)) = BufReader::new(stream).lines().next() {
+                let request: Request = serde_json::from_str(&line).unwrap();
+                let mut response = serde_json::to_string(&reply(request.id, json!("ok"))).unwrap();
+                if newline {
+                    response.push('\n');
+                }
+                writer.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (addr, connections)
+    }
+
+    #[test]
+    fn test_pool_priority() {
+        let served = Arc::new(Mutex::new(vec![]));
+        let log = served.clone();
+        let addr = line_server(move |req| {
+            log.lock().unwrap().push(req.method.clone());
+            thread::sleep(Duration::from_millis(100));
+            reply(req.id, Value::Null)
+        });
+        let client = Arc::new(TcpClient::new(&addr).unwrap().with_pool_size(2));
+
+        // With the main connection held, the requests share one other
+        client.sequence(|_| {
+            let send = |method: &str, priority| {
+                let client = client.clone();
+                let method = method.to_owned();
+                let thread = thread::spawn(move || {
+                    let req = client.build_request(method, vec![]);
+                    client.send_request_with_priority(&req, priority).unwrap();
+                });
+                thread::sleep(Duration::from_millis(30));
+                thread
+            };
+            let threads = vec![send("first", Priority::Normal),
+                               send("low", Priority::Low),
+                               send("high", Priority::High)];
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+        assert_eq!(*served.lock().unwrap(), vec!["first", "high", "low"]);
+        assert!(Priority::High > Priority::Normal && Priority::Normal > Priority::Low);
+    }
+
+    #[test]
+    fn test_pool_priority_main_freed() {
+        let served = Arc::new(Mutex::new(vec![]));
+        let log = served.clone();
+        let addr = line_server(move |req| {
+            log.lock().unwrap().push(req.method.clone());
+            if req.method == "first" {
+                thread::sleep(Duration::from_millis(400));
+            }
+            reply(req.id, Value::Null)
+        });
+        let client = Arc::new(TcpClient::new(&addr).unwrap().with_pool_size(2));
+        let send = |method: &str, priority, wait| {
+            let client = client.clone();
+            let method = method.to_owned();
+            let thread = thread::spawn(move || {
+                let req = client.build_request(method, vec![]);
+                client.send_request_with_priority(&req, priority).unwrap();
+            });
+            thread::sleep(Duration::from_millis(wait));
+            thread
+        };
+
+        // The main connection comes free while the high request waits, so
+        // a low request arriving then must not take it first
+        let sequence = {
+            let client = client.clone();
+            thread::spawn(move || client.sequence(|_| thread::sleep(Duration::from_millis(150))))
+        };
+        thread::sleep(Duration::from_millis(20));
+        let threads = vec![send("first", Priority::Normal, 30),
+                           send("high", Priority::High, 150),
+                           send("low", Priority::Low, 0)];
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        sequence.join().unwrap();
+        assert_eq!(*served.lock().unwrap(), vec!["first", "high", "low"]);
+    }
+
+    #[test]
+    fn test_pool_idle_timeout() {
+        let (addr, connections) = counting_server(usize::MAX);
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = TcpClient::new(&addr)
+            .unwrap()
+            .with_clock(clock.clone())
+            .with_pool_size(2)
+            .with_pool_idle_timeout(Duration::from_secs(60));
+        assert_eq!(client.pool_idle_timeout(), Some(Duration::from_secs(60)));
+
+        // Holding the main connection sends each request over the pool
+        client.sequence(|_| {
+            let ping = |expected| {
+                let req = client.build_request("server.ping".to_owned(), vec![]);
+                client.send_request(&req).unwrap();
+                assert_eq!(connections.load(Ordering::SeqCst), expected);
+            };
+            ping(2);
+            clock.advance(Duration::from_secs(60));
+            ping(2);
+            clock.advance(Duration::from_secs(61));
+            ping(3);
+            ping(3);
+        });
+    }
+
+    #[test]
+    fn test_connection_reused() {
+        let (addr, connections) = counting_server(usize::MAX);
+        let client = TcpClient::new(&addr).unwrap();
+        for _ in 0..3 {
+            let req = client.build_request("server.ping".to_owned(), vec![]);
+            client.send_request(&req).unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_pool() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let addr = tcp_server(move |stream| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Request = serde_json::from_str(&line.unwrap()).unwrap();
+                thread::sleep(Duration::from_millis(200));
+                let response = reply(request.id.clone(), request.params[0].clone());
+                writeln!(writer, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+            }
+        });
+        assert_eq!(TcpClient::new(&addr).unwrap().pool_size(), 1);
+
+        let client = Arc::new(TcpClient::new(&addr).unwrap().with_pool_size(4));
+        assert_eq!(client.pool_size(), 4);
+        let started = Instant::now();
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let req = client.build_request("echo".to_owned(), vec![json!(i)]);
+                    assert_eq!(client.send_request(&req).unwrap().result, Some(json!(i)));
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Eight requests over four connections take two rounds, not eight
+        assert!(started.elapsed() < Duration::from_millis(1200));
+        assert!(connections.load(Ordering::SeqCst) <= 5);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (addr, connections) = counting_server(usize::MAX);
+        let client = TcpClient::new(&addr).unwrap();
+        client.reconnect().unwrap();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_reconnects_when_closed() {
+        let (addr, connections) = counting_server(1);
+        let client = TcpClient::new(&addr).unwrap();
+        for _ in 0..3 {
+            let req = client.build_request("server.ping".to_owned(), vec![]);
+            client.send_request(&req).unwrap();
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let (addr, connections) = counting_server(0);
+        let policy = RetryPolicy::new(2, Duration::from_millis(10));
+        let client = TcpClient::new(&addr).unwrap().with_retry_policy(policy);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+        // The first connection, then one per retry
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        // Backing off waits on the client's clock
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let policy = RetryPolicy::new(2, Duration::from_secs(60));
+        let client = TcpClient::new(&addr)
+            .unwrap()
+            .with_retry_policy(policy)
+            .with_clock(clock.clone());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        let started = Instant::now();
+        assert!(client.send_request(&req).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(clock.elapsed(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counter = pings.clone();
+        let addr = line_server(move |req| {
+            if req.method == "server.ping" {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            reply(req.id, Value::Null)
+        });
+        let client = TcpClient::new(&addr).unwrap();
+        assert_eq!(client.keepalive(), None);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pings.load(Ordering::SeqCst), 0);
+
+        let client = client.with_keepalive(Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(300));
+        assert!(pings.load(Ordering::SeqCst) >= 2);
+
+        // Pings stop with the client
+        drop(client);
+        thread::sleep(Duration::from_millis(100));
+        let after_drop = pings.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(pings.load(Ordering::SeqCst), after_drop);
+    }
+
+    #[test]
+    fn test_pause_keepalive() {
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counter = pings.clone();
+        let addr = line_server(move |req| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            reply(req.id, Value::Null)
+        });
+        // Time only passes as the keep-alive thread waits on it
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = TcpClient::new(&addr).unwrap().with_clock(clock.clone());
+        client.pause_keepalive();
+        let client = client.with_keepalive(Duration::from_secs(60));
+        thread::sleep(Duration::from_millis(100));
+        assert!(clock.elapsed() > Duration::from_secs(120));
+        assert_eq!(pings.load(Ordering::SeqCst), 0);
+
+        client.resume_keepalive();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while pings.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "no ping after resuming");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // A ping under way when pausing is still answered, but no more follow
+        client.pause_keepalive();
+        thread::sleep(Duration::from_millis(50));
+        let paused_at = pings.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pings.load(Ordering::SeqCst), paused_at);
+    }
+
+    #[test]
+    fn test_keepalive_failure_reconnects() {
+        // The first connection answers the first ping, then the next fails
+        let (addr, connections) = counting_server(1);
+        let client = TcpClient::new(&addr)
+            .unwrap()
+            .with_retry_policy(RetryPolicy::never())
+            .with_keepalive(Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_is_connected() {
+        let (addr, connections) = closing_server(true);
+        let client = TcpClient::new(&addr).unwrap();
+        assert!(client.is_connected());
+
+        // The server closes the connection after answering
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!client.is_connected());
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_close_after_response() {
+        for &newline in &[true, false] {
+            let (addr, connections) = closing_server(newline);
+            let client = TcpClient::new(&addr).unwrap().with_retry_policy(RetryPolicy::never());
+            for _ in 0..3 {
+                let req = client.build_request("server.ping".to_owned(), vec![]);
+                let response = client.send_request(&req).unwrap();
+                assert_eq!(response.result, Some(json!("ok")));
+                // A close after the newline is only seen once it arrives,
+                // while one ending the response is seen reading it
+                if newline {
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            assert_eq!(connections.load(Ordering::SeqCst), 3);
+        }
+    }
+
+    #[test]
+    fn test_no_retries() {
+        let (addr, connections) = counting_server(1);
+        let client = TcpClient::new(&addr).unwrap().with_retry_policy(RetryPolicy::never());
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let methods = Arc::new(Mutex::new(vec![]));
+        let seen = methods.clone();
+        let addr = line_server(move |req| {
+            seen.lock().unwrap().push(req.method.clone());
+            reply(req.id, Value::Null)
+        });
+        let client = Arc::new(TcpClient::new(&addr).unwrap());
+
+        let (started, wait) = channel();
+        let other = client.clone();
+        let concurrent = thread::spawn(move || {
+            wait.recv().unwrap();
+            let req = other.build_request("server.banner".to_owned(), vec![]);
+            other.send_request(&req).unwrap();
+        });
+        client.sequence(|seq| {
+            let req = seq.build_request("blockchain.scripthash.subscribe".to_owned(), vec![]);
+            seq.send_request(&req).unwrap();
+            // Give the other thread every chance to cut in
+            started.send(()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            let req = seq.build_request("blockchain.scripthash.get_history".to_owned(), vec![]);
+            seq.send_request(&req).unwrap();
+        });
+        concurrent.join().unwrap();
+
+        assert_eq!(*methods.lock().unwrap(),
+                   vec!["blockchain.scripthash.subscribe",
+                        "blockchain.scripthash.get_history",
+                        "server.banner"]);
+    }
+
+    #[test]
+    fn test_server_closes() {
+        let addr = tcp_server(|stream| {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+        });
+        let client = TcpClient::new(&addr).unwrap();
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Io(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
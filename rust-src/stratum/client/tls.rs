@@ -0,0 +1,597 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # TLS transport
+//!
+//! Support for servers which speak newline-delimited JSON over TLS, as
+//! most public Electrum servers do on port 50002
+//!
+
+use std::convert::TryFrom;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rustls;
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+             SignatureScheme, StreamOwned};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use serde_json::value::Value;
+use webpki_roots;
+
+use {Params, Request, Response};
+use error::Error;
+use retry::is_connection_error;
+use super::socks5;
+use super::tcp::{DEFAULT_READ_BUFFER_CAPACITY, LineStream, connect_direct, is_open,
+                 keep_alive, timeout_error, unshare};
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// How a `TlsClient` authenticates the server
+///
+/// By default the server's certificate must chain to one of the Mozilla
+/// root certificates and be valid for the host connected to.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    server_name: Option<String>,
+    root_certificate: Option<Vec<u8>>,
+    accept_invalid_certs: bool,
+    proxy: Option<SocketAddr>,
+    timeout: Option<Duration>,
+}
+
+impl TlsConfig {
+    /// Creates the default configuration
+    pub fn new() -> TlsConfig {
+        TlsConfig {
+            server_name: None,
+            root_certificate: None,
+            accept_invalid_certs: false,
+            proxy: None,
+            timeout: None,
+        }
+    }
+
+    /// Checks the certificate against `name` rather than the host connected
+    /// to, for servers reached by address or through a tunnel
+    pub fn with_server_name(mut self, name: &str) -> TlsConfig {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+
+    /// Trusts only the given DER-encoded root certificate, in place of the
+    /// Mozilla roots
+    pub fn with_root_certificate(mut self, der: Vec<u8>) -> TlsConfig {
+        self.root_certificate = Some(der);
+        self
+    }
+
+    /// Accepts any certificate, as many Electrum servers use self-signed
+    /// ones
+    ///
+    /// The connection is still encrypted, but anyone able to intercept it
+    /// can pose as the server.
+    pub fn with_accept_invalid_certs(mut self, accept: bool) -> TlsConfig {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Connects through the SOCKS5 proxy at `proxy`, such as a local Tor
+    /// daemon, which resolves the host itself so it may be a .onion address
+    pub fn with_socks5_proxy(mut self, proxy: SocketAddr) -> TlsConfig {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Gives up on connecting, the handshake and each later read and write
+    /// after `timeout`, failing with `Error::Timeout`
+    ///
+    /// Without a timeout, a server which never replies blocks
+    /// `TlsClient::send_request` forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> TlsConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Accessor for the timeout, if one is set
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> TlsConfig {
+        TlsConfig::new()
+    }
+}
+
+/// A handle to a remote JSONRPC server over TLS
+pub struct TlsClient {
+    host: String,
+    port: u16,
+    config: TlsConfig,
+    // Shared with the keep-alive thread, if there is one
+    connection: Arc<Mutex<LineStream<TlsStream>>>,
+    nonce: Arc<AtomicU64>,
+    keepalive: Option<Duration>,
+    keepalive_paused: Arc<AtomicBool>,
+}
+
+impl TlsClient {
+    /// Connects to the server at `host` and `port`, completing the TLS
+    /// handshake before returning
+    ///
+    /// Gives `Error::Tls` if the handshake fails, including when the server
+    /// is not trusted under `config`, `Error::Proxy` if a proxy given in
+    /// `config` cannot reach the server, and `Error::Timeout` if the
+    /// timeout given in `config` passes first.
+    pub fn new(host: &str, port: u16, config: TlsConfig) -> Result<TlsClient, Error> {
+        let stream = connect(host, port, &config)?;
+        Ok(TlsClient {
+            host: host.to_owned(),
+            port,
+            config,
+            connection: Arc::new(Mutex::new(LineStream::new(stream,
+                                                            DEFAULT_READ_BUFFER_CAPACITY))),
+            nonce: Arc::new(AtomicU64::new(0)),
+            keepalive: None,
+            keepalive_paused: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Sets the initial capacity of the buffers responses are read into, as
+    /// with `TcpClient::with_read_buffer_capacity`
+    pub fn with_read_buffer_capacity(self, capacity: usize) -> TlsClient {
+        let connection = unshare(self.connection);
+        let client = TlsClient {
+            connection: Arc::new(Mutex::new(connection.with_capacity(capacity))),
+            ..self
+        };
+        if let Some(interval) = client.keepalive {
+            client.spawn_keepalive(interval);
+        }
+        client
+    }
+
+    /// Sends `server.ping` whenever the connection has been idle for
+    /// `interval`, as with `TcpClient::with_keepalive`
+    pub fn with_keepalive(mut self, interval: Duration) -> TlsClient {
+        if self.keepalive.is_none() {
+            self.spawn_keepalive(interval);
+        }
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Accessor for the keep-alive interval, if pings are sent
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+
+    /// Stops keep-alive pings until `resume_keepalive` is called, as with
+    /// `TcpClient::pause_keepalive`
+    pub fn pause_keepalive(&self) {
+        self.keepalive_paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Sends keep-alive pings again after `pause_keepalive`
+    pub fn resume_keepalive(&self) {
+        self.keepalive_paused.store(false, Ordering::SeqCst);
+    }
+
+    fn spawn_keepalive(&self, interval: Duration) {
+        let connection = Arc::downgrade(&self.connection);
+        let nonce = self.nonce.clone();
+        let paused = self.keepalive_paused.clone();
+        thread::spawn(move || keep_alive(&connection, &nonce, &paused, interval));
+    }
+
+    /// Replaces the connection with a fresh one to the same server, as
+    /// connected to originally, repeating the handshake
+    pub fn reconnect(&self) -> Result<(), Error> {
+        let mut connection = self.connection.lock().unwrap();
+        self.reconnect_locked(&mut connection)
+    }
+
+    fn reconnect_locked(&self, connection: &mut LineStream<TlsStream>) -> Result<(), Error> {
+        let stream = connect(&self.host, self.port, &self.config)?;
+        *connection = LineStream::new(stream, connection.capacity());
+        Ok(())
+    }
+
+    /// Sends a request to the server, returning the line it answers with
+    ///
+    /// Requests on one client are sent one at a time, each waiting for its
+    /// response before the next is written. If a keep-alive ping or an
+    /// earlier request found the connection dropped, or an earlier request
+    /// timed out with its response still due, the client reconnects before
+    /// sending.
+    pub fn send_request(&self, request: &Request) -> Result<Response, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.broken {
+            self.reconnect_locked(&mut connection)?;
+        }
+        let result = connection.round_trip(request);
+        if let Err(ref e) = result {
+            connection.broken |= is_connection_error(e);
+        }
+        result
+    }
+
+    /// Builds a request
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        let nonce = self.nonce.fetch_add(1, Ordering::SeqCst) + 1;
+        Request {
+            method: name,
+            params: Params::Positional(params),
+            id: From::from(nonce),
+            jsonrpc: None,
+        }
+    }
+
+    /// Accessor for the last-used nonce
+    pub fn last_nonce(&self) -> u64 {
+        self.nonce.load(Ordering::SeqCst)
+    }
+
+    /// Whether the connection is established, checked locally as with
+    /// `TcpClient::is_connected`
+    pub fn is_connected(&self) -> bool {
+        match self.connection.try_lock() {
+            Ok(connection) => !connection.broken && is_open(connection.get_ref().get_ref()),
+            Err(_) => true,
+        }
+    }
+
+    /// Accessor for the host the client is connected to
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Accessor for the port the client is connected to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+/// Connects to `host` and `port` as `config` says, completing the handshake
+fn connect(host: &str, port: u16, config: &TlsConfig) -> Result<TlsStream, Error> {
+    let tls_config = client_config(config)?;
+    let server_name = config.server_name.as_ref().map_or(host, |name| name.as_str());
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    let mut session = ClientConnection::new(Arc::new(tls_config), server_name)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    let mut stream = match config.proxy {
+        Some(ref proxy) => socks5::connect(proxy, host, port, config.timeout)?,
+        None => connect_direct(&(host, port), config.timeout)?,
+    };
+    while session.is_handshaking() {
+        session.complete_io(&mut stream).map_err(handshake_error)?;
+    }
+    Ok(StreamOwned::new(session, stream))
+}
+
+fn client_config(config: &TlsConfig) -> Result<ClientConfig, Error> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    if config.accept_invalid_certs {
+        return Ok(builder.dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    match config.root_certificate {
+        Some(ref der) => {
+            roots.add(CertificateDer::from(der.clone()))
+                .map_err(|e| Error::Tls(e.to_string()))?
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// Separates TLS failures, which rustls reports as invalid data, from
+/// failures of the underlying connection
+fn handshake_error(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::InvalidData {
+        Error::Tls(e.to_string())
+    } else {
+        timeout_error(e)
+    }
+}
+
+/// A verifier which accepts any certificate, while still checking that the
+/// handshake is signed by it
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(&self,
+                          _end_entity: &CertificateDer,
+                          _intermediates: &[CertificateDer],
+                          _server_name: &ServerName,
+                          _ocsp_response: &[u8],
+                          _now: UnixTime)
+                          -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message,
+                                               cert,
+                                               dss,
+                                               &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self,
+                              message: &[u8],
+                              cert: &CertificateDer,
+                              dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message,
+                                               cert,
+                                               dss,
+                                               &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use rcgen;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use rustls::crypto::ring;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use serde_json;
+    use serde_json::Value;
+
+    use {Request, Response};
+    use error::Error;
+    use test_utils::{reply, tcp_server};
+    use super::{TlsClient, TlsConfig};
+
+    /// Starts a TLS server with a self-signed certificate for `localhost`,
+    /// echoing each request's method as its result, and returns its port
+    /// and certificate
+    fn tls_server() -> (u16, Vec<u8>) {
+        tls_server_with(|req| Some(reply(req.id, Value::String(req.method))))
+    }
+
+    /// Starts a TLS server as `tls_server` does, answering each request
+    /// with what `handler` gives, and closing the connection on `None`
+    fn tls_server_with<F>(handler: F) -> (u16, Vec<u8>)
+        where F: Fn(Request) -> Option<Response> + Send + Sync + 'static
+    {
+        let handler = Arc::new(handler);
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair
+            .serialize_der()));
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                let session = ServerConnection::new(config.clone()).unwrap();
+                let handler = handler.clone();
+                thread::spawn(move || {
+                    let mut stream = BufReader::new(StreamOwned::new(session, stream));
+                    let mut line = String::new();
+                    while let Ok(n) = stream.read_line(&mut line) {
+                        if n == 0 {
+                            return;
+                        }
+                        let request: Request = serde_json::from_str(&line).unwrap();
+                        let response = match handler(request) {
+                            Some(response) => response,
+                            None => return,
+                        };
+                        let response = serde_json::to_string(&response).unwrap();
+                        if writeln!(stream.get_mut(), "{}", response).is_err() {
+                            return;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (port, cert.to_vec())
+    }
+
+    #[test]
+    fn test_custom_root_certificate() {
+        let (port, cert) = tls_server();
+        let config = TlsConfig::new().with_root_certificate(cert);
+        let client = TlsClient::new("localhost", port, config).unwrap();
+
+        for method in &["server.ping", "server.banner"] {
+            let req = client.build_request(method.to_string(), vec![]);
+            let response = client.send_request(&req).unwrap();
+            assert_eq!(response.into_result::<String>().unwrap(), *method);
+        }
+        assert_eq!(client.last_nonce(), 2);
+    }
+
+    #[test]
+    fn test_server_name() {
+        let (port, cert) = tls_server();
+        let config = TlsConfig::new().with_root_certificate(cert).with_server_name("localhost");
+        let client = TlsClient::new("127.0.0.1", port, config).unwrap();
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_ok());
+    }
+
+    #[test]
+    fn test_accept_invalid_certs() {
+        let (port, _) = tls_server();
+        let config = TlsConfig::new().with_accept_invalid_certs(true);
+        let client = TlsClient::new("127.0.0.1", port, config)
+            .unwrap()
+            .with_read_buffer_capacity(64);
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert_eq!(client.send_request(&req).unwrap().into_result::<String>().unwrap(),
+                   "server.ping");
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Accepts the connection but never starts the handshake
+        let addr = tcp_server(|_stream| thread::sleep(Duration::from_secs(5)));
+        let port = addr.rsplit(':').next().unwrap().parse().unwrap();
+        let config = TlsConfig::new()
+            .with_accept_invalid_certs(true)
+            .with_timeout(Duration::from_millis(100));
+        assert_eq!(config.timeout(), Some(Duration::from_millis(100)));
+        match TlsClient::new("127.0.0.1", port, config.clone()) {
+            Err(Error::Timeout) => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("handshake completed"),
+        }
+
+        // Completes the handshake but never replies
+        let (port, _) = tls_server_with(|_| {
+            thread::sleep(Duration::from_secs(5));
+            None
+        });
+        let client = TlsClient::new("127.0.0.1", port, config).unwrap();
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout_recovers() {
+        let (port, _) = tls_server_with(|req| {
+            if req.method == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            Some(reply(req.id, Value::String(req.method)))
+        });
+        let config = TlsConfig::new()
+            .with_accept_invalid_certs(true)
+            .with_timeout(Duration::from_millis(100));
+        let client = TlsClient::new("127.0.0.1", port, config).unwrap();
+
+        let req = client.build_request("slow".to_owned(), vec![]);
+        match client.send_request(&req) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        for _ in 0..3 {
+            let req = client.build_request("server.ping".to_owned(), vec![]);
+            let response = client.send_request(&req).unwrap();
+            assert_eq!(response.result, Some(Value::String("server.ping".to_owned())));
+        }
+    }
+
+    #[test]
+    fn test_keepalive() {
+        // The first ping is answered, and the second drops the connection
+        let pings = Arc::new(AtomicUsize::new(0));
+        let counter = pings.clone();
+        let (port, _) = tls_server_with(move |req| {
+            if req.method == "server.ping" && counter.fetch_add(1, Ordering::SeqCst) == 1 {
+                return None;
+            }
+            Some(reply(req.id, Value::String(req.method)))
+        });
+        let config = TlsConfig::new().with_accept_invalid_certs(true);
+        let client = TlsClient::new("127.0.0.1", port, config).unwrap();
+        assert_eq!(client.keepalive(), None);
+        let client = client.with_read_buffer_capacity(1024)
+            .with_keepalive(Duration::from_millis(50))
+            .with_read_buffer_capacity(2048);
+        assert_eq!(client.keepalive(), Some(Duration::from_millis(50)));
+
+        // Pinging stops once a ping finds the connection dropped
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(pings.load(Ordering::SeqCst), 2);
+
+        // and the next request reconnects
+        let req = client.build_request("server.banner".to_owned(), vec![]);
+        let response = client.send_request(&req).unwrap();
+        assert_eq!(response.result, Some(Value::String("server.banner".to_owned())));
+    }
+
+    #[test]
+    fn test_is_connected() {
+        let (port, _) = tls_server_with(|req| {
+            if req.method == "close" {
+                return None;
+            }
+            Some(reply(req.id, Value::Null))
+        });
+        let config = TlsConfig::new().with_accept_invalid_certs(true);
+        let client = TlsClient::new("127.0.0.1", port, config).unwrap();
+        assert!(client.is_connected());
+
+        let req = client.build_request("close".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+        assert!(!client.is_connected());
+
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        client.send_request(&req).unwrap();
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_untrusted_certificate() {
+        let (port, _) = tls_server();
+        match TlsClient::new("localhost", port, TlsConfig::new()) {
+            Err(Error::Tls(_)) => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("connected to an untrusted server"),
+        }
+    }
+}
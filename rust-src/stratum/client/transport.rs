@@ -0,0 +1,143 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Transports
+//!
+//! What carries a `Client`'s serialized requests to a server and brings
+//! back the response, so that the HTTP connection can be swapped out, e.g.
+//! for canned responses in tests
+//!
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde_json;
+use serde_json::value::Value;
+
+use {Request, Response};
+use error::{Error, RpcError, StandardError, result_to_response, standard_error};
+
+/// Carries one serialized request, or batch of requests, to a server and
+/// returns the body of its response
+///
+/// `Client` is itself a transport, posting over HTTP, and uses another in
+/// its place when created with `Client::with_transport`.
+pub trait Transport: Send + Sync {
+    /// Sends `request_json` and returns the response body
+    fn round_trip(&self, request_json: &str) -> Result<String, Error>;
+}
+
+/// A transport answering each request with a canned result or error for
+/// its method, without contacting any server
+///
+/// Requests for a method with nothing canned get a method-not-found error.
+/// Every request is recorded, for tests to check what a client sent.
+#[derive(Default)]
+pub struct MockTransport {
+    answers: HashMap<String, Result<Value, RpcError>>,
+    requests: Mutex<Vec<Request>>,
+}
+
+impl MockTransport {
+    /// Creates a transport with nothing canned
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Answers requests for `method` with `result`
+    pub fn with_result(mut self, method: &str, result: Value) -> MockTransport {
+        self.answers.insert(method.to_owned(), Ok(result));
+        self
+    }
+
+    /// Answers requests for `method` with `error`
+    pub fn with_error(mut self, method: &str, error: RpcError) -> MockTransport {
+        self.answers.insert(method.to_owned(), Err(error));
+        self
+    }
+
+    /// The requests sent so far, oldest first, with batches flattened
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn answer(&self, request: Request) -> Response {
+        let answer = match self.answers.get(&request.method) {
+            Some(answer) => answer.clone(),
+            None => Err(standard_error(StandardError::MethodNotFound, None)),
+        };
+        let response = result_to_response(answer, request.id.clone());
+        self.requests.lock().unwrap().push(request);
+        response
+    }
+}
+
+impl Transport for MockTransport {
+    fn round_trip(&self, request_json: &str) -> Result<String, Error> {
+        let response_json = match serde_json::from_str::<Vec<Request>>(request_json) {
+            Ok(batch) => {
+                let responses: Vec<Response> =
+                    batch.into_iter().map(|request| self.answer(request)).collect();
+                serde_json::to_string(&responses)?
+            }
+            Err(_) => serde_json::to_string(&self.answer(serde_json::from_str(request_json)?))?,
+        };
+        Ok(response_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use client::Client;
+    use error::{Error, RpcError, StandardError};
+    use super::MockTransport;
+
+    #[test]
+    fn test_server_version() {
+        let transport = MockTransport::new().with_result("server.version",
+                                                         json!(["ElectrumX 1.16.0", "1.4"]));
+        let client = Client::with_transport(transport);
+        assert_eq!(client.server_version("wallet", "1.4").unwrap(),
+                   ("ElectrumX 1.16.0".to_owned(), "1.4".to_owned()));
+    }
+
+    #[test]
+    fn test_errors_and_batches() {
+        let error = RpcError {
+            code: 1,
+            message: "the transaction was rejected by network rules".to_owned(),
+            data: None,
+        };
+        let transport = MockTransport::new()
+            .with_result("server.ping", Value::Null)
+            .with_error("blockchain.transaction.broadcast", error.clone());
+        let client = Client::with_transport(transport);
+
+        let batch = vec![client.build_request("server.ping".to_owned(), vec![]),
+                         client.build_request("blockchain.transaction.broadcast".to_owned(),
+                                              vec![json!("00")]),
+                         client.build_request("server.banner".to_owned(), vec![])];
+        let responses = client.send_batch(&batch).unwrap();
+        assert!(responses[0].error.is_none());
+        assert_eq!(responses[1].error, Some(error));
+        match responses[2].clone().check_error() {
+            Err(Error::Rpc(ref e)) => {
+                assert_eq!(e.as_standard(), Some(StandardError::MethodNotFound))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # Clocks
+//!
+//! A source of time for the client, which tests can replace to control
+//! time-dependent behaviour without sleeping
+//!
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of monotonic and wall-clock time
+pub trait Clock: Send + Sync {
+    /// The current monotonic time
+    fn now(&self) -> Instant;
+    /// The current wall-clock time
+    fn system_time(&self) -> SystemTime;
+    /// Waits for `duration` to pass
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock, which clients use unless given another
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A clock which only moves when told to
+///
+/// `sleep` advances the clock by the requested duration and returns at
+/// once, so code waiting on this clock runs without real delays.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_system: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    /// Creates a clock reading `system_time` as the wall-clock time
+    pub fn new(system_time: SystemTime) -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            start_system: system_time,
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// How far the clock has been moved forward since it was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.start_system + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use super::{Clock, MockClock};
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+        let started = clock.now();
+
+        clock.advance(Duration::from_secs(30));
+        let real = Instant::now();
+        clock.sleep(Duration::from_secs(3600));
+        assert!(real.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(clock.now() - started, Duration::from_secs(3630));
+        assert_eq!(clock.system_time(),
+                   UNIX_EPOCH + Duration::from_secs(1_500_003_630));
+    }
+}
@@ -0,0 +1,233 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Request coalescing
+//!
+//! A layer over `Client` which shares a single in-flight request between
+//! callers asking for the same thing at the same time
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use serde_json;
+
+use {Request, Response};
+use client::Client;
+use error::Error;
+
+/// Where the leader of a coalesced request leaves its response
+struct Slot {
+    // `None` until the request completes, then `Some(None)` if it failed
+    response: Mutex<Option<Option<Response>>>,
+    done: Condvar,
+}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            response: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+
+    fn finish(&self, response: Option<Response>) {
+        *self.response.lock().unwrap() = Some(response);
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> Option<Response> {
+        let mut response = self.response.lock().unwrap();
+        while response.is_none() {
+            response = self.done.wait(response).unwrap();
+        }
+        response.clone().unwrap()
+    }
+}
+
+/// The leader's hold on a coalesced request, which takes the request out
+/// of flight and wakes its waiters when dropped, even if sending panicked
+struct Lead<'a> {
+    in_flight: &'a Mutex<HashMap<(String, String), Arc<Slot>>>,
+    key: (String, String),
+    slot: Arc<Slot>,
+    response: Option<Response>,
+}
+
+impl<'a> Drop for Lead<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.remove(&self.key);
+        }
+        self.slot.finish(self.response.take());
+    }
+}
+
+/// A client which, when a request with the same method and params as one
+/// already in flight is sent, waits for and shares that request's response
+/// rather than sending a duplicate
+///
+/// Callers that joined a request which then failed send their own request,
+/// so each caller still sees its own error.
+pub struct CoalescingClient {
+    client: Client,
+    in_flight: Mutex<HashMap<(String, String), Arc<Slot>>>,
+}
+
+impl CoalescingClient {
+    /// Wraps a client
+    pub fn new(client: Client) -> CoalescingClient {
+        CoalescingClient {
+            client,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accessor for the underlying client
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Sends a request, or joins an identical one already in flight
+    ///
+    /// A shared response has its id replaced by the id of `request`.
+    pub fn send_request(&self, request: &Request) -> Result<Response, Error> {
+        let key = (request.method.clone(), serde_json::to_string(&request.params)?);
+        let (slot, leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(slot) => (slot.clone(), false),
+                None => {
+                    let slot = Arc::new(Slot::new());
+                    in_flight.insert(key.clone(), slot.clone());
+                    (slot, true)
+                }
+            }
+        };
+
+        if leader {
+            let mut lead = Lead {
+                in_flight: &self.in_flight,
+                key,
+                slot,
+                response: None,
+            };
+            let result = self.client.send_request(request);
+            lead.response = result.as_ref().ok().cloned();
+            result
+        } else {
+            match slot.wait() {
+                Some(mut response) => {
+                    response.id = request.id.clone();
+                    Ok(response)
+                }
+                None => self.client.send_request(request),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use test_utils::{MockServer, reply};
+    use super::CoalescingClient;
+
+    #[test]
+    fn test_identical_requests_coalesce() {
+        let server = MockServer::new(|req| {
+            thread::sleep(Duration::from_millis(300));
+            reply(req.id, json!({"confirmed": 5, "unconfirmed": 0}))
+        });
+        let client = Arc::new(CoalescingClient::new(server.client()));
+
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    // Let the first request get in flight before the second
+                    thread::sleep(Duration::from_millis(100 * i));
+                    let req = client.client()
+                        .build_request("blockchain.scripthash.get_balance".to_owned(),
+                                       vec![json!("ab")]);
+                    let response = client.send_request(&req).unwrap();
+                    assert_eq!(response.id, req.id);
+                    response.result.unwrap()
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), json!({"confirmed": 5, "unconfirmed": 0}));
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_leader_panic_releases_waiters() {
+        let server = MockServer::new(|req| {
+            thread::sleep(Duration::from_millis(300));
+            reply(req.id, json!({"confirmed": 5, "unconfirmed": 0}))
+        });
+        // The first response to be validated panics
+        let panicked = AtomicBool::new(false);
+        let client = server.client().with_response_validator(move |_| {
+            if !panicked.swap(true, Ordering::SeqCst) {
+                panic!("validator failed");
+            }
+            Ok(())
+        });
+        let client = Arc::new(CoalescingClient::new(client));
+
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100 * i));
+                    let req = client.client()
+                        .build_request("blockchain.scripthash.get_balance".to_owned(),
+                                       vec![json!("ab")]);
+                    client.send_request(&req).unwrap().result.unwrap()
+                })
+            })
+            .collect();
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join()).collect();
+        assert!(results[0].is_err());
+        assert_eq!(*results[1].as_ref().unwrap(), json!({"confirmed": 5, "unconfirmed": 0}));
+
+        // Nothing is left in flight for later requests to join
+        let req = client.client()
+            .build_request("blockchain.scripthash.get_balance".to_owned(), vec![json!("ab")]);
+        assert!(client.send_request(&req).is_ok());
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[test]
+    fn test_different_requests_do_not_coalesce() {
+        let server = MockServer::new(|req| reply(req.id, req.params[0].clone()));
+        let client = CoalescingClient::new(server.client());
+
+        for param in &["ab", "cd", "ab"] {
+            let req = client.client()
+                .build_request("blockchain.scripthash.get_balance".to_owned(),
+                               vec![json!(param)]);
+            assert_eq!(client.send_request(&req).unwrap().result.unwrap(), json!(param));
+        }
+        assert_eq!(server.hits(), 3);
+    }
+}
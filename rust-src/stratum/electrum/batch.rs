@@ -0,0 +1,192 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # Batch building
+//!
+//! Queueing several typed helper calls to send together, with each result
+//! decoded to its own type
+//!
+
+use serde_json;
+use serde_json::value::Value;
+
+use Request;
+use client::Client;
+use error::Error;
+use super::requests;
+use super::types::{Balance, Utxo};
+
+/// A queued call, remembering how to decode its result
+enum Call {
+    Balance(String),
+    ListUnspent(String),
+    EstimateFee(u16),
+}
+
+/// The typed result of one call in a batch
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchResult {
+    /// The result of `get_balance`
+    Balance(Balance),
+    /// The result of `list_unspent`
+    Utxos(Vec<Utxo>),
+    /// The result of `estimate_fee`, in coins per kilobyte, or -1 if the
+    /// server has no estimate
+    Fee(f64),
+}
+
+/// Queues typed calls, then sends them together with `send`
+#[derive(Default)]
+pub struct BatchBuilder {
+    calls: Vec<Call>,
+}
+
+impl BatchBuilder {
+    /// Creates an empty batch
+    pub fn new() -> BatchBuilder {
+        BatchBuilder::default()
+    }
+
+    /// Queues a `blockchain.scripthash.get_balance` call
+    pub fn get_balance(mut self, scripthash: &str) -> BatchBuilder {
+        self.calls.push(Call::Balance(scripthash.to_owned()));
+        self
+    }
+
+    /// Queues a `blockchain.scripthash.listunspent` call
+    pub fn list_unspent(mut self, scripthash: &str) -> BatchBuilder {
+        self.calls.push(Call::ListUnspent(scripthash.to_owned()));
+        self
+    }
+
+    /// Queues a `blockchain.estimatefee` call
+    pub fn estimate_fee(mut self, blocks: u16) -> BatchBuilder {
+        self.calls.push(Call::EstimateFee(blocks));
+        self
+    }
+
+    /// The number of queued calls
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Whether no calls are queued
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Sends the queued calls as one JSONRPC batch, returning their results
+    /// in the order the calls were queued
+    ///
+    /// An error response to one call does not fail the others. An invalid
+    /// scripthash, or more calls than the client's recorded server limits
+    /// allow, fails the batch before anything is sent, and transport errors
+    /// abort it, as does a call the server leaves unanswered, with
+    /// `Error::IncompleteBatch`.
+    pub fn send(&self, client: &Client) -> Result<Vec<Result<BatchResult, Error>>, Error> {
+        let requests = self.calls
+            .iter()
+            .map(|call| build(call, client))
+            .collect::<Result<Vec<Request>, Error>>()?;
+        client.server_limits().check_batch(requests.len())?;
+
+        // The responses come back in the order of the requests
+        let responses = client.send_batch_complete(&requests)?;
+        Ok(self.calls
+            .iter()
+            .zip(responses)
+            .map(|(call, response)| response.into_result().and_then(|v| decode(call, v)))
+            .collect())
+    }
+}
+
+fn build(call: &Call, client: &Client) -> Result<Request, Error> {
+    Ok(match *call {
+        Call::Balance(ref scripthash) => {
+            let scripthash = requests::normalize_scripthash(scripthash)?;
+            requests::scripthash_get_balance(&scripthash, client.next_id())
+        }
+        Call::ListUnspent(ref scripthash) => {
+            let scripthash = requests::normalize_scripthash(scripthash)?;
+            requests::scripthash_listunspent(&scripthash, client.next_id())
+        }
+        Call::EstimateFee(blocks) => requests::estimatefee(blocks, client.next_id()),
+    })
+}
+
+fn decode(call: &Call, result: Value) -> Result<BatchResult, Error> {
+    Ok(match *call {
+        Call::Balance(_) => BatchResult::Balance(serde_json::value::from_value(result)?),
+        Call::ListUnspent(_) => {
+            let utxos = serde_json::value::from_value(result)
+                .map_err(|e| Error::InvalidUtxo(e.to_string()))?;
+            BatchResult::Utxos(utxos)
+        }
+        Call::EstimateFee(_) => BatchResult::Fee(serde_json::value::from_value(result)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use test_utils::{MockServer, reply, reply_error};
+    use electrum::Balance;
+    use super::{BatchBuilder, BatchResult};
+
+    const SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+
+    #[test]
+    fn test_batch_builder() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.scripthash.get_balance" => {
+                reply(req.id, json!({"confirmed": 1000, "unconfirmed": -40}))
+            }
+            "blockchain.estimatefee" => {
+                assert_eq!(req.params, vec![json!(6)]);
+                reply(req.id, json!(0.00012))
+            }
+            "blockchain.scripthash.listunspent" => reply_error(req.id, -32603, "busy"),
+            _ => panic!("unexpected method {}", req.method),
+        });
+
+        let batch = BatchBuilder::new()
+            .get_balance(SCRIPTHASH)
+            .estimate_fee(6)
+            .list_unspent(SCRIPTHASH);
+        assert_eq!(batch.len(), 3);
+        let results = batch.send(&server.client()).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(*results[0].as_ref().unwrap(),
+                   BatchResult::Balance(Balance {
+                       confirmed: 1000,
+                       unconfirmed: -40,
+                   }));
+        assert_eq!(*results[1].as_ref().unwrap(), BatchResult::Fee(0.00012));
+        match results[2] {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32603),
+            ref other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_batch_builder_invalid_scripthash() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let batch = BatchBuilder::new().estimate_fee(6).get_balance("8b01");
+        match batch.send(&server.client()) {
+            Err(Error::InvalidScripthash(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 0);
+    }
+}
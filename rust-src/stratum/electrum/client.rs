@@ -0,0 +1,1469 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Electrum client methods
+//!
+//! Helpers on `Client` for common Electrum calls
+//!
+
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde_json;
+use serde_json::value::Value;
+
+use {Request, Response};
+use client::Client;
+use error::{Error, RpcError, StandardError};
+use hex;
+use retry::RetryBudget;
+
+use super::requests;
+use super::header::{HeaderSync, ParsedHeader};
+use super::info::{ServerInfo, ServerLimits};
+use super::merkle::MerkleProof;
+use super::transaction::{parse_inputs, txid};
+use super::types::{Balance, HistoryEntry, HistoryWithTx, ScripthashSnapshot, Utxo,
+                   WalletSnapshot};
+use super::version::ProtocolVersion;
+
+/// How often `broadcast_and_confirm` checks whether a transaction is visible
+const BROADCAST_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The bitcoind error code for a node still loading its block index
+const RPC_IN_WARMUP: i32 = -28;
+
+/// The bitcoind error code for an unknown transaction, among other things
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// A method introduced by a protocol version, which a server claiming the
+/// version must support
+struct ProtocolProbe {
+    version: (u32, u32),
+    previous: (u32, u32),
+    request: fn(Value) -> Request,
+}
+
+/// One probe per protocol version, newest first
+///
+/// The probes ask about the genesis block, which every server has.
+const PROTOCOL_PROBES: [ProtocolProbe; 3] = [ProtocolProbe {
+                                                 version: (1, 4),
+                                                 previous: (1, 3),
+                                                 request: probe_id_from_pos,
+                                             },
+                                             ProtocolProbe {
+                                                 version: (1, 3),
+                                                 previous: (1, 2),
+                                                 request: probe_block_header,
+                                             },
+                                             ProtocolProbe {
+                                                 version: (1, 2),
+                                                 previous: (1, 1),
+                                                 request: probe_block_headers,
+                                             }];
+
+fn probe_id_from_pos(id: Value) -> Request {
+    requests::transaction_id_from_pos(0, 0, false, id)
+}
+
+fn probe_block_header(id: Value) -> Request {
+    requests::block_header(0, id)
+}
+
+fn probe_block_headers(id: Value) -> Request {
+    requests::block_headers(0, 1, id)
+}
+
+/// Whether an error indicates the server's node is still starting up
+fn is_warming_up(error: &RpcError) -> bool {
+    let message = error.message.to_lowercase();
+    error.code == RPC_IN_WARMUP || message.contains("warming up") ||
+    message.contains("loading block index")
+}
+
+/// Whether `error` says the server does not know a transaction, either as
+/// bitcoind's own error or wrapped in a daemon error, as ElectrumX does
+fn is_tx_not_found(error: &RpcError) -> bool {
+    error.code == RPC_INVALID_ADDRESS_OR_KEY ||
+    error.message.to_lowercase().contains("no such mempool or blockchain transaction")
+}
+
+/// The `[server_software, protocol_version]` result of `server.version`
+fn version_pair(response: Response) -> Result<(String, String), Error> {
+    let result: Value = response.into_result()?;
+    match result.as_array().map(Vec::as_slice) {
+        Some([Value::String(software), Value::String(protocol)]) => {
+            Ok((software.clone(), protocol.clone()))
+        }
+        _ => Err(Error::NoErrorOrResult),
+    }
+}
+
+/// The part of a `blockchain.headers.subscribe` result we care about
+#[derive(Deserialize)]
+struct Tip {
+    height: u32,
+}
+
+/// A whole `blockchain.headers.subscribe` result
+#[derive(Deserialize)]
+struct TipHeader {
+    height: u32,
+    hex: String,
+}
+
+/// The result of `blockchain.transaction.id_from_pos` asked for a proof
+#[derive(Deserialize)]
+struct TxIdWithProof {
+    tx_hash: String,
+    merkle: Vec<String>,
+}
+
+/// The parts of a verbose `blockchain.transaction.get` result needed to
+/// work out a fee rate
+#[derive(Deserialize)]
+struct VerboseTx {
+    vin: Vec<VerboseInput>,
+    vout: Vec<VerboseOutput>,
+    vsize: u64,
+}
+
+/// An input of a verbose transaction; coinbase inputs have no prevout
+#[derive(Deserialize)]
+struct VerboseInput {
+    txid: Option<String>,
+    vout: Option<u32>,
+}
+
+/// An output of a verbose transaction, valued in BTC
+#[derive(Deserialize)]
+struct VerboseOutput {
+    value: f64,
+}
+
+/// Converts a BTC amount, as bitcoind reports it, to satoshis
+fn btc_to_sat(value: f64) -> i64 {
+    (value * 100_000_000.0).round() as i64
+}
+
+impl Client {
+    /// Names the client and the protocol version, or `[min, max]` range of
+    /// versions, it speaks, returning the server's software version and the
+    /// protocol version it chose
+    ///
+    /// A result which is not a pair of strings gives
+    /// `Error::NoErrorOrResult`.
+    pub fn server_version(&self,
+                          client_name: &str,
+                          protocol: &str)
+                          -> Result<(String, String), Error> {
+        let request = requests::server_version(client_name, protocol, self.next_id());
+        version_pair(self.send_request(&request)?)
+    }
+
+    /// Fetches the server's banner
+    pub fn server_banner(&self) -> Result<String, Error> {
+        let request = requests::server_banner(self.next_id());
+        self.send_request(&request)?.into_result()
+    }
+
+    /// Fetches the server's `server.features` result
+    pub fn server_features(&self) -> Result<Value, Error> {
+        let request = requests::server_features(self.next_id());
+        self.send_request(&request)?.into_result()
+    }
+
+    /// Fetches information about the server, from its banner, its
+    /// `server.features` result and the timestamp of its chain tip
+    pub fn server_info(&self) -> Result<ServerInfo, Error> {
+        let mut info = ServerInfo::from_banner(self.server_banner()?);
+        info.set_identity(self.url(), &self.server_features()?);
+        info.time_skew = Some(self.time_skew()?);
+        Ok(info)
+    }
+
+    /// Fetches the header at `height`
+    ///
+    /// This is shaped by the recorded protocol version: from 1.3, or with
+    /// no version recorded, it uses `blockchain.block.header`, and before
+    /// that a one-header `blockchain.block.headers` request.
+    pub fn block_header(&self, height: u32) -> Result<ParsedHeader, Error> {
+        match self.protocol_version() {
+            Some(version) if version < ProtocolVersion::new(1, 3, 0) => {
+                match self.sync_headers(height, height + 1).next() {
+                    Some(header) => header,
+                    None => Err(Error::InvalidHeader(format!("no header at height {}", height))),
+                }
+            }
+            _ => {
+                let request = requests::block_header(height, self.next_id());
+                let raw: String = self.send_request(&request)?.into_result()?;
+                ParsedHeader::from_hex(&raw)
+            }
+        }
+    }
+
+    /// Fetches the height and header of the server's chain tip
+    pub fn tip_header(&self) -> Result<(u32, ParsedHeader), Error> {
+        let request = requests::headers_subscribe(self.next_id());
+        let tip: TipHeader = self.send_request(&request)?.into_result()?;
+        Ok((tip.height, ParsedHeader::from_hex(&tip.hex)?))
+    }
+
+    /// Estimates how many seconds the server's clock is ahead of the
+    /// client's, which is negative if it is behind
+    ///
+    /// This compares the timestamp of the tip header with the client's
+    /// clock, so it is coarse: miners may set timestamps well away from the
+    /// true time, and the tip is usually some minutes old.
+    pub fn time_skew(&self) -> Result<i64, Error> {
+        let (_, header) = self.tip_header()?;
+        let now = self.clock()
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        Ok(i64::from(header.time) - now)
+    }
+
+    /// Negotiates a protocol version with `server.version`, then records it
+    /// and the limits from `server.features`, returning the client ready
+    /// for use
+    ///
+    /// The client is consumed, so a failure at any step leaves no
+    /// half-initialized client behind: the error is returned and the whole
+    /// sequence can be retried with a fresh client.
+    pub fn connect_and_handshake(self,
+                                 client_name: &str,
+                                 protocol_version: &ProtocolVersion)
+                                 -> Result<Client, Error> {
+        self.handshake(client_name, protocol_version, None)
+    }
+
+    /// Performs the same handshake as `connect_and_handshake`, retrying
+    /// transport failures while `budget` allows
+    pub fn connect_and_handshake_with_budget(self,
+                                             client_name: &str,
+                                             protocol_version: &ProtocolVersion,
+                                             budget: &RetryBudget)
+                                             -> Result<Client, Error> {
+        self.handshake(client_name, protocol_version, Some(budget))
+    }
+
+    fn handshake(self,
+                 client_name: &str,
+                 protocol_version: &ProtocolVersion,
+                 budget: Option<&RetryBudget>)
+                 -> Result<Client, Error> {
+        let request = requests::server_version(client_name,
+                                               &protocol_version.to_string(),
+                                               self.next_id());
+        let (_, negotiated) = version_pair(self.send_within(&request, budget)?)?;
+        let negotiated = negotiated.parse()?;
+        let request = requests::server_features(self.next_id());
+        let features: Value = self.send_within(&request, budget)?.into_result()?;
+        let limits = ServerLimits::from_features(&features);
+        Ok(self.with_protocol_version(negotiated).with_server_limits(limits))
+    }
+
+    /// Sends a request, retrying within `budget` if there is one
+    fn send_within(&self,
+                   request: &Request,
+                   budget: Option<&RetryBudget>)
+                   -> Result<Response, Error> {
+        match budget {
+            Some(budget) => self.send_request_with_budget(request, budget),
+            None => self.send_request(request),
+        }
+    }
+
+    /// Sends a batch with `send_batch_complete`, retrying the whole batch
+    /// within `budget` if there is one
+    fn send_batch_within(&self,
+                         batch: &[Request],
+                         budget: Option<&RetryBudget>)
+                         -> Result<Vec<Response>, Error> {
+        let budget = match budget {
+            Some(budget) => budget,
+            None => return self.send_batch_complete(batch),
+        };
+        loop {
+            match self.send_batch_complete(batch) {
+                Ok(responses) => return Ok(responses),
+                Err(e) => budget.retry(e)?,
+            }
+        }
+    }
+
+    /// Checks the recorded protocol version against what the server does,
+    /// downgrading it past any version whose methods the server rejects
+    /// as not found
+    ///
+    /// This probes one method per version up to the recorded one, newest
+    /// first, so it costs a round trip for each. Errors other than
+    /// `MethodNotFound` show the method exists and are ignored. A client
+    /// with no recorded version is returned unchanged.
+    pub fn verify_protocol_version(self) -> Result<Client, Error> {
+        let mut version = match self.protocol_version() {
+            Some(version) => version,
+            None => return Ok(self),
+        };
+        for probe in &PROTOCOL_PROBES {
+            if version < ProtocolVersion::new(probe.version.0, probe.version.1, 0) {
+                continue;
+            }
+            let response = self.send_request(&(probe.request)(self.next_id()))?;
+            match response.error {
+                Some(ref e) if e.as_standard() == Some(StandardError::MethodNotFound) => {
+                    version = ProtocolVersion::new(probe.previous.0, probe.previous.1, 0);
+                }
+                _ => break,
+            }
+        }
+        Ok(self.with_protocol_version(version))
+    }
+
+    /// Fetches the limits the server advertises in its features, for use
+    /// with `Client::with_server_limits`
+    pub fn fetch_server_limits(&self) -> Result<ServerLimits, Error> {
+        Ok(ServerLimits::from_features(&self.server_features()?))
+    }
+
+    /// Fetches the height of the server's chain tip
+    pub fn tip_height(&self) -> Result<u32, Error> {
+        let request = requests::headers_subscribe(self.next_id());
+        let tip: Tip = self.send_request(&request)?.into_result()?;
+        Ok(tip.height)
+    }
+
+    /// Returns how many blocks the server's chain tip is behind
+    /// `reference_height`, which is negative if the server is ahead
+    pub fn tip_age(&self, reference_height: u32) -> Result<i64, Error> {
+        let height = self.tip_height()?;
+        Ok(reference_height as i64 - height as i64)
+    }
+
+    /// Fetches the balance of a scripthash
+    ///
+    /// Like the other scripthash helpers, this rejects a scripthash which is
+    /// not 32 bytes of hex and sends it in lowercase.
+    pub fn scripthash_get_balance(&self, scripthash: &str) -> Result<Balance, Error> {
+        let scripthash = requests::normalize_scripthash(scripthash)?;
+        let request = requests::scripthash_get_balance(&scripthash, self.next_id());
+        self.send_request(&request)?.into_result()
+    }
+
+    /// Whether the server's chain tip is within `tolerance` blocks of
+    /// `reference_height`
+    ///
+    /// A server whose node is still warming up counts as not synced rather
+    /// than as an error.
+    pub fn is_synced(&self, reference_height: u32, tolerance: u32) -> Result<bool, Error> {
+        match self.tip_age(reference_height) {
+            Ok(age) => Ok(age <= tolerance as i64),
+            Err(Error::Rpc(ref e)) if is_warming_up(e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetches the combined balance of all of `scripthashes`, in one batch
+    ///
+    /// Confirmed and unconfirmed amounts are summed separately. Since a
+    /// partial sum would be misleading, the first error response aborts the
+    /// call with `Error::ScripthashFailed` naming the scripthash, and a
+    /// truncated batch gives `Error::IncompleteBatch`. More
+    /// scripthashes than the recorded server limits allow give
+    /// `Error::ExceedsServerLimit` without sending anything.
+    pub fn wallet_balance(&self, scripthashes: &[&str]) -> Result<Balance, Error> {
+        self.sum_balances(scripthashes, None)
+    }
+
+    /// Fetches the combined balance of all of `scripthashes` like
+    /// `wallet_balance`, retrying transport failures while `budget` allows
+    ///
+    /// A failed batch is resent whole, and running out of budget aborts the
+    /// call with `Error::RetryBudgetExhausted`.
+    pub fn wallet_balance_with_budget(&self,
+                                      scripthashes: &[&str],
+                                      budget: &RetryBudget)
+                                      -> Result<Balance, Error> {
+        self.sum_balances(scripthashes, Some(budget))
+    }
+
+    fn sum_balances(&self,
+                    scripthashes: &[&str],
+                    budget: Option<&RetryBudget>)
+                    -> Result<Balance, Error> {
+        let scripthashes = scripthashes.iter()
+            .map(|s| requests::normalize_scripthash(s))
+            .collect::<Result<Vec<String>, Error>>()?;
+        self.server_limits().check_batch(scripthashes.len())?;
+
+        let batch: Vec<Request> = scripthashes.iter()
+            .map(|scripthash| requests::scripthash_get_balance(scripthash, self.next_id()))
+            .collect();
+        let responses = self.send_batch_within(&batch, budget)?;
+
+        let mut total = Balance {
+            confirmed: 0,
+            unconfirmed: 0,
+        };
+        for (scripthash, response) in scripthashes.into_iter().zip(responses) {
+            let balance: Balance = match response.into_result() {
+                Ok(balance) => balance,
+                Err(e) => return Err(Error::ScripthashFailed(scripthash, Box::new(e))),
+            };
+            total.confirmed += balance.confirmed;
+            total.unconfirmed += balance.unconfirmed;
+        }
+        Ok(total)
+    }
+
+    /// Fetches the unspent outputs of a scripthash
+    ///
+    /// Gives `Error::InvalidUtxo` if the server's result cannot be decoded.
+    pub fn scripthash_listunspent(&self, scripthash: &str) -> Result<Vec<Utxo>, Error> {
+        let scripthash = requests::normalize_scripthash(scripthash)?;
+        let request = requests::scripthash_listunspent(&scripthash, self.next_id());
+        let result: Value = self.send_request(&request)?.into_result()?;
+        serde_json::value::from_value(result).map_err(|e| Error::InvalidUtxo(e.to_string()))
+    }
+
+    /// Fetches the history of a scripthash in the server's order
+    pub fn scripthash_get_history(&self, scripthash: &str) -> Result<Vec<HistoryEntry>, Error> {
+        let scripthash = requests::normalize_scripthash(scripthash)?;
+        let request = requests::scripthash_get_history(&scripthash, self.next_id());
+        let result: Value = self.send_request(&request)?.into_result()?;
+        Ok(serde_json::value::from_value(result)?)
+    }
+
+    /// Fetches the history of a scripthash along with the raw hex of each
+    /// transaction in it
+    ///
+    /// As with `transactions_get`, a transaction the server cannot give is
+    /// an error for its entry alone.
+    pub fn history_with_txs(&self,
+                            scripthash: &str)
+                            -> Result<Vec<HistoryWithTx>, Error> {
+        let history = self.scripthash_get_history(scripthash)?;
+        let txs = {
+            let txids: Vec<&str> = history.iter().map(|e| e.tx_hash.as_str()).collect();
+            self.transactions_get(&txids)?
+        };
+        Ok(history.into_iter().zip(txs).collect())
+    }
+
+    /// Checks whether output `vout` of `txid` has been spent, as far as the
+    /// server knows, including by unconfirmed transactions
+    ///
+    /// Servers cannot look up spends directly, so this needs `scripthash`,
+    /// the scripthash the output pays to: a spending transaction appears in
+    /// its history, and each transaction there is fetched and its inputs
+    /// checked. That costs a request per history entry, and a wrong
+    /// scripthash simply reports the output as unspent.
+    pub fn is_output_spent(&self, txid: &str, vout: u32, scripthash: &str) -> Result<bool, Error> {
+        let txid = txid.to_ascii_lowercase();
+        for entry in self.scripthash_get_history(scripthash)? {
+            if entry.tx_hash.eq_ignore_ascii_case(&txid) {
+                continue;
+            }
+            let raw = self.transaction_get_bytes(&entry.tx_hash)?;
+            if parse_inputs(&raw)?.iter().any(|input| input.txid == txid && input.vout == vout) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Fetches the confirmed and unconfirmed history of a scripthash as one
+    /// list, in the order a wallet would show it
+    ///
+    /// Confirmed transactions come first by ascending height, followed by
+    /// any claiming a height above `chain_height`, which the caller has not
+    /// yet seen confirmed, then unconfirmed transactions and finally those
+    /// with unconfirmed inputs. The server's order is kept between equals.
+    pub fn scripthash_history_sorted(&self,
+                                     scripthash: &str,
+                                     chain_height: u32)
+                                     -> Result<Vec<HistoryEntry>, Error> {
+        let mut history = self.scripthash_get_history(scripthash)?;
+        let chain_height = i64::from(chain_height);
+        history.sort_by_key(|entry| match entry.height {
+            h if h > chain_height => (1, h),
+            h if h > 0 => (0, h),
+            0 => (2, 0),
+            _ => (3, 0),
+        });
+        Ok(history)
+    }
+
+    /// Subscribes to a scripthash, returning its current status, which is
+    /// `None` if it has no history
+    pub fn scripthash_subscribe(&self, scripthash: &str) -> Result<Option<String>, Error> {
+        let scripthash = requests::normalize_scripthash(scripthash)?;
+        let request = requests::scripthash_subscribe(&scripthash, self.next_id());
+        let response = self.send_request(&request)?;
+        // The null status of a scripthash with no history arrives as no
+        // result at all
+        if response.is_none() {
+            Ok(None)
+        } else {
+            response.into_result()
+        }
+    }
+
+    /// Fetches the balance, history and subscription status of each of
+    /// `scripthashes`, as a wallet needs when it opens
+    ///
+    /// Everything is fetched in one batch of three requests per distinct
+    /// scripthash, which must fit the recorded server limits. A truncated
+    /// batch gives `Error::IncompleteBatch`, and an error for any one
+    /// scripthash fails the call with `Error::ScripthashFailed` naming it.
+    pub fn prefetch(&self, scripthashes: &[&str]) -> Result<WalletSnapshot, Error> {
+        let mut distinct: Vec<String> = vec![];
+        for scripthash in scripthashes {
+            let scripthash = requests::normalize_scripthash(scripthash)?;
+            if !distinct.contains(&scripthash) {
+                distinct.push(scripthash);
+            }
+        }
+        self.server_limits().check_batch(3 * distinct.len())?;
+
+        let mut batch = Vec::with_capacity(3 * distinct.len());
+        for scripthash in &distinct {
+            batch.push(requests::scripthash_get_balance(scripthash, self.next_id()));
+            batch.push(requests::scripthash_get_history(scripthash, self.next_id()));
+            batch.push(requests::scripthash_subscribe(scripthash, self.next_id()));
+        }
+        let mut responses = self.send_batch_complete(&batch)?.into_iter();
+
+        let mut snapshot = WalletSnapshot { scripthashes: Vec::with_capacity(distinct.len()) };
+        for scripthash in distinct {
+            let mut next = || responses.next().ok_or(Error::NoErrorOrResult);
+            let fetched = next().and_then(Response::into_result).and_then(|balance| {
+                let history = next()?.into_result()?;
+                let status = next()?;
+                // As in `scripthash_subscribe`, a null status is no result
+                let status = if status.is_none() { None } else { status.into_result()? };
+                Ok((balance, history, status))
+            });
+            let (balance, history, status) = match fetched {
+                Ok(fetched) => fetched,
+                Err(e) => return Err(Error::ScripthashFailed(scripthash, Box::new(e))),
+            };
+            snapshot.scripthashes.push(ScripthashSnapshot {
+                scripthash,
+                balance,
+                history,
+                status,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    /// Subscribes to each of `scripthashes` in one batch, returning their
+    /// statuses aligned with the input
+    ///
+    /// Each distinct scripthash is only subscribed once, however many times
+    /// or in whatever case it appears. A truncated batch gives
+    /// `Error::IncompleteBatch`. More distinct scripthashes than the
+    /// recorded server limits allow give `Error::ExceedsServerLimit`
+    /// without sending anything.
+    pub fn subscribe_many(&self, scripthashes: &[&str]) -> Result<Vec<Option<String>>, Error> {
+        let normalized = scripthashes.iter()
+            .map(|s| requests::normalize_scripthash(s))
+            .collect::<Result<Vec<String>, Error>>()?;
+        let mut unique: Vec<&str> = vec![];
+        for scripthash in &normalized {
+            if !unique.contains(&scripthash.as_str()) {
+                unique.push(scripthash);
+            }
+        }
+        self.server_limits().check_batch(unique.len())?;
+
+        let batch: Vec<Request> = unique.iter()
+            .map(|scripthash| requests::scripthash_subscribe(scripthash, self.next_id()))
+            .collect();
+        let mut statuses = HashMap::new();
+        for (scripthash, response) in unique.into_iter().zip(self.send_batch_complete(&batch)?) {
+            // As in `scripthash_subscribe`, a null status is no result
+            let status = if response.is_none() { None } else { response.into_result()? };
+            statuses.insert(scripthash, status);
+        }
+        Ok(normalized.iter().map(|s| statuses[s.as_str()].clone()).collect())
+    }
+
+    /// Lazily fetches the headers from `from_height` up to but excluding
+    /// `to_height`, checking that each extends the one before
+    ///
+    /// Headers are requested in chunks of at most `HEADER_CHUNK_SIZE` as the
+    /// iterator advances. The iterator ends after the first error.
+    /// `HeaderSync::with_progress` reports how far the sync has got.
+    pub fn sync_headers<'a>(&'a self, from_height: u32, to_height: u32) -> HeaderSync<'a> {
+        HeaderSync::new(self, from_height, to_height)
+    }
+
+    /// Fetches a raw transaction, decoded from hex
+    pub fn transaction_get_bytes(&self, txid: &str) -> Result<Vec<u8>, Error> {
+        let request = requests::transaction_get(txid, false, self.next_id());
+        let raw: String = self.send_request(&request)?.into_result()?;
+        hex::decode(&raw)
+    }
+
+    /// Fetches the raw transaction hex for each of `txids`, in one batch
+    ///
+    /// The results are aligned with `txids`, so an error response for one
+    /// transaction (e.g. because the server does not know it) does not fail
+    /// the others. Transport errors and truncated batches abort the whole
+    /// call, and more txids than the recorded server limits allow give
+    /// `Error::ExceedsServerLimit` without sending anything.
+    pub fn transactions_get(&self, txids: &[&str]) -> Result<Vec<Result<String, Error>>, Error> {
+        self.server_limits().check_batch(txids.len())?;
+        let batch: Vec<Request> = txids.iter()
+            .map(|txid| requests::transaction_get(txid, false, self.next_id()))
+            .collect();
+        Ok(self.send_batch_complete(&batch)?.into_iter().map(Response::into_result).collect())
+    }
+
+    /// Works out the fee rate of a transaction, in satoshis per virtual byte
+    ///
+    /// The transaction is fetched verbose, then the transactions its inputs
+    /// spend are fetched in one batch to value the inputs. Coinbase
+    /// transactions have no fee, and give `Error::InvalidTransaction`, as
+    /// does a transaction whose outputs are worth more than its inputs.
+    pub fn tx_fee_rate(&self, txid: &str) -> Result<f64, Error> {
+        let request = requests::transaction_get(txid, true, self.next_id());
+        let tx: VerboseTx = self.send_request(&request)?.into_result()?;
+        if tx.vsize == 0 {
+            return Err(Error::InvalidTransaction(format!("{} has no size", txid)));
+        }
+
+        let mut prevouts = Vec::with_capacity(tx.vin.len());
+        for input in &tx.vin {
+            match (input.txid.as_ref(), input.vout) {
+                (Some(prev_txid), Some(vout)) => prevouts.push((prev_txid.as_str(), vout)),
+                _ => return Err(Error::InvalidTransaction(format!("{} is a coinbase", txid))),
+            }
+        }
+        let mut prev_txids: Vec<&str> = prevouts.iter().map(|&(prev_txid, _)| prev_txid).collect();
+        prev_txids.sort();
+        prev_txids.dedup();
+        self.server_limits().check_batch(prev_txids.len())?;
+
+        let batch: Vec<Request> = prev_txids.iter()
+            .map(|prev_txid| requests::transaction_get(prev_txid, true, self.next_id()))
+            .collect();
+        let mut prev_txs = HashMap::new();
+        for (prev_txid, response) in prev_txids.into_iter().zip(self.send_batch_complete(&batch)?) {
+            let prev_tx: VerboseTx = response.into_result()?;
+            prev_txs.insert(prev_txid, prev_tx);
+        }
+
+        let mut input_value = 0;
+        for (prev_txid, vout) in prevouts {
+            let output = prev_txs.get(prev_txid)
+                .and_then(|prev_tx| prev_tx.vout.get(vout as usize))
+                .ok_or_else(|| {
+                    Error::InvalidTransaction(format!("{} spends missing output {}:{}",
+                                                      txid,
+                                                      prev_txid,
+                                                      vout))
+                })?;
+            input_value += btc_to_sat(output.value);
+        }
+        let output_value: i64 = tx.vout.iter().map(|output| btc_to_sat(output.value)).sum();
+        let fee = input_value - output_value;
+        if fee < 0 {
+            return Err(Error::InvalidTransaction(format!("{} spends more than its inputs", txid)));
+        }
+        Ok(fee as f64 / tx.vsize as f64)
+    }
+
+    /// Fetches the raw coinbase transaction of the block at `height`,
+    /// checking it against the block's header
+    ///
+    /// The coinbase's txid is fetched with its merkle proof, which must lead
+    /// to the merkle root of the header at `height`, and the transaction
+    /// fetched must hash to that txid. Either failing gives
+    /// `Error::InvalidMerkleProof`.
+    pub fn coinbase_tx(&self, height: u32) -> Result<String, Error> {
+        let header = self.block_header(height)?;
+        let request = requests::transaction_id_from_pos(height, 0, true, self.next_id());
+        let id: TxIdWithProof = self.send_request(&request)?.into_result()?;
+        let proof = MerkleProof {
+            pos: 0,
+            merkle: id.merkle,
+        };
+        proof.verify(&id.tx_hash, &header)?;
+
+        let request = requests::transaction_get(&id.tx_hash, false, self.next_id());
+        let raw: String = self.send_request(&request)?.into_result()?;
+        if !txid(&hex::decode(&raw)?)?.eq_ignore_ascii_case(&id.tx_hash) {
+            return Err(Error::InvalidMerkleProof(id.tx_hash));
+        }
+        Ok(raw)
+    }
+
+    /// Broadcasts a raw transaction, returning its txid
+    pub fn broadcast(&self, raw_hex: &str) -> Result<String, Error> {
+        let request = requests::transaction_broadcast(raw_hex, self.next_id());
+        self.send_request(&request)?.into_result()
+    }
+
+    /// Broadcasts a raw transaction, then polls until the server returns it
+    /// from `blockchain.transaction.get`
+    ///
+    /// Some servers return a txid for a transaction they then drop, so this
+    /// gives `Error::BroadcastNotSeen` if the transaction is not visible
+    /// within `timeout`. Only errors saying the transaction is not found
+    /// lead to another poll; any other error response is returned at once.
+    pub fn broadcast_and_confirm(&self, raw_hex: &str, timeout: Duration) -> Result<String, Error> {
+        let txid = self.broadcast(raw_hex)?;
+        let deadline = self.clock().now() + timeout;
+        loop {
+            let request = requests::transaction_get(&txid, false, self.next_id());
+            match self.send_request(&request)?.check_error() {
+                Ok(()) => return Ok(txid),
+                Err(Error::Rpc(ref e)) if is_tx_not_found(e) => {}
+                Err(e) => return Err(e),
+            }
+
+            let now = self.clock().now();
+            if now >= deadline {
+                return Err(Error::BroadcastNotSeen(txid));
+            }
+            self.clock().sleep(BROADCAST_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use clock::MockClock;
+    use electrum::{Balance, ProtocolDowngrade};
+    use electrum::header::tests::chain;
+    use electrum::merkle::tests::{BLOCK_100000_HEX, BLOCK_100000_TXIDS, coinbase_proof};
+    use electrum::transaction::tests::{BLOCK_100000_COINBASE_HEX, PREV_TXID, spending};
+    use error::Error;
+    use hex;
+    use retry::{RetryBudget, RetryPolicy};
+    use test_utils::{MockServer, reply, reply_error};
+
+    const SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+
+    fn tip_server(height: u32) -> MockServer {
+        MockServer::new(move |req| {
+            assert_eq!(req.method, "blockchain.headers.subscribe");
+            reply(req.id, json!({"height": height, "hex": "00"}))
+        })
+    }
+
+    #[test]
+    fn test_time_skew() {
+        let tip = hex::encode(&chain(1)[0].to_bytes());
+        let server = MockServer::new(move |req| {
+            assert_eq!(req.method, "blockchain.headers.subscribe");
+            reply(req.id, json!({"height": 0, "hex": tip}))
+        });
+        // The genesis block is timestamped 1231006505
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(1231006000)));
+        let client = server.client().with_clock(clock.clone());
+
+        assert_eq!(client.time_skew().unwrap(), 505);
+        clock.advance(Duration::from_secs(1000));
+        assert_eq!(client.time_skew().unwrap(), -495);
+    }
+
+    #[test]
+    fn test_tip_age_behind() {
+        let server = tip_server(500_000);
+        assert_eq!(server.client().tip_age(500_004).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_tip_age_current_or_ahead() {
+        let server = tip_server(500_000);
+        assert_eq!(server.client().tip_age(500_000).unwrap(), 0);
+        assert_eq!(server.client().tip_age(499_999).unwrap(), -1);
+    }
+
+    #[test]
+    fn test_is_synced() {
+        let server = tip_server(500_000);
+        assert!(server.client().is_synced(500_000, 0).unwrap());
+        assert!(server.client().is_synced(500_002, 2).unwrap());
+        assert!(server.client().is_synced(499_990, 0).unwrap());
+    }
+
+    #[test]
+    fn test_is_synced_lagging() {
+        let server = tip_server(500_000);
+        assert!(!server.client().is_synced(500_003, 2).unwrap());
+    }
+
+    #[test]
+    fn test_is_synced_warming_up() {
+        let server = MockServer::new(|req| {
+            reply_error(req.id, -28, "daemon error: Loading block index...")
+        });
+        assert!(!server.client().is_synced(500_000, 2).unwrap());
+
+        let server = MockServer::new(|req| reply_error(req.id, -32603, "internal error"));
+        assert!(server.client().is_synced(500_000, 2).is_err());
+    }
+
+    #[test]
+    fn test_uppercase_scripthash_sent_lowercase() {
+        let mut balances = HashMap::new();
+        balances.insert(SCRIPTHASH.to_owned(), json!({"confirmed": 7, "unconfirmed": 0}));
+        let server = MockServer::new(move |req| {
+            let empty = json!({"confirmed": 0, "unconfirmed": 0});
+            let balance = req.params[0].as_str().and_then(|s| balances.get(s)).unwrap_or(&empty);
+            reply(req.id, balance.clone())
+        });
+        let client = server.client();
+
+        let balance = client.scripthash_get_balance(&SCRIPTHASH.to_uppercase()).unwrap();
+        assert_eq!(balance.confirmed, 7);
+    }
+
+    #[test]
+    fn test_invalid_scripthash_not_sent() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        match server.client().scripthash_get_balance("not a scripthash") {
+            Err(Error::InvalidScripthash(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[test]
+    fn test_transactions_get() {
+        let server = MockServer::new(|req| if req.params[0] == json!("aa") {
+            reply(req.id, json!("0100"))
+        } else {
+            reply_error(req.id, -5, "No such mempool or blockchain transaction")
+        });
+        let client = server.client();
+
+        let results = client.transactions_get(&["aa", "bb", "aa"]).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "0100");
+        match results[1] {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -5),
+            _ => panic!("expected an RPC error"),
+        }
+        assert_eq!(results[2].as_ref().unwrap(), "0100");
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_transactions_get_exceeds_limit() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "server.features" => reply(req.id, json!({"max_batch_size": 2})),
+            _ => reply(req.id, json!("0100")),
+        });
+        let client = server.client();
+        let limits = client.fetch_server_limits().unwrap();
+        let client = client.with_server_limits(limits);
+
+        assert_eq!(client.transactions_get(&["aa", "bb"]).unwrap().len(), 2);
+        let hits = server.hits();
+        match client.transactions_get(&["aa", "bb", "cc"]) {
+            Err(Error::ExceedsServerLimit(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), hits);
+    }
+
+    #[test]
+    fn test_transactions_get_empty() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let results = server.client().transactions_get(&[]).unwrap();
+        assert!(results.is_empty());
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[test]
+    fn test_scripthash_history_sorted() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.scripthash.get_history");
+            reply(req.id,
+                  json!([{"tx_hash": "mempool", "height": 0, "fee": 200},
+                         {"tx_hash": "b", "height": 200007},
+                         {"tx_hash": "child", "height": -1, "fee": 300},
+                         {"tx_hash": "ahead", "height": 200012},
+                         {"tx_hash": "a", "height": 200004},
+                         {"tx_hash": "c", "height": 200007}]))
+        });
+        let history = server.client().scripthash_history_sorted(SCRIPTHASH, 200010).unwrap();
+        let order: Vec<&str> = history.iter().map(|e| e.tx_hash.as_str()).collect();
+        assert_eq!(order, ["a", "b", "c", "ahead", "mempool", "child"]);
+        assert_eq!(history[4].fee, Some(200));
+    }
+
+    #[test]
+    fn test_connect_and_handshake() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "server.version" => {
+                assert_eq!(req.params, vec![json!("wallet"), json!("1.4")]);
+                reply(req.id, json!(["ElectrumX 1.16.0", "1.4"]))
+            }
+            "server.features" => reply(req.id, json!({"max_batch_size": 50})),
+            _ => panic!("unexpected method {}", req.method),
+        });
+
+        let client = server.client()
+            .connect_and_handshake("wallet", &"1.4".parse().unwrap())
+            .unwrap();
+        assert_eq!(client.protocol_version(), Some("1.4".parse().unwrap()));
+        assert_eq!(client.server_limits().max_batch_size, Some(50));
+    }
+
+    #[test]
+    fn test_connect_and_handshake_features_fail() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "server.version" => reply(req.id, json!(["ElectrumX 1.16.0", "1.4"])),
+            _ => reply_error(req.id, -32603, "internal error"),
+        });
+
+        match server.client().connect_and_handshake("wallet", &"1.4".parse().unwrap()) {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32603),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("a client was produced despite the failed handshake"),
+        }
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[test]
+    fn test_server_version() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "server.version");
+            assert_eq!(req.params, vec![json!("wallet"), json!("1.4")]);
+            reply(req.id, json!(["ElectrumX 1.16.0", "1.4"]))
+        });
+        assert_eq!(server.client().server_version("wallet", "1.4").unwrap(),
+                   ("ElectrumX 1.16.0".to_owned(), "1.4".to_owned()));
+
+        let server = MockServer::new(|req| reply(req.id, json!(["ElectrumX 1.16.0"])));
+        match server.client().server_version("wallet", "1.4") {
+            Err(Error::NoErrorOrResult) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let empty = "aa".repeat(32);
+        let server = MockServer::new(move |req| {
+            let funded = req.params[0] == json!(SCRIPTHASH);
+            match (req.method.as_str(), funded) {
+                ("blockchain.scripthash.get_balance", true) => {
+                    reply(req.id, json!({"confirmed": 1000, "unconfirmed": -200}))
+                }
+                ("blockchain.scripthash.get_balance", false) => {
+                    reply(req.id, json!({"confirmed": 0, "unconfirmed": 0}))
+                }
+                ("blockchain.scripthash.get_history", true) => {
+                    reply(req.id, json!([{"tx_hash": "a", "height": 10}]))
+                }
+                ("blockchain.scripthash.get_history", false) => reply(req.id, json!([])),
+                ("blockchain.scripthash.subscribe", true) => reply(req.id, json!("status")),
+                ("blockchain.scripthash.subscribe", false) => reply(req.id, json!(null)),
+                _ => panic!("unexpected method {}", req.method),
+            }
+        });
+        let other = "AA".repeat(32);
+        let snapshot = server.client().prefetch(&[SCRIPTHASH, &other, &empty]).unwrap();
+        assert_eq!(server.hits(), 1);
+        assert_eq!(snapshot.scripthashes.len(), 2);
+
+        let funded = snapshot.get(SCRIPTHASH).unwrap();
+        assert_eq!(funded.history.len(), 1);
+        assert_eq!(funded.history[0].tx_hash, "a");
+        assert_eq!(funded.status, Some("status".to_owned()));
+        let unused = snapshot.get(&empty).unwrap();
+        assert!(unused.history.is_empty());
+        assert_eq!(unused.status, None);
+        assert_eq!(snapshot.balance(),
+                   Balance {
+                       confirmed: 1000,
+                       unconfirmed: -200,
+                   });
+    }
+
+    #[test]
+    fn test_handshake_with_budget() {
+        // Drops the first attempt at each request
+        let attempts = Arc::new(Mutex::new(HashMap::new()));
+        let server = MockServer::flaky(move |req| {
+            let mut attempts = attempts.lock().unwrap();
+            let attempt = attempts.entry(req.method.clone()).or_insert(0);
+            *attempt += 1;
+            if *attempt == 1 {
+                return None;
+            }
+            Some(match req.method.as_str() {
+                "server.version" => reply(req.id, json!(["ElectrumX 1.16.0", "1.4"])),
+                "server.features" => reply(req.id, json!({"max_batch_size": 10})),
+                _ => panic!("unexpected method {}", req.method),
+            })
+        });
+        let budget = RetryBudget::new(2);
+        // Only the budget retries the dropped attempts
+        let client = server.client()
+            .with_retry_policy(RetryPolicy::never())
+            .connect_and_handshake_with_budget("wallet", &"1.4".parse().unwrap(), &budget)
+            .unwrap();
+        assert_eq!(client.protocol_version(), Some("1.4".parse().unwrap()));
+        assert_eq!(client.server_limits().max_batch_size, Some(10));
+        assert_eq!(budget.remaining(), 0);
+    }
+
+    #[test]
+    fn test_wallet_balance_exhausts_budget() {
+        // Drops the connection on the second request of every batch
+        let count = AtomicUsize::new(0);
+        let server = MockServer::flaky(move |req| {
+            assert_eq!(req.method, "blockchain.scripthash.get_balance");
+            if count.fetch_add(1, Ordering::SeqCst) % 3 == 1 {
+                return None;
+            }
+            Some(reply(req.id, json!({"confirmed": 1000, "unconfirmed": 0})))
+        });
+        let budget = RetryBudget::new(1);
+        let others = ["aa".repeat(32), "bb".repeat(32)];
+        let scripthashes = [SCRIPTHASH, &others[0], &others[1]];
+
+        // The first batch takes the only retry, and the second needs another
+        let client = server.client().with_retry_policy(RetryPolicy::never());
+        match client.wallet_balance_with_budget(&scripthashes, &budget) {
+            Err(Error::RetryBudgetExhausted(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(budget.remaining(), 0);
+        assert_eq!(server.hits(), 0);
+    }
+
+    #[test]
+    fn test_verify_protocol_version_downgrades() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.transaction.id_from_pos" => {
+                reply_error(req.id, -32601, "unknown method")
+            }
+            "blockchain.block.header" => reply(req.id, json!("0100")),
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client()
+            .with_protocol_version("1.4".parse().unwrap())
+            .verify_protocol_version()
+            .unwrap();
+        assert_eq!(client.protocol_version(), Some("1.3".parse().unwrap()));
+        assert_eq!(server.hits(), 2);
+    }
+
+    fn fee_rate_server() -> MockServer {
+        MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.transaction.get");
+            assert_eq!(req.params[1], json!(true));
+            let tx = match req.params[0].as_str().unwrap() {
+                // Spends 100000 + 50000 + 25000 sat, pays out 145000 sat
+                "spend" => {
+                    json!({"vin": [{"txid": "a", "vout": 0},
+                                   {"txid": "b", "vout": 1},
+                                   {"txid": "a", "vout": 1}],
+                           "vout": [{"value": 0.0012, "n": 0}, {"value": 0.00025, "n": 1}],
+                           "vsize": 150})
+                }
+                "a" => {
+                    json!({"vin": [{"coinbase": "04ffff001d"}],
+                           "vout": [{"value": 0.001, "n": 0}, {"value": 0.00025, "n": 1}],
+                           "vsize": 100})
+                }
+                "b" => {
+                    json!({"vin": [{"txid": "a", "vout": 0}],
+                           "vout": [{"value": 0.1, "n": 0}, {"value": 0.0005, "n": 1}],
+                           "vsize": 110})
+                }
+                _ => return reply_error(req.id, -5, "No such mempool or blockchain transaction"),
+            };
+            reply(req.id, tx)
+        })
+    }
+
+    #[test]
+    fn test_tx_fee_rate() {
+        let server = fee_rate_server();
+        // A fee of 30000 sat over 150 vbytes
+        assert_eq!(server.client().tx_fee_rate("spend").unwrap(), 200.0);
+        // One request for the transaction, then one batch for both prevouts
+        assert_eq!(server.hits(), 2);
+    }
+
+    fn coinbase_server(coinbase: String) -> MockServer {
+        MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.block.header" => {
+                assert_eq!(req.params, vec![json!(100000)]);
+                reply(req.id, json!(BLOCK_100000_HEX))
+            }
+            "blockchain.transaction.id_from_pos" => {
+                assert_eq!(req.params, vec![json!(100000), json!(0), json!(true)]);
+                let proof = coinbase_proof();
+                reply(req.id, json!({"tx_hash": BLOCK_100000_TXIDS[0], "merkle": proof.merkle}))
+            }
+            "blockchain.transaction.get" => {
+                assert_eq!(req.params, vec![json!(BLOCK_100000_TXIDS[0]), json!(false)]);
+                reply(req.id, json!(coinbase))
+            }
+            _ => panic!("unexpected method {}", req.method),
+        })
+    }
+
+    #[test]
+    fn test_coinbase_tx() {
+        let server = coinbase_server(BLOCK_100000_COINBASE_HEX.to_owned());
+        assert_eq!(server.client().coinbase_tx(100000).unwrap(), BLOCK_100000_COINBASE_HEX);
+    }
+
+    #[test]
+    fn test_coinbase_tx_mismatch() {
+        // A transaction which does not hash to the proven txid
+        let server = coinbase_server(hex::encode(&spending(&[(PREV_TXID, 0)], false)));
+        match server.client().coinbase_tx(100000) {
+            Err(Error::InvalidMerkleProof(ref txid)) => assert_eq!(txid, BLOCK_100000_TXIDS[0]),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tx_fee_rate_coinbase() {
+        match fee_rate_server().client().tx_fee_rate("a") {
+            Err(Error::InvalidTransaction(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_adaptive_downgrade() {
+        let genesis = hex::encode(&chain(1)[0].to_bytes());
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.block.header" => reply_error(req.id, -32601, "unknown method"),
+            "blockchain.block.headers" => {
+                reply(req.id, json!({"count": 1, "hex": genesis, "max": 2016}))
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let downgrades = Arc::new(Mutex::new(vec![]));
+        let seen = downgrades.clone();
+        let client = server.client()
+            .with_protocol_version("1.4".parse().unwrap())
+            .with_adaptive_downgrade(2, move |d| seen.lock().unwrap().push(d.clone()));
+
+        // Two misses at 1.4 downgrade to 1.3, which still uses the same method
+        for _ in 0..2 {
+            assert!(client.block_header(0).is_err());
+        }
+        assert_eq!(client.protocol_version(), Some("1.3".parse().unwrap()));
+        for _ in 0..2 {
+            assert!(client.block_header(0).is_err());
+        }
+        assert_eq!(client.protocol_version(), Some("1.2".parse().unwrap()));
+
+        // At 1.2 the older request shape succeeds
+        assert_eq!(client.block_header(0).unwrap(), chain(1)[0]);
+        assert_eq!(*downgrades.lock().unwrap(),
+                   vec![ProtocolDowngrade {
+                            from: "1.4".parse().unwrap(),
+                            to: "1.3".parse().unwrap(),
+                            method: "blockchain.block.header".to_owned(),
+                        },
+                        ProtocolDowngrade {
+                            from: "1.3".parse().unwrap(),
+                            to: "1.2".parse().unwrap(),
+                            method: "blockchain.block.header".to_owned(),
+                        }]);
+        assert_eq!(server.hits(), 5);
+    }
+
+    #[test]
+    fn test_no_downgrade_without_opt_in() {
+        let server = MockServer::new(|req| reply_error(req.id, -32601, "unknown method"));
+        let client = server.client().with_protocol_version("1.4".parse().unwrap());
+        for _ in 0..3 {
+            assert!(client.block_header(0).is_err());
+        }
+        assert_eq!(client.protocol_version(), Some("1.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_verify_protocol_version_keeps_supported() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.transaction.id_from_pos");
+            reply(req.id,
+                  json!("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"))
+        });
+        let client = server.client()
+            .with_protocol_version("1.4.2".parse().unwrap())
+            .verify_protocol_version()
+            .unwrap();
+        assert_eq!(client.protocol_version(), Some("1.4.2".parse().unwrap()));
+
+        let client = server.client().verify_protocol_version().unwrap();
+        assert_eq!(client.protocol_version(), None);
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_wallet_balance() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.scripthash.get_balance");
+            let balance = match &req.params[0].as_str().unwrap()[..2] {
+                "8b" => json!({"confirmed": 1000, "unconfirmed": 0}),
+                "f0" => json!({"confirmed": 250, "unconfirmed": -100}),
+                _ => json!({"confirmed": 0, "unconfirmed": 40}),
+            };
+            reply(req.id, balance)
+        });
+        let (second, third) = ("f0".repeat(32), "0a".repeat(32));
+
+        let balance = server.client().wallet_balance(&[SCRIPTHASH, &second, &third]).unwrap();
+        assert_eq!(balance,
+                   Balance {
+                       confirmed: 1250,
+                       unconfirmed: -60,
+                   });
+        assert_eq!(server.hits(), 1);
+        assert_eq!(server.client().wallet_balance(&[]).unwrap().total(), 0);
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_wallet_balance_failure() {
+        let bad = "f0".repeat(32);
+        let server = MockServer::new(move |req| if req.params[0] == json!(SCRIPTHASH) {
+            reply(req.id, json!({"confirmed": 1000, "unconfirmed": 0}))
+        } else {
+            reply_error(req.id, -32603, "history too large")
+        });
+
+        match server.client().wallet_balance(&[SCRIPTHASH, &"F0".repeat(32)]) {
+            Err(Error::ScripthashFailed(ref scripthash, ref e)) => {
+                assert_eq!(*scripthash, bad);
+                match **e {
+                    Error::Rpc(ref e) => assert_eq!(e.code, -32603),
+                    ref other => panic!("unexpected error {:?}", other),
+                }
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_is_output_spent() {
+        let other = "11".repeat(32);
+        let funding = hex::encode(&spending(&[(&other, 5)], false));
+        let spend = hex::encode(&spending(&[(&other, 0), (PREV_TXID, 1)], true));
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.scripthash.get_history" => {
+                reply(req.id,
+                      json!([{"tx_hash": PREV_TXID, "height": 200004},
+                             {"tx_hash": "bb", "height": 0}]))
+            }
+            "blockchain.transaction.get" if req.params[0] == json!("bb") => {
+                reply(req.id, json!(spend))
+            }
+            "blockchain.transaction.get" => reply(req.id, json!(funding)),
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client();
+
+        assert!(client.is_output_spent(PREV_TXID, 1, SCRIPTHASH).unwrap());
+        assert!(!client.is_output_spent(&PREV_TXID.to_uppercase(), 0, SCRIPTHASH).unwrap());
+        assert!(!client.is_output_spent(&"22".repeat(32), 1, SCRIPTHASH).unwrap());
+    }
+
+    #[test]
+    fn test_history_with_txs() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.scripthash.get_history" => {
+                reply(req.id,
+                      json!([{"tx_hash": "aa", "height": 200004},
+                             {"tx_hash": "bb", "height": 0, "fee": 200}]))
+            }
+            "blockchain.transaction.get" if req.params[0] == json!("aa") => {
+                reply(req.id, json!("0100"))
+            }
+            "blockchain.transaction.get" => {
+                reply_error(req.id, -5, "No such mempool or blockchain transaction")
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+
+        let history = server.client().history_with_txs(SCRIPTHASH).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].0.tx_hash, "aa");
+        assert_eq!(history[0].1.as_ref().unwrap(), "0100");
+        assert_eq!(history[1].0.fee, Some(200));
+        match history[1].1 {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -5),
+            _ => panic!("expected an RPC error"),
+        }
+        // The history, then both transactions in one batch
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[test]
+    fn test_subscribe_many_dedupes() {
+        let other = "f0".repeat(32);
+        let subscribed = Arc::new(Mutex::new(vec![]));
+        let seen = subscribed.clone();
+        let server = MockServer::new(move |req| {
+            assert_eq!(req.method, "blockchain.scripthash.subscribe");
+            let scripthash = req.params[0].as_str().unwrap().to_owned();
+            let status = if scripthash == SCRIPTHASH {
+                json!("1f3a")
+            } else {
+                json!(null)
+            };
+            seen.lock().unwrap().push(scripthash);
+            reply(req.id, status)
+        });
+
+        let upper = SCRIPTHASH.to_uppercase();
+        let statuses = server.client()
+            .subscribe_many(&[SCRIPTHASH, &other, &upper, SCRIPTHASH])
+            .unwrap();
+        assert_eq!(statuses,
+                   vec![Some("1f3a".to_owned()), None, Some("1f3a".to_owned()),
+                        Some("1f3a".to_owned())]);
+        assert_eq!(*subscribed.lock().unwrap(), vec![SCRIPTHASH.to_owned(), other]);
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_transaction_get_bytes() {
+        let server = MockServer::new(|req| if req.params[0] == json!("aa") {
+            reply(req.id, json!("0100ff"))
+        } else {
+            reply(req.id, json!("0100f"))
+        });
+        let client = server.client();
+
+        assert_eq!(client.transaction_get_bytes("aa").unwrap(), vec![0x01, 0x00, 0xff]);
+        match client.transaction_get_bytes("bb") {
+            Err(Error::InvalidHex) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_broadcast_and_confirm() {
+        let lookups = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.transaction.broadcast" => reply(req.id, json!("abcd")),
+            "blockchain.transaction.get" => {
+                assert_eq!(req.params[0], json!("abcd"));
+                // Not visible on the first lookup, then present
+                if lookups.fetch_add(1, Ordering::SeqCst) == 0 {
+                    reply_error(req.id, -5, "No such mempool or blockchain transaction")
+                } else {
+                    reply(req.id, json!("0100"))
+                }
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+
+        let txid = server.client().broadcast_and_confirm("0100", Duration::from_secs(5)).unwrap();
+        assert_eq!(txid, "abcd");
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[test]
+    fn test_broadcast_not_seen_mock_clock() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.transaction.broadcast" => reply(req.id, json!("abcd")),
+            _ => reply_error(req.id, -5, "No such mempool or blockchain transaction"),
+        });
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = server.client().with_clock(clock.clone());
+
+        let started = Instant::now();
+        match client.broadcast_and_confirm("0100", Duration::from_secs(10)) {
+            Err(Error::BroadcastNotSeen(ref txid)) => assert_eq!(txid, "abcd"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // The broadcast, then a lookup every 500ms up to the deadline
+        assert_eq!(server.hits(), 1 + 21);
+    }
+
+    #[test]
+    fn test_broadcast_not_seen() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.transaction.broadcast" => reply(req.id, json!("abcd")),
+            _ => reply_error(req.id, -5, "No such mempool or blockchain transaction"),
+        });
+
+        match server.client().broadcast_and_confirm("0100", Duration::from_millis(50)) {
+            Err(Error::BroadcastNotSeen(ref txid)) => assert_eq!(txid, "abcd"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_broadcast_lookup_error() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.transaction.broadcast" => reply(req.id, json!("abcd")),
+            _ => reply_error(req.id, -32601, "unknown method"),
+        });
+        match server.client().broadcast_and_confirm("0100", Duration::from_secs(5)) {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32601),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(server.hits(), 2);
+
+        // ElectrumX wraps the not-found error in a daemon error
+        let lookups = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.transaction.broadcast" => reply(req.id, json!("abcd")),
+            _ if lookups.fetch_add(1, Ordering::SeqCst) == 0 => {
+                reply_error(req.id,
+                            2,
+                            "daemon error: DaemonError({'code': -5, 'message': 'No such \
+                             mempool or blockchain transaction. Use gettransaction for \
+                             wallet transactions.'})")
+            }
+            _ => reply(req.id, json!("0100")),
+        });
+        assert_eq!(server.client().broadcast_and_confirm("0100", Duration::from_secs(5)).unwrap(),
+                   "abcd");
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[test]
+    fn test_broadcast_rejected() {
+        let server = MockServer::new(|req| reply_error(req.id, 1, "bad-txns-inputs-missingorspent"));
+        match server.client().broadcast_and_confirm("0100", Duration::from_secs(5)) {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
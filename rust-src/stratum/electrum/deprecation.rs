@@ -0,0 +1,110 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Method deprecations
+//!
+//! Which methods each protocol version has deprecated or removed
+//!
+
+use super::version::ProtocolVersion;
+
+/// Removed in protocol 1.1
+const DEPRECATED_1_1: &[&str] = &["blockchain.numblocks.subscribe", "blockchain.utxo.get_address"];
+
+/// Deprecated in protocol 1.2, in favour of the scripthash methods
+const DEPRECATED_1_2: &[&str] = &["blockchain.numblocks.subscribe",
+                                  "blockchain.utxo.get_address",
+                                  "blockchain.address.get_balance",
+                                  "blockchain.address.get_history",
+                                  "blockchain.address.get_mempool",
+                                  "blockchain.address.listunspent",
+                                  "blockchain.address.subscribe",
+                                  "blockchain.block.get_chunk"];
+
+/// Deprecated in protocol 1.3, in favour of `blockchain.block.header`
+const DEPRECATED_1_3: &[&str] = &["blockchain.numblocks.subscribe",
+                                  "blockchain.utxo.get_address",
+                                  "blockchain.address.get_balance",
+                                  "blockchain.address.get_history",
+                                  "blockchain.address.get_mempool",
+                                  "blockchain.address.listunspent",
+                                  "blockchain.address.subscribe",
+                                  "blockchain.block.get_chunk",
+                                  "blockchain.block.get_header"];
+
+/// The methods deprecated or removed as of `protocol`
+pub fn deprecated_methods(protocol: &ProtocolVersion) -> &'static [&'static str] {
+    if *protocol >= ProtocolVersion::new(1, 3, 0) {
+        DEPRECATED_1_3
+    } else if *protocol >= ProtocolVersion::new(1, 2, 0) {
+        DEPRECATED_1_2
+    } else if *protocol >= ProtocolVersion::new(1, 1, 0) {
+        DEPRECATED_1_1
+    } else {
+        &[]
+    }
+}
+
+/// A warning for a call to `method` if `protocol` deprecates it
+pub fn deprecation_warning(method: &str, protocol: &ProtocolVersion) -> Option<String> {
+    if deprecated_methods(protocol).contains(&method) {
+        Some(format!("{} is deprecated as of protocol {}; the server may not support it",
+                     method,
+                     protocol))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_utils::{MockServer, reply};
+    use super::super::version::ProtocolVersion;
+    use super::{deprecated_methods, deprecation_warning};
+
+    fn version(s: &str) -> ProtocolVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_deprecated_methods() {
+        assert!(deprecated_methods(&version("1.0")).is_empty());
+        assert!(deprecated_methods(&version("1.1")).contains(&"blockchain.utxo.get_address"));
+        assert!(!deprecated_methods(&version("1.1")).contains(&"blockchain.address.get_balance"));
+        assert!(deprecated_methods(&version("1.4")).contains(&"blockchain.address.get_balance"));
+        assert!(deprecated_methods(&version("1.4")).contains(&"blockchain.block.get_header"));
+        assert!(!deprecated_methods(&version("1.4")).contains(&"blockchain.scripthash.get_balance"));
+    }
+
+    #[test]
+    fn test_deprecation_warning() {
+        let warning = deprecation_warning("blockchain.address.get_balance", &version("1.4"));
+        assert_eq!(warning.unwrap(),
+                   "blockchain.address.get_balance is deprecated as of protocol 1.4; the server \
+                    may not support it");
+        assert!(deprecation_warning("blockchain.address.get_balance", &version("1.1")).is_none());
+        assert!(deprecation_warning("server.ping", &version("1.4")).is_none());
+    }
+
+    #[test]
+    fn test_deprecated_call_still_sent() {
+        let server = MockServer::new(|req| reply(req.id, json!({"confirmed": 1, "unconfirmed": 0})));
+        let client = server.client().with_protocol_version(version("1.4"));
+
+        let req = client.build_request("blockchain.address.get_balance".to_owned(),
+                                       vec![json!("1BoatSLRHtKNngkdXEeobR76b53LETtpyT")]);
+        assert!(client.send_request(&req).unwrap().result.is_some());
+        assert_eq!(server.hits(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # Fee estimation
+//!
+//! Estimates derived from the mempool fee histogram returned by
+//! `mempool.get_fee_histogram`
+//!
+
+/// The most vbytes of transactions a block holds
+pub const MAX_BLOCK_VSIZE: u64 = 1_000_000;
+
+/// Estimates how many blocks a transaction paying `fee_rate` satoshis per
+/// vbyte will wait before confirming
+///
+/// `histogram` is a fee histogram as the server returns it: pairs of a fee
+/// rate and the vsize of mempool transactions paying around that rate. The
+/// estimate assumes miners take the highest fee rates first and that no
+/// better-paying transactions arrive, so only the backlog at or above
+/// `fee_rate` gets ahead. The result is always at least 1.
+pub fn blocks_to_confirm(histogram: &[(f64, u64)], fee_rate: f64) -> u32 {
+    let backlog: u64 = histogram.iter()
+        .filter(|&&(rate, _)| rate >= fee_rate)
+        .map(|&(_, vsize)| vsize)
+        .sum();
+    (backlog / MAX_BLOCK_VSIZE) as u32 + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::blocks_to_confirm;
+
+    #[test]
+    fn test_blocks_to_confirm() {
+        let histogram = [(60.0, 400_000), (40.0, 700_000), (20.0, 1_500_000), (5.0, 3_000_000)];
+        assert_eq!(blocks_to_confirm(&histogram, 100.0), 1);
+        assert_eq!(blocks_to_confirm(&histogram, 60.0), 1);
+        assert_eq!(blocks_to_confirm(&histogram, 40.0), 2);
+        assert_eq!(blocks_to_confirm(&histogram, 10.0), 3);
+        assert_eq!(blocks_to_confirm(&histogram, 1.0), 6);
+    }
+
+    #[test]
+    fn test_blocks_to_confirm_empty_mempool() {
+        assert_eq!(blocks_to_confirm(&[], 1.0), 1);
+    }
+
+    #[test]
+    fn test_blocks_to_confirm_server_histogram() {
+        let histogram: Vec<(f64, u64)> =
+            serde_json::from_str("[[12, 128812], [4, 92524], [2, 6478638], [1, 22890421]]")
+                .unwrap();
+        assert_eq!(blocks_to_confirm(&histogram, 4.0), 1);
+        assert_eq!(blocks_to_confirm(&histogram, 2.0), 7);
+    }
+}
@@ -0,0 +1,404 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Block headers
+//!
+//! Parsing of serialized block headers, and a lazy iterator which fetches
+//! a range of headers from a server chunk by chunk
+//!
+
+use std::collections::VecDeque;
+
+use sha2::{Digest, Sha256};
+
+use client::Client;
+use error::Error;
+use hex;
+
+use super::requests;
+
+/// The size of a serialized block header in bytes
+pub const HEADER_SIZE: usize = 80;
+
+/// The most headers requested from the server at once
+pub const HEADER_CHUNK_SIZE: u32 = 2016;
+
+/// A block header decoded from its 80-byte serialization
+///
+/// Hashes are kept in internal byte order, i.e. as they are serialized. The
+/// `_display` methods give the reversed hex form which block explorers and
+/// RPC interfaces show.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedHeader {
+    /// The block version
+    pub version: i32,
+    /// The hash of the previous block
+    pub prev_hash: [u8; 32],
+    /// The merkle root of the block's transactions
+    pub merkle_root: [u8; 32],
+    /// The block timestamp
+    pub time: u32,
+    /// The compact encoding of the difficulty target
+    pub bits: u32,
+    /// The nonce
+    pub nonce: u32,
+}
+
+impl ParsedHeader {
+    /// Decodes a header from its serialization
+    pub fn from_bytes(bytes: &[u8]) -> Result<ParsedHeader, Error> {
+        if bytes.len() != HEADER_SIZE {
+            return Err(Error::InvalidHeader(format!("expected {} bytes, got {}",
+                                                    HEADER_SIZE,
+                                                    bytes.len())));
+        }
+        let mut prev_hash = [0; 32];
+        prev_hash.copy_from_slice(&bytes[4..36]);
+        let mut merkle_root = [0; 32];
+        merkle_root.copy_from_slice(&bytes[36..68]);
+        Ok(ParsedHeader {
+            version: read_u32(&bytes[0..4]) as i32,
+            prev_hash,
+            merkle_root,
+            time: read_u32(&bytes[68..72]),
+            bits: read_u32(&bytes[72..76]),
+            nonce: read_u32(&bytes[76..80]),
+        })
+    }
+
+    /// Decodes a header from the hex of its serialization
+    pub fn from_hex(hex: &str) -> Result<ParsedHeader, Error> {
+        ParsedHeader::from_bytes(&hex::decode(hex)?)
+    }
+
+    /// Serializes the header
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&(self.version as u32).to_le_bytes());
+        bytes[4..36].copy_from_slice(&self.prev_hash);
+        bytes[36..68].copy_from_slice(&self.merkle_root);
+        bytes[68..72].copy_from_slice(&self.time.to_le_bytes());
+        bytes[72..76].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[76..80].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// The double-SHA256 hash of the header, in internal byte order
+    pub fn hash(&self) -> [u8; 32] {
+        let first = Sha256::digest(&self.to_bytes()[..]);
+        let second = Sha256::digest(&first[..]);
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&second[..]);
+        hash
+    }
+
+    /// The hash of the header as it is conventionally displayed
+    pub fn hash_display(&self) -> String {
+        display_hex(&self.hash())
+    }
+
+    /// The hash of the previous block, in internal byte order
+    pub fn prev_hash_bytes(&self) -> [u8; 32] {
+        self.prev_hash
+    }
+
+    /// The hash of the previous block as it is conventionally displayed
+    pub fn prev_hash_display(&self) -> String {
+        display_hex(&self.prev_hash)
+    }
+
+    /// The merkle root, in internal byte order
+    pub fn merkle_root_bytes(&self) -> [u8; 32] {
+        self.merkle_root
+    }
+
+    /// The merkle root as it is conventionally displayed
+    pub fn merkle_root_display(&self) -> String {
+        display_hex(&self.merkle_root)
+    }
+}
+
+/// Hex-encodes a hash in reversed byte order
+fn display_hex(hash: &[u8; 32]) -> String {
+    let mut reversed = *hash;
+    reversed.reverse();
+    hex::encode(&reversed)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
+
+/// The result of a `blockchain.block.headers` request
+#[derive(Deserialize)]
+struct HeaderChunk {
+    count: u32,
+    hex: String,
+}
+
+/// An iterator over a range of headers, fetched lazily from a server
+///
+/// Each header is checked to extend the one before it. The first header of
+/// the range is not checked, since its predecessor is not fetched.
+pub struct HeaderSync<'a> {
+    client: &'a Client,
+    from_height: u32,
+    height: u32,
+    fetch_height: u32,
+    to_height: u32,
+    pending: VecDeque<ParsedHeader>,
+    prev_hash: Option<[u8; 32]>,
+    done: bool,
+    progress: Option<Box<dyn FnMut(u32, u32) + 'a>>,
+}
+
+impl<'a> HeaderSync<'a> {
+    pub(crate) fn new(client: &'a Client, from_height: u32, to_height: u32) -> HeaderSync<'a> {
+        HeaderSync {
+            client,
+            from_height,
+            height: from_height,
+            fetch_height: from_height,
+            to_height,
+            pending: VecDeque::new(),
+            prev_hash: None,
+            done: false,
+            progress: None,
+        }
+    }
+
+    /// Calls `progress` after each chunk is fetched with the number of
+    /// headers fetched so far and the number in the whole range, e.g. to
+    /// drive a progress bar
+    pub fn with_progress<F>(mut self, progress: F) -> HeaderSync<'a>
+        where F: FnMut(u32, u32) + 'a
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    fn fetch_chunk(&mut self) -> Result<(), Error> {
+        let count = (self.to_height - self.fetch_height).min(HEADER_CHUNK_SIZE);
+        let request = requests::block_headers(self.fetch_height, count, self.client.next_id());
+        let chunk: HeaderChunk = self.client.send_request(&request)?.into_result()?;
+
+        // Servers may return fewer headers than requested, but never more
+        let count = chunk.count.min(count);
+        if count == 0 {
+            return Err(Error::InvalidHeader(format!("no headers returned from height {}",
+                                                    self.fetch_height)));
+        }
+        let bytes = hex::decode(&chunk.hex)?;
+        if bytes.len() < count as usize * HEADER_SIZE {
+            return Err(Error::InvalidHeader(format!("expected {} headers", count)));
+        }
+        for raw in bytes.chunks(HEADER_SIZE).take(count as usize) {
+            self.pending.push_back(ParsedHeader::from_bytes(raw)?);
+        }
+        self.fetch_height += count;
+        if let Some(ref mut progress) = self.progress {
+            progress(self.fetch_height - self.from_height, self.to_height - self.from_height);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for HeaderSync<'a> {
+    type Item = Result<ParsedHeader, Error>;
+
+    fn next(&mut self) -> Option<Result<ParsedHeader, Error>> {
+        if self.done {
+            return None;
+        }
+        if self.pending.is_empty() {
+            if self.fetch_height >= self.to_height {
+                self.done = true;
+                return None;
+            }
+            if let Err(e) = self.fetch_chunk() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        let header = self.pending.pop_front()?;
+        if let Some(prev_hash) = self.prev_hash {
+            if header.prev_hash != prev_hash {
+                self.done = true;
+                return Some(Err(Error::UnlinkedHeader(self.height)));
+            }
+        }
+        self.prev_hash = Some(header.hash());
+        self.height += 1;
+        Some(Ok(header))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use error::Error;
+    use hex;
+    use test_utils::{MockServer, reply};
+
+    use super::ParsedHeader;
+
+    /// The genesis block header
+    pub const GENESIS_HEX: &str = "0100000000000000000000000000000000000000000000000000000000000000\
+                                   000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa\
+                                   4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    /// Builds a chain of `len` linked headers on top of the genesis block
+    pub fn chain(len: usize) -> Vec<ParsedHeader> {
+        let mut headers = vec![ParsedHeader::from_hex(GENESIS_HEX).unwrap()];
+        while headers.len() < len {
+            let mut next = headers.last().unwrap().clone();
+            next.prev_hash = headers.last().unwrap().hash();
+            next.time += 600;
+            headers.push(next);
+        }
+        headers
+    }
+
+    /// A server answering `blockchain.block.headers` from `headers`,
+    /// returning at most `max` headers per request
+    pub fn header_server(headers: Vec<ParsedHeader>, max: usize) -> MockServer {
+        MockServer::new(move |req| {
+            assert_eq!(req.method, "blockchain.block.headers");
+            let start = req.params[0].as_u64().unwrap() as usize;
+            let count = req.params[1].as_u64().unwrap() as usize;
+            let end = (start + count.min(max)).min(headers.len());
+            let hex: String = headers[start..end].iter().map(|h| hex::encode(&h.to_bytes())).collect();
+            reply(req.id, json!({"count": end - start, "hex": hex, "max": max}))
+        })
+    }
+
+    #[test]
+    fn test_parse_genesis() {
+        let header = ParsedHeader::from_hex(GENESIS_HEX).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.prev_hash, [0; 32]);
+        assert_eq!(header.time, 1231006505);
+        assert_eq!(header.bits, 0x1d00ffff);
+        assert_eq!(header.nonce, 2083236893);
+        assert_eq!(hex::encode(&header.to_bytes()), GENESIS_HEX);
+
+        let mut hash = header.hash();
+        hash.reverse();
+        assert_eq!(hex::encode(&hash),
+                   "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    }
+
+    #[test]
+    fn test_hash_byte_orders() {
+        // Block 1
+        let header = ParsedHeader::from_hex("010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a08\
+                                             9c68d6190000000000982051fd1e4ba744bbbe680e1fee14677b\
+                                             a1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299")
+            .unwrap();
+
+        assert_eq!(hex::encode(&header.prev_hash_bytes()),
+                   "6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000");
+        assert_eq!(header.prev_hash_display(),
+                   "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        assert_eq!(hex::encode(&header.merkle_root_bytes()),
+                   "982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e");
+        assert_eq!(header.merkle_root_display(),
+                   "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
+        assert_eq!(header.hash_display(),
+                   "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048");
+
+        let genesis = ParsedHeader::from_hex(GENESIS_HEX).unwrap();
+        assert_eq!(header.prev_hash_bytes(), genesis.hash());
+        assert_eq!(header.prev_hash_display(), genesis.hash_display());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        match ParsedHeader::from_hex("0100") {
+            Err(Error::InvalidHeader(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match ParsedHeader::from_hex(&GENESIS_HEX.replace("0", "g")) {
+            Err(Error::InvalidHex) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(ParsedHeader::from_bytes(&[0; 81]).is_err());
+    }
+
+    #[test]
+    fn test_sync_headers_multi_chunk() {
+        let headers = chain(10);
+        let server = header_server(headers.clone(), 3);
+        let client = server.client();
+
+        let synced: Vec<ParsedHeader> = client.sync_headers(2, 10)
+            .collect::<Result<_, Error>>()
+            .unwrap();
+        assert_eq!(synced, headers[2..10].to_vec());
+        // 8 headers, 3 at a time
+        assert_eq!(server.hits(), 3);
+    }
+
+    #[test]
+    fn test_sync_headers_progress() {
+        let server = header_server(chain(10), 3);
+        let client = server.client();
+
+        let mut progress = vec![];
+        let synced = client.sync_headers(2, 10)
+            .with_progress(|fetched, total| progress.push((fetched, total)))
+            .count();
+        assert_eq!(synced, 8);
+        assert_eq!(progress, vec![(3, 8), (6, 8), (8, 8)]);
+    }
+
+    #[test]
+    fn test_sync_headers_is_lazy() {
+        let server = header_server(chain(10), 3);
+        let client = server.client();
+
+        let mut sync = client.sync_headers(0, 10);
+        assert_eq!(server.hits(), 0);
+        sync.next().unwrap().unwrap();
+        assert_eq!(server.hits(), 1);
+    }
+
+    #[test]
+    fn test_sync_headers_unlinked() {
+        let mut headers = chain(6);
+        headers[4].prev_hash = [1; 32];
+        let server = header_server(headers, 3);
+        let client = server.client();
+
+        let results: Vec<_> = client.sync_headers(0, 6).collect();
+        assert_eq!(results.len(), 5);
+        assert!(results[..4].iter().all(|r| r.is_ok()));
+        match results[4] {
+            Err(Error::UnlinkedHeader(4)) => {}
+            ref other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sync_headers_past_tip() {
+        let server = header_server(chain(4), 10);
+        let client = server.client();
+
+        let results: Vec<_> = client.sync_headers(2, 6).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+    }
+}
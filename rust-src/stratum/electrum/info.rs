@@ -0,0 +1,315 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Server information
+//!
+//! What a client knows about the server it is talking to
+//!
+
+use std::time::Duration;
+
+use hyper::Url;
+use serde_json::value::Value;
+use sha2::{Digest, Sha256};
+
+use error::Error;
+use hex;
+
+/// A rate limit advertised by a server in its banner
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitHint {
+    /// The most requests the server says it accepts
+    pub max_requests: u32,
+    /// The period the limit applies to, if the banner gave one
+    pub per: Option<Duration>,
+}
+
+/// Information about a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    /// The server's banner
+    pub banner: String,
+    /// A rate limit found in the banner, if any
+    pub rate_limit: Option<RateLimitHint>,
+    /// Roughly how many seconds the server's clock is ahead of the client's,
+    /// if known; see `Client::time_skew`
+    pub time_skew: Option<i64>,
+    /// The host the server was reached at, if known
+    pub host: Option<String>,
+    /// The hash of the server's genesis block, from the `genesis_hash`
+    /// feature, if known
+    pub genesis_hash: Option<String>,
+    /// The server's software version, from the `server_version` feature,
+    /// if known
+    pub server_version: Option<String>,
+}
+
+/// Limits a server advertises in `server.features`
+///
+/// None of these are part of the protocol, so each is `None` unless the
+/// server gave it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// The most requests the server accepts in one batch, from the
+    /// `max_batch_size` feature
+    pub max_batch_size: Option<usize>,
+    /// The longest history the server returns for a scripthash, from the
+    /// `max_history` feature
+    ///
+    /// This is informational: the server reports a longer history as an
+    /// error, and the client cannot know the length before asking.
+    pub max_history: Option<usize>,
+}
+
+impl ServerLimits {
+    /// Picks the known limits out of a `server.features` result, ignoring
+    /// anything which is not a non-negative integer
+    pub fn from_features(features: &Value) -> ServerLimits {
+        let limit = |key| {
+            features.get(key)
+                .and_then(Value::as_u64)
+                .map(|n| n as usize)
+        };
+        ServerLimits {
+            max_batch_size: limit("max_batch_size"),
+            max_history: limit("max_history"),
+        }
+    }
+
+    /// Checks that a batch of `size` requests is within the server's limit
+    pub fn check_batch(&self, size: usize) -> Result<(), Error> {
+        match self.max_batch_size {
+            Some(max) if size > max => {
+                Err(Error::ExceedsServerLimit(format!("batch of {} requests exceeds the \
+                                                       server's limit of {}",
+                                                      size,
+                                                      max)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerInfo {
+    /// Creates server information from a banner, picking out any rate limit
+    pub fn from_banner(banner: String) -> ServerInfo {
+        let rate_limit = parse_rate_limit(&banner);
+        ServerInfo {
+            banner,
+            rate_limit,
+            time_skew: None,
+            host: None,
+            genesis_hash: None,
+            server_version: None,
+        }
+    }
+
+    /// Fills in the host from the URL a client posts to, and the genesis
+    /// hash and software version from a `server.features` result
+    pub(crate) fn set_identity(&mut self, url: &str, features: &Value) {
+        self.host = Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_owned));
+        let feature = |key| features.get(key).and_then(Value::as_str).map(str::to_owned);
+        self.genesis_hash = feature("genesis_hash");
+        self.server_version = feature("server_version");
+    }
+
+    /// A stable identifier for the server, for keying cached capabilities
+    /// such as the negotiated protocol and limits
+    ///
+    /// This combines the genesis hash, software version and host, so it is
+    /// the same on every connection to one server, while its banner and
+    /// clock skew vary, and changes when the server is upgraded. Parts which
+    /// are not known are treated as empty.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        for part in &[&self.genesis_hash, &self.server_version, &self.host] {
+            let part = part.as_ref().map_or("", String::as_str);
+            // Length-prefixed, so that parts cannot run into each other
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hex::encode(&hasher.finalize()[..])
+    }
+}
+
+/// Looks for a rate limit in a banner
+///
+/// Banners are freeform, so this is best-effort: it recognizes phrases like
+/// "max requests: 100", "maximum 100 requests per minute" and
+/// "50 requests/sec", and returns `None` for anything else.
+pub fn parse_rate_limit(banner: &str) -> Option<RateLimitHint> {
+    let banner = banner.to_lowercase();
+    let words: Vec<&str> = banner.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    for (i, word) in words.iter().enumerate() {
+        // "<n> requests [per] <unit>"
+        if let Ok(max_requests) = word.parse() {
+            if is_requests(words.get(i + 1)) {
+                return Some(RateLimitHint {
+                    max_requests,
+                    per: period(&words[i + 2..]),
+                });
+            }
+        }
+        // "max[imum] requests <n> [per <unit>]"
+        if (*word == "max" || *word == "maximum") && is_requests(words.get(i + 1)) {
+            if let Some(max_requests) = words.get(i + 2).and_then(|w| w.parse().ok()) {
+                return Some(RateLimitHint {
+                    max_requests,
+                    per: period(&words[i + 3..]),
+                });
+            }
+        }
+    }
+    None
+}
+
+fn is_requests(word: Option<&&str>) -> bool {
+    matches!(word, Some(&"requests") | Some(&"reqs"))
+}
+
+fn period(words: &[&str]) -> Option<Duration> {
+    let words = match words.first() {
+        Some(&"per") | Some(&"a") | Some(&"every") => &words[1..],
+        _ => words,
+    };
+    match words.first() {
+        Some(&"s") | Some(&"sec") | Some(&"second") | Some(&"seconds") => {
+            Some(Duration::from_secs(1))
+        }
+        Some(&"m") | Some(&"min") | Some(&"minute") | Some(&"minutes") => {
+            Some(Duration::from_secs(60))
+        }
+        Some(&"h") | Some(&"hr") | Some(&"hour") | Some(&"hours") => {
+            Some(Duration::from_secs(3600))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use clock::MockClock;
+    use electrum::header::tests::chain;
+    use error::Error;
+    use hex;
+    use test_utils::{MockServer, reply};
+    use super::{RateLimitHint, ServerInfo, ServerLimits, parse_rate_limit};
+
+    const GENESIS_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    #[test]
+    fn test_parse_rate_limit() {
+        assert_eq!(parse_rate_limit("Welcome to ElectrumX!\nMax requests: 100"),
+                   Some(RateLimitHint {
+                       max_requests: 100,
+                       per: None,
+                   }));
+        assert_eq!(parse_rate_limit("Please stay under 50 requests/sec."),
+                   Some(RateLimitHint {
+                       max_requests: 50,
+                       per: Some(Duration::from_secs(1)),
+                   }));
+        assert_eq!(parse_rate_limit("Maximum requests 600 per minute, or you will be banned"),
+                   Some(RateLimitHint {
+                       max_requests: 600,
+                       per: Some(Duration::from_secs(60)),
+                   }));
+    }
+
+    #[test]
+    fn test_parse_rate_limit_unrecognized() {
+        assert_eq!(parse_rate_limit(""), None);
+        assert_eq!(parse_rate_limit("Welcome to ElectrumX 1.8.5"), None);
+        assert_eq!(parse_rate_limit("Requests are limited, be nice"), None);
+        assert_eq!(parse_rate_limit("max requests: lots"), None);
+    }
+
+    #[test]
+    fn test_server_info() {
+        let tip = hex::encode(&chain(1)[0].to_bytes());
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "server.banner" => reply(req.id, json!("Hello! Limit: 10 requests per second")),
+            "server.features" => {
+                reply(req.id,
+                      json!({"genesis_hash": GENESIS_HASH, "server_version": "ElectrumX 1.16.0"}))
+            }
+            "blockchain.headers.subscribe" => reply(req.id, json!({"height": 0, "hex": tip})),
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1231007105));
+
+        let info = server.client().with_clock(Arc::new(clock)).server_info().unwrap();
+        assert_eq!(info.banner, "Hello! Limit: 10 requests per second");
+        assert_eq!(info.rate_limit.unwrap().max_requests, 10);
+        assert_eq!(info.time_skew, Some(-600));
+        assert_eq!(info.host.as_deref(), Some("127.0.0.1"));
+        assert_eq!(info.genesis_hash.as_deref(), Some(GENESIS_HASH));
+        assert_eq!(info.server_version.as_deref(), Some("ElectrumX 1.16.0"));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let info = |banner: &str, host: &str, version: &str| {
+            let mut info = ServerInfo::from_banner(banner.to_owned());
+            info.set_identity(&format!("http://{}:50001", host),
+                              &json!({"genesis_hash": GENESIS_HASH, "server_version": version}));
+            info
+        };
+        let first = info("Welcome!", "electrum.example.com", "ElectrumX 1.16.0");
+        let mut second = info("Welcome back!", "electrum.example.com", "ElectrumX 1.16.0");
+        second.time_skew = Some(3);
+        assert_eq!(first.fingerprint(), second.fingerprint());
+
+        for other in &[info("Welcome!", "other.example.com", "ElectrumX 1.16.0"),
+                       info("Welcome!", "electrum.example.com", "ElectrumX 1.17.0"),
+                       ServerInfo::from_banner("Welcome!".to_owned())] {
+            assert_ne!(first.fingerprint(), other.fingerprint());
+        }
+    }
+
+    #[test]
+    fn test_server_limits_from_features() {
+        let limits = ServerLimits::from_features(&json!({
+            "server_version": "ElectrumX 1.16.0",
+            "max_batch_size": 10,
+            "max_history": "lots",
+        }));
+        assert_eq!(limits,
+                   ServerLimits {
+                       max_batch_size: Some(10),
+                       max_history: None,
+                   });
+        assert_eq!(ServerLimits::from_features(&json!({})), ServerLimits::default());
+    }
+
+    #[test]
+    fn test_server_limits_check_batch() {
+        let limits = ServerLimits {
+            max_batch_size: Some(2),
+            max_history: None,
+        };
+        assert!(limits.check_batch(2).is_ok());
+        match limits.check_batch(3) {
+            Err(Error::ExceedsServerLimit(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(ServerLimits::default().check_batch(1000).is_ok());
+    }
+}
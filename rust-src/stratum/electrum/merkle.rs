@@ -0,0 +1,189 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Merkle proofs
+//!
+//! Checking that a transaction is in a block, from the merkle branch the
+//! server gives for it and the block's header
+//!
+
+use sha2::{Digest, Sha256};
+
+use error::Error;
+use hex;
+
+use super::header::ParsedHeader;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// The merkle branch of a transaction, as returned by
+/// `blockchain.transaction.get_merkle`
+pub struct MerkleProof {
+    /// The position of the transaction in its block
+    pub pos: u32,
+    /// The hashes of the branch, from the transaction's sibling upwards, in
+    /// the usual display byte order
+    pub merkle: Vec<String>,
+}
+
+impl MerkleProof {
+    /// Works out the merkle root the branch leads to from `txid`, in
+    /// internal byte order
+    pub fn root(&self, txid: &str) -> Result<[u8; 32], Error> {
+        let mut hash = internal_hash(txid)?;
+        let mut pos = self.pos;
+        for sibling in &self.merkle {
+            let sibling = internal_hash(sibling)?;
+            hash = if pos & 1 == 0 {
+                hash_pair(&hash, &sibling)
+            } else {
+                hash_pair(&sibling, &hash)
+            };
+            pos >>= 1;
+        }
+        Ok(hash)
+    }
+
+    /// Checks that the branch leads from `txid` to the merkle root of
+    /// `header`, giving `Error::InvalidMerkleProof` if it does not
+    pub fn verify(&self, txid: &str, header: &ParsedHeader) -> Result<(), Error> {
+        if self.root(txid)? != header.merkle_root_bytes() {
+            return Err(Error::InvalidMerkleProof(txid.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+/// Verifies each of a set of merkle proofs, given with the txid it proves
+/// and the header of its block, returning a result per entry
+///
+/// With the `parallel` feature the proofs are verified across threads.
+pub fn verify_proofs(entries: &[(&str, MerkleProof, ParsedHeader)]) -> Vec<Result<(), Error>> {
+    verify_each(entries)
+}
+
+#[cfg(feature = "parallel")]
+fn verify_each(entries: &[(&str, MerkleProof, ParsedHeader)]) -> Vec<Result<(), Error>> {
+    use rayon::prelude::*;
+    entries.par_iter().map(|&(txid, ref proof, ref header)| proof.verify(txid, header)).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn verify_each(entries: &[(&str, MerkleProof, ParsedHeader)]) -> Vec<Result<(), Error>> {
+    entries.iter().map(|&(txid, ref proof, ref header)| proof.verify(txid, header)).collect()
+}
+
+/// The double-SHA256 hash of `bytes`, in internal byte order
+pub(crate) fn sha256d(bytes: &[u8]) -> [u8; 32] {
+    let first = Sha256::digest(bytes);
+    let second = Sha256::digest(&first[..]);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&second[..]);
+    hash
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut concat = [0; 64];
+    concat[..32].copy_from_slice(left);
+    concat[32..].copy_from_slice(right);
+    sha256d(&concat)
+}
+
+/// Decodes a displayed hash into internal byte order
+fn internal_hash(display: &str) -> Result<[u8; 32], Error> {
+    let bytes = hex::decode(display)?;
+    if bytes.len() != 32 {
+        return Err(Error::InvalidMerkleProof(display.to_owned()));
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&bytes);
+    hash.reverse();
+    Ok(hash)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use error::Error;
+    use electrum::header::ParsedHeader;
+    use super::{MerkleProof, verify_proofs};
+
+    /// The header of block 100000, which holds four transactions
+    pub const BLOCK_100000_HEX: &str = "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1c\
+                                        d00200000000006657a9252aacd5c0b2940996ecff952228c3067cc38d\
+                                        4885efb5a4ac4247e9f337221b4d4c86041b0f2b5710";
+
+    /// The txids of block 100000, in block order
+    pub const BLOCK_100000_TXIDS: [&str; 4] =
+        ["8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+         "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+         "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+         "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d"];
+
+    /// The hash of the last two transactions of block 100000
+    const RIGHT_BRANCH: &str = "8e30899078ca1813be036a073bbf80b86cdddde1c96e9e9c99e9e3782df4ae49";
+
+    /// The proof of the coinbase of block 100000
+    pub fn coinbase_proof() -> MerkleProof {
+        MerkleProof {
+            pos: 0,
+            merkle: vec![BLOCK_100000_TXIDS[1].to_owned(), RIGHT_BRANCH.to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let header = ParsedHeader::from_hex(BLOCK_100000_HEX).unwrap();
+        coinbase_proof().verify(BLOCK_100000_TXIDS[0], &header).unwrap();
+
+        // The second transaction is the coinbase's sibling, on the right
+        let proof = MerkleProof {
+            pos: 1,
+            merkle: vec![BLOCK_100000_TXIDS[0].to_owned(), RIGHT_BRANCH.to_owned()],
+        };
+        proof.verify(BLOCK_100000_TXIDS[1], &header).unwrap();
+    }
+
+    #[test]
+    fn test_verify_proofs() {
+        let header = ParsedHeader::from_hex(BLOCK_100000_HEX).unwrap();
+        let mut tampered = coinbase_proof();
+        tampered.merkle[1] = "00".repeat(32);
+        let entries = [(BLOCK_100000_TXIDS[0], coinbase_proof(), header.clone()),
+                       (BLOCK_100000_TXIDS[0], tampered, header.clone()),
+                       (BLOCK_100000_TXIDS[3], coinbase_proof(), header)];
+
+        let results = verify_proofs(&entries);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok());
+        for result in &results[1..] {
+            match *result {
+                Err(Error::InvalidMerkleProof(_)) => {}
+                ref other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects() {
+        let header = ParsedHeader::from_hex(BLOCK_100000_HEX).unwrap();
+        let wrong_pos = MerkleProof { pos: 1, ..coinbase_proof() };
+        let wrong_txid = BLOCK_100000_TXIDS[2];
+        for &(ref proof, txid) in &[(wrong_pos, BLOCK_100000_TXIDS[0]),
+                                    (coinbase_proof(), wrong_txid)] {
+            match proof.verify(txid, &header) {
+                Err(Error::InvalidMerkleProof(ref t)) => assert_eq!(t, txid),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
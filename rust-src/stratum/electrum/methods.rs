@@ -0,0 +1,139 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Typed methods
+//!
+//! One function per Electrum method, each building the request, sending it
+//! with a `Client` and decoding the result into its type, for callers who
+//! would rather not handle `Request`s and `Value`s themselves
+//!
+//! The functions are named after the methods they call, with dots replaced
+//! by underscores. Scripthashes are normalized as by the request builders.
+//!
+
+use serde_json::value::Value;
+
+use client::Client;
+use error::Error;
+
+use super::requests;
+use super::types::{Balance, HistoryEntry, Utxo};
+
+/// Calls `blockchain.transaction.get`, returning the raw transaction hex
+/// as a string, or the decoded transaction as an object if `verbose`
+pub fn blockchain_transaction_get(client: &Client,
+                                  txid: &str,
+                                  verbose: bool)
+                                  -> Result<Value, Error> {
+    let request = requests::transaction_get(txid, verbose, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.transaction.broadcast`, returning the txid
+pub fn blockchain_transaction_broadcast(client: &Client, raw_tx: &str) -> Result<String, Error> {
+    let request = requests::transaction_broadcast(raw_tx, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.scripthash.get_balance`
+pub fn blockchain_scripthash_get_balance(client: &Client,
+                                         scripthash: &str)
+                                         -> Result<Balance, Error> {
+    let scripthash = requests::normalize_scripthash(scripthash)?;
+    let request = requests::scripthash_get_balance(&scripthash, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.scripthash.get_history`
+pub fn blockchain_scripthash_get_history(client: &Client,
+                                         scripthash: &str)
+                                         -> Result<Vec<HistoryEntry>, Error> {
+    let scripthash = requests::normalize_scripthash(scripthash)?;
+    let request = requests::scripthash_get_history(&scripthash, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.scripthash.listunspent`
+pub fn blockchain_scripthash_listunspent(client: &Client,
+                                         scripthash: &str)
+                                         -> Result<Vec<Utxo>, Error> {
+    let scripthash = requests::normalize_scripthash(scripthash)?;
+    let request = requests::scripthash_listunspent(&scripthash, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.estimatefee`, returning the fee rate in coins per
+/// kilobyte needed to confirm within `blocks`, or -1 if the server's node
+/// cannot estimate it
+pub fn blockchain_estimatefee(client: &Client, blocks: u16) -> Result<f64, Error> {
+    let request = requests::estimatefee(blocks, client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+/// Calls `blockchain.relayfee`, returning the lowest fee rate in coins per
+/// kilobyte the server's node relays
+pub fn blockchain_relayfee(client: &Client) -> Result<f64, Error> {
+    let request = requests::relayfee(client.next_id());
+    client.send_request(&request)?.into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use test_utils::{MockServer, reply};
+    use super::{blockchain_estimatefee, blockchain_scripthash_get_balance,
+                blockchain_transaction_get};
+    use super::super::types::Balance;
+
+    const SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+
+    #[test]
+    fn test_typed_methods() {
+        let server = MockServer::new(|req| match req.method.as_str() {
+            "blockchain.transaction.get" => {
+                assert_eq!(req.params, vec![json!("ab"), json!(true)]);
+                reply(req.id, json!({"txid": "ab", "vsize": 110}))
+            }
+            "blockchain.scripthash.get_balance" => {
+                assert_eq!(req.params, vec![json!(SCRIPTHASH)]);
+                reply(req.id, json!({"confirmed": 1000, "unconfirmed": -200}))
+            }
+            "blockchain.estimatefee" => {
+                assert_eq!(req.params, vec![json!(6)]);
+                reply(req.id, json!(0.0002))
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client();
+
+        let tx = blockchain_transaction_get(&client, "ab", true).unwrap();
+        assert_eq!(tx["vsize"], json!(110));
+        let uppercase = SCRIPTHASH.to_uppercase();
+        assert_eq!(blockchain_scripthash_get_balance(&client, &uppercase).unwrap(),
+                   Balance {
+                       confirmed: 1000,
+                       unconfirmed: -200,
+                   });
+        assert_eq!(blockchain_estimatefee(&client, 6).unwrap(), 0.0002);
+    }
+
+    #[test]
+    fn test_typed_method_bad_result() {
+        let server = MockServer::new(|req| reply(req.id, json!("not a balance")));
+        match blockchain_scripthash_get_balance(&server.client(), SCRIPTHASH) {
+            Err(Error::Json(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Electrum protocol
+//!
+//! Support for the Electrum flavour of Stratum, as spoken by Electrum
+//! servers such as ElectrumX and electrs
+//!
+
+mod batch;
+mod client;
+mod deprecation;
+mod fees;
+mod header;
+mod info;
+mod merkle;
+pub mod methods;
+pub mod requests;
+mod selection;
+mod transaction;
+mod types;
+mod version;
+mod watch;
+
+pub use self::batch::{BatchBuilder, BatchResult};
+pub use self::deprecation::{deprecated_methods, deprecation_warning};
+pub use self::fees::{MAX_BLOCK_VSIZE, blocks_to_confirm};
+pub use self::header::{HEADER_CHUNK_SIZE, HEADER_SIZE, HeaderSync, ParsedHeader};
+pub use self::info::{RateLimitHint, ServerInfo, ServerLimits, parse_rate_limit};
+pub use self::merkle::{MerkleProof, verify_proofs};
+pub use self::selection::{INPUT_VSIZE, TX_BASE_VSIZE, estimate_fee, select_coins};
+pub use self::transaction::{OutPoint, parse_inputs, txid};
+pub use self::types::{Balance, HistoryEntry, HistoryWithTx, ScripthashSnapshot, Utxo,
+                      WalletSnapshot};
+pub use self::version::{ProtocolDowngrade, ProtocolVersion};
+pub use self::watch::{BalanceStream, ServerEvent, ServerWatcher, TipEvent, TipTracker,
+                      status_changed};
@@ -0,0 +1,237 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Request builders
+//!
+//! Functions that shape Electrum requests without sending them, so that
+//! requests can be dispatched over any transport
+//!
+//! Servers expect scripthashes in lowercase hex, and some silently return
+//! empty results for uppercase ones, so the scripthash builders lowercase
+//! their input.
+//!
+
+use serde_json::value::Value;
+
+use {Params, Request};
+use error::Error;
+use hex;
+
+/// Checks that `scripthash` is 32 bytes of hex, returning it in lowercase
+pub fn normalize_scripthash(scripthash: &str) -> Result<String, Error> {
+    match hex::decode(scripthash) {
+        Ok(ref bytes) if bytes.len() == 32 => Ok(hex::encode(bytes)),
+        _ => Err(Error::InvalidScripthash(scripthash.to_owned())),
+    }
+}
+
+fn request(method: &str, params: Vec<Value>, id: Value) -> Request {
+    Request {
+        method: method.to_owned(),
+        params: Params::Positional(params),
+        id,
+        jsonrpc: None,
+    }
+}
+
+/// Builds a `server.version` request
+pub fn server_version(client_name: &str, protocol_version: &str, id: Value) -> Request {
+    request("server.version",
+            vec![From::from(client_name), From::from(protocol_version)],
+            id)
+}
+
+/// Builds a `server.banner` request
+pub fn server_banner(id: Value) -> Request {
+    request("server.banner", vec![], id)
+}
+
+/// Builds a `server.features` request
+pub fn server_features(id: Value) -> Request {
+    request("server.features", vec![], id)
+}
+
+/// Builds a `server.ping` request
+pub fn server_ping(id: Value) -> Request {
+    request("server.ping", vec![], id)
+}
+
+/// Builds a `blockchain.headers.subscribe` request
+pub fn headers_subscribe(id: Value) -> Request {
+    request("blockchain.headers.subscribe", vec![], id)
+}
+
+/// Builds a `blockchain.block.header` request
+pub fn block_header(height: u32, id: Value) -> Request {
+    request("blockchain.block.header", vec![From::from(height)], id)
+}
+
+/// Builds a `blockchain.block.headers` request
+pub fn block_headers(start_height: u32, count: u32, id: Value) -> Request {
+    request("blockchain.block.headers",
+            vec![From::from(start_height), From::from(count)],
+            id)
+}
+
+/// Builds a `blockchain.estimatefee` request
+pub fn estimatefee(blocks: u16, id: Value) -> Request {
+    request("blockchain.estimatefee", vec![From::from(blocks)], id)
+}
+
+/// Builds a `blockchain.relayfee` request
+pub fn relayfee(id: Value) -> Request {
+    request("blockchain.relayfee", vec![], id)
+}
+
+/// Builds a `blockchain.scripthash.get_balance` request
+pub fn scripthash_get_balance(scripthash: &str, id: Value) -> Request {
+    request("blockchain.scripthash.get_balance",
+            vec![From::from(scripthash.to_ascii_lowercase())],
+            id)
+}
+
+/// Builds a `blockchain.scripthash.get_history` request
+pub fn scripthash_get_history(scripthash: &str, id: Value) -> Request {
+    request("blockchain.scripthash.get_history",
+            vec![From::from(scripthash.to_ascii_lowercase())],
+            id)
+}
+
+/// Builds a `blockchain.scripthash.get_mempool` request
+pub fn scripthash_get_mempool(scripthash: &str, id: Value) -> Request {
+    request("blockchain.scripthash.get_mempool",
+            vec![From::from(scripthash.to_ascii_lowercase())],
+            id)
+}
+
+/// Builds a `blockchain.scripthash.listunspent` request
+pub fn scripthash_listunspent(scripthash: &str, id: Value) -> Request {
+    request("blockchain.scripthash.listunspent",
+            vec![From::from(scripthash.to_ascii_lowercase())],
+            id)
+}
+
+/// Builds a `blockchain.scripthash.subscribe` request
+pub fn scripthash_subscribe(scripthash: &str, id: Value) -> Request {
+    request("blockchain.scripthash.subscribe",
+            vec![From::from(scripthash.to_ascii_lowercase())],
+            id)
+}
+
+/// Builds a `blockchain.transaction.broadcast` request
+pub fn transaction_broadcast(raw_tx: &str, id: Value) -> Request {
+    request("blockchain.transaction.broadcast",
+            vec![From::from(raw_tx)],
+            id)
+}
+
+/// Builds a `blockchain.transaction.get` request
+pub fn transaction_get(txid: &str, verbose: bool, id: Value) -> Request {
+    request("blockchain.transaction.get",
+            vec![From::from(txid), From::from(verbose)],
+            id)
+}
+
+/// Builds a `blockchain.transaction.get_merkle` request
+pub fn transaction_get_merkle(txid: &str, height: u32, id: Value) -> Request {
+    request("blockchain.transaction.get_merkle",
+            vec![From::from(txid), From::from(height)],
+            id)
+}
+
+/// Builds a `blockchain.transaction.id_from_pos` request
+pub fn transaction_id_from_pos(height: u32, tx_pos: u32, merkle: bool, id: Value) -> Request {
+    request("blockchain.transaction.id_from_pos",
+            vec![From::from(height), From::from(tx_pos), From::from(merkle)],
+            id)
+}
+
+/// Builds a `mempool.get_fee_histogram` request
+pub fn mempool_get_fee_histogram(id: Value) -> Request {
+    request("mempool.get_fee_histogram", vec![], id)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use error::Error;
+    use super::{server_version, headers_subscribe, normalize_scripthash, scripthash_subscribe,
+                transaction_get, transaction_id_from_pos};
+
+    #[test]
+    fn test_server_version() {
+        let req = server_version("relectrum", "1.4", Value::Number(From::from(1)));
+        assert_eq!(req.method, "server.version");
+        assert_eq!(req.params,
+                   vec![Value::String("relectrum".to_owned()), Value::String("1.4".to_owned())]);
+        assert_eq!(req.id, Value::Number(From::from(1)));
+    }
+
+    #[test]
+    fn test_headers_subscribe() {
+        let req = headers_subscribe(Value::Number(From::from(2)));
+        assert_eq!(req.method, "blockchain.headers.subscribe");
+        assert!(req.params.is_empty());
+        assert_eq!(req.id, Value::Number(From::from(2)));
+    }
+
+    #[test]
+    fn test_scripthash_subscribe() {
+        let scripthash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        let req = scripthash_subscribe(scripthash, Value::String("abc".to_owned()));
+        assert_eq!(req.method, "blockchain.scripthash.subscribe");
+        assert_eq!(req.params, vec![Value::String(scripthash.to_owned())]);
+        assert_eq!(req.id, Value::String("abc".to_owned()));
+    }
+
+    #[test]
+    fn test_scripthash_lowercased() {
+        let scripthash = "8B01DF4E368EA28F8DC0423BCF7A4923E3A12D307C875E47A0CFBF90B5C39161";
+        let req = scripthash_subscribe(scripthash, Value::Number(From::from(1)));
+        assert_eq!(req.params, vec![Value::String(scripthash.to_lowercase())]);
+    }
+
+    #[test]
+    fn test_normalize_scripthash() {
+        let scripthash = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        assert_eq!(normalize_scripthash(scripthash).unwrap(), scripthash);
+        assert_eq!(normalize_scripthash(&scripthash.to_uppercase()).unwrap(), scripthash);
+
+        for bad in &["", "8b01", &scripthash[1..], &scripthash.replace("8", "g")] {
+            match normalize_scripthash(bad) {
+                Err(Error::InvalidScripthash(_)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_transaction_get() {
+        let txid = "f3e1bf48975b8d6060a9de8884296abb80be618dc00ae3cb2f6cee3085e09403";
+        let req = transaction_get(txid, true, Value::Number(From::from(3)));
+        assert_eq!(req.method, "blockchain.transaction.get");
+        assert_eq!(req.params, vec![Value::String(txid.to_owned()), Value::Bool(true)]);
+    }
+
+    #[test]
+    fn test_transaction_id_from_pos() {
+        let req = transaction_id_from_pos(100, 0, false, Value::Number(From::from(4)));
+        assert_eq!(req.method, "blockchain.transaction.id_from_pos");
+        assert_eq!(req.params,
+                   vec![Value::Number(From::from(100)),
+                        Value::Number(From::from(0)),
+                        Value::Bool(false)]);
+    }
+}
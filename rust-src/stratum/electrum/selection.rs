@@ -0,0 +1,145 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Coin selection
+//!
+//! Choosing which unspent outputs to spend to pay a given amount
+//!
+
+use std::cmp::Reverse;
+
+use error::Error;
+
+use super::types::Utxo;
+
+/// The estimated size in vbytes of a transaction without its inputs,
+/// assuming two P2WPKH outputs
+pub const TX_BASE_VSIZE: u64 = 73;
+
+/// The estimated size in vbytes of each P2WPKH input
+pub const INPUT_VSIZE: u64 = 68;
+
+/// The fee for a transaction spending `inputs` inputs at `fee_rate`
+/// satoshis per vbyte
+pub fn estimate_fee(inputs: usize, fee_rate: f64) -> u64 {
+    let vsize = TX_BASE_VSIZE + INPUT_VSIZE * inputs as u64;
+    (vsize as f64 * fee_rate).ceil() as u64
+}
+
+/// Selects outputs to pay `target_amount` satoshis plus the fee at
+/// `fee_rate` satoshis per vbyte, largest first
+///
+/// The fee grows with each selected input, as estimated by `estimate_fee`.
+/// Gives `Error::InsufficientFunds` if all the outputs together do not
+/// cover the amount and fee, including when they add up to more than a
+/// `u64` holds.
+pub fn select_coins(utxos: &[Utxo], target_amount: u64, fee_rate: f64) -> Result<Vec<Utxo>, Error> {
+    let mut candidates = utxos.to_vec();
+    candidates.sort_by_key(|u| Reverse(u.value));
+
+    let needed = |inputs: usize| {
+        target_amount.checked_add(estimate_fee(inputs, fee_rate)).ok_or(Error::InsufficientFunds)
+    };
+    let mut selected = vec![];
+    let mut total: u64 = 0;
+    for utxo in candidates {
+        if total >= needed(selected.len())? {
+            break;
+        }
+        // No amount a u64 holds is more than a sum which overflows it
+        total = total.saturating_add(utxo.value);
+        selected.push(utxo);
+    }
+
+    if total >= needed(selected.len())? {
+        Ok(selected)
+    } else {
+        Err(Error::InsufficientFunds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use super::super::types::Utxo;
+    use super::{estimate_fee, select_coins};
+
+    fn utxo(tx_pos: u32, value: u64) -> Utxo {
+        Utxo {
+            tx_hash: "f3e1bf48975b8d6060a9de8884296abb80be618dc00ae3cb2f6cee3085e09403".to_owned(),
+            tx_pos,
+            height: 500_000,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_estimate_fee() {
+        assert_eq!(estimate_fee(0, 1.0), 73);
+        assert_eq!(estimate_fee(2, 1.0), 209);
+        assert_eq!(estimate_fee(1, 1.5), 212);
+    }
+
+    #[test]
+    fn test_select_exact_match() {
+        let fee = estimate_fee(2, 2.0);
+        let utxos = vec![utxo(0, 1_000), utxo(1, 60_000), utxo(2, 40_000 - 1_000 + fee)];
+        let selected = select_coins(&utxos, 99_000, 2.0).unwrap();
+        assert_eq!(selected.len(), 2);
+        let total: u64 = selected.iter().map(|u| u.value).sum();
+        assert_eq!(total, 99_000 + fee);
+    }
+
+    #[test]
+    fn test_select_overshoot() {
+        let utxos = vec![utxo(0, 10_000), utxo(1, 500_000), utxo(2, 20_000)];
+        let selected = select_coins(&utxos, 100_000, 1.0).unwrap();
+        assert_eq!(selected, vec![utxo(1, 500_000)]);
+    }
+
+    #[test]
+    fn test_select_largest_first() {
+        let utxos = vec![utxo(0, 30_000), utxo(1, 50_000), utxo(2, 40_000)];
+        let selected = select_coins(&utxos, 80_000, 1.0).unwrap();
+        let positions: Vec<u32> = selected.iter().map(|u| u.tx_pos).collect();
+        assert_eq!(positions, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_select_insufficient_funds() {
+        let utxos = vec![utxo(0, 30_000), utxo(1, 50_000)];
+        match select_coins(&utxos, 80_000, 1.0) {
+            Err(Error::InsufficientFunds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match select_coins(&[], 1, 1.0) {
+            Err(Error::InsufficientFunds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_select_huge_amounts() {
+        // The target plus any fee is more than a u64 holds
+        let utxos = vec![utxo(0, u64::MAX), utxo(1, 50_000)];
+        match select_coins(&utxos, u64::MAX, 1.0) {
+            Err(Error::InsufficientFunds) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The outputs together are more than a u64 holds
+        let utxos = vec![utxo(0, u64::MAX / 2 + 10), utxo(1, u64::MAX / 2 + 10)];
+        let selected = select_coins(&utxos, u64::MAX - 300, 1.0).unwrap();
+        assert_eq!(selected.len(), 2);
+    }
+}
@@ -0,0 +1,244 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+//! # Transactions
+//!
+//! Just enough decoding of raw transactions to see what they spend
+//!
+
+use error::Error;
+use hex;
+
+use super::merkle::sha256d;
+
+/// A reference to a transaction output
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    /// The txid of the transaction containing the output, in the usual
+    /// display byte order
+    pub txid: String,
+    /// The index of the output in its transaction
+    pub vout: u32,
+}
+
+/// Decodes the outputs spent by the inputs of a serialized transaction,
+/// with or without witness data
+pub fn parse_inputs(raw: &[u8]) -> Result<Vec<OutPoint>, Error> {
+    let mut reader = Reader { raw, pos: 0 };
+    reader.take(4)?;  // version
+    let mut count = reader.varint()?;
+    if count == 0 {
+        // The segwit marker, which must be followed by a nonzero flag
+        if reader.take(1)?[0] == 0 {
+            return Err(invalid("no inputs"));
+        }
+        count = reader.varint()?;
+    }
+
+    let mut inputs = vec![];
+    for _ in 0..count {
+        let mut txid = [0; 32];
+        txid.copy_from_slice(reader.take(32)?);
+        txid.reverse();
+        let vout = reader.take(4)?;
+        let script_len = reader.varint()?;
+        reader.take(script_len)?;
+        reader.take(4)?;  // sequence
+        inputs.push(OutPoint {
+            txid: hex::encode(&txid),
+            vout: u32::from_le_bytes([vout[0], vout[1], vout[2], vout[3]]),
+        });
+    }
+    Ok(inputs)
+}
+
+/// Works out the txid of a serialized transaction, with or without
+/// witness data, in the usual display byte order
+///
+/// Witness data is not committed to by the txid, so it is left out of the
+/// hash.
+pub fn txid(raw: &[u8]) -> Result<String, Error> {
+    let mut reader = Reader { raw, pos: 0 };
+    reader.take(4)?;  // version
+    let mut body_start = reader.pos;
+    let mut count = reader.varint()?;
+    let segwit = count == 0;
+    if segwit {
+        if reader.take(1)?[0] == 0 {
+            return Err(invalid("no inputs"));
+        }
+        body_start = reader.pos;
+        count = reader.varint()?;
+    }
+    for _ in 0..count {
+        reader.take(36)?;  // outpoint
+        let script_len = reader.varint()?;
+        reader.take(script_len)?;
+        reader.take(4)?;  // sequence
+    }
+    for _ in 0..reader.varint()? {
+        reader.take(8)?;  // value
+        let script_len = reader.varint()?;
+        reader.take(script_len)?;
+    }
+    let body_end = reader.pos;
+    if raw.len() < body_end + 4 {
+        return Err(invalid("unexpected end of data"));
+    }
+
+    let mut hash = if segwit {
+        let mut stripped = raw[..4].to_vec();
+        stripped.extend_from_slice(&raw[body_start..body_end]);
+        stripped.extend_from_slice(&raw[raw.len() - 4..]);
+        sha256d(&stripped)
+    } else {
+        sha256d(raw)
+    };
+    hash.reverse();
+    Ok(hex::encode(&hash))
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidTransaction(reason.to_owned())
+}
+
+struct Reader<'a> {
+    raw: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8], Error> {
+        let remaining = self.raw.len() - self.pos;
+        if len > remaining as u64 {
+            return Err(invalid("unexpected end of data"));
+        }
+        let bytes = &self.raw[self.pos..self.pos + len as usize];
+        self.pos += len as usize;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let len = match self.take(1)?[0] {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            n => return Ok(u64::from(n)),
+        };
+        let bytes = self.take(len)?;
+        Ok(bytes.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b)))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use error::Error;
+    use hex;
+    use super::{OutPoint, parse_inputs, txid};
+
+    pub const PREV_TXID: &str = "9f2c45a12db0144909b5db269415f7319179105982ac70ed80d76ea79d923ebf";
+
+    /// The coinbase transaction of block 100000
+    pub const BLOCK_100000_COINBASE_HEX: &str = "01000000010000000000000000000000000000000000\
+                                                 000000000000000000000000000000ffffffff08044c\
+                                                 86041b020602ffffffff0100f2052a01000000434104\
+                                                 1b0e8c2567c12536aa13357b79a073dc4444acb83c4e\
+                                                 c7a0e2f99dd7457516c5817242da796924ca4e99947d\
+                                                 087fedf9ce467cb9f7c6287078f801df276fdf84ac00\
+                                                 000000";
+
+    /// A serialized transaction spending `inputs`, paying one empty output
+    pub fn spending(inputs: &[(&str, u32)], segwit: bool) -> Vec<u8> {
+        let mut raw = vec![2, 0, 0, 0];
+        if segwit {
+            raw.extend_from_slice(&[0, 1]);
+        }
+        raw.push(inputs.len() as u8);
+        for &(txid, vout) in inputs {
+            let mut txid = hex::decode(txid).unwrap();
+            txid.reverse();
+            raw.extend_from_slice(&txid);
+            raw.extend_from_slice(&vout.to_le_bytes());
+            // A three-byte script, then the sequence
+            raw.extend_from_slice(&[3, 0xaa, 0xbb, 0xcc, 0xff, 0xff, 0xff, 0xff]);
+        }
+        raw.extend_from_slice(&[1, 0x10, 0x27, 0, 0, 0, 0, 0, 0, 0]);
+        if segwit {
+            // An empty witness for each input
+            raw.extend(vec![0; inputs.len()]);
+        }
+        raw.extend_from_slice(&[0, 0, 0, 0]);
+        raw
+    }
+
+    #[test]
+    fn test_txid() {
+        let raw = hex::decode(BLOCK_100000_COINBASE_HEX).unwrap();
+        assert_eq!(txid(&raw).unwrap(),
+                   "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87");
+
+        // Witness data does not change the txid
+        let inputs = [(PREV_TXID, 1)];
+        assert_eq!(txid(&spending(&inputs, true)).unwrap(),
+                   txid(&spending(&inputs, false)).unwrap());
+    }
+
+    #[test]
+    fn test_parse_inputs() {
+        let other = "11".repeat(32);
+        for &segwit in &[false, true] {
+            let raw = spending(&[(PREV_TXID, 1), (&other, 0x0102)], segwit);
+            assert_eq!(parse_inputs(&raw).unwrap(),
+                       vec![OutPoint {
+                                txid: PREV_TXID.to_owned(),
+                                vout: 1,
+                            },
+                            OutPoint {
+                                txid: other.clone(),
+                                vout: 0x0102,
+                            }]);
+        }
+    }
+
+    #[test]
+    fn test_parse_inputs_truncated() {
+        let raw = spending(&[(PREV_TXID, 1)], false);
+        for len in &[0, 3, 5, 40, 45] {
+            match parse_inputs(&raw[..*len]) {
+                Err(Error::InvalidTransaction(_)) => {}
+                other => panic!("unexpected result {:?} for length {}", other, len),
+            }
+            match txid(&raw[..*len]) {
+                Err(Error::InvalidTransaction(_)) => {}
+                other => panic!("unexpected result {:?} for length {}", other, len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_script_length() {
+        // One input, whose script claims to be u64::MAX bytes long
+        let mut raw = vec![2, 0, 0, 0, 1];
+        raw.extend(vec![0; 36]);
+        raw.push(0xff);
+        raw.extend(vec![0xff; 8]);
+        match txid(&raw) {
+            Err(Error::InvalidTransaction(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match parse_inputs(&raw) {
+            Err(Error::InvalidTransaction(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
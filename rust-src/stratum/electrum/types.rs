@@ -0,0 +1,287 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Result types
+//!
+//! Typed forms of the results of Electrum methods
+//!
+
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use serde_json::value::Value;
+
+use error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// The balance of a scripthash, in satoshis
+pub struct Balance {
+    /// The confirmed balance
+    pub confirmed: u64,
+    /// The net effect of unconfirmed transactions, which is negative when
+    /// they spend more than they pay in
+    pub unconfirmed: i64,
+}
+
+impl Balance {
+    /// The balance including unconfirmed transactions
+    pub fn total(&self) -> u64 {
+        if self.unconfirmed < 0 {
+            self.confirmed.saturating_sub(self.unconfirmed.unsigned_abs())
+        } else {
+            self.confirmed + self.unconfirmed as u64
+        }
+    }
+
+    /// The balance which can be spent now
+    ///
+    /// This is the confirmed balance, less any net amount being spent by
+    /// unconfirmed transactions. Unconfirmed incoming funds are not counted.
+    pub fn spendable(&self) -> u64 {
+        self.confirmed.min(self.total())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// An unspent output, as returned by `blockchain.scripthash.listunspent`
+pub struct Utxo {
+    /// The txid of the transaction containing the output
+    pub tx_hash: String,
+    /// The index of the output in its transaction
+    pub tx_pos: u32,
+    /// The height of the block containing the transaction, or zero or less
+    /// if it is unconfirmed
+    ///
+    /// Servers differ in how they encode this, so both numbers and numeric
+    /// strings are accepted.
+    #[serde(deserialize_with = "deserialize_height")]
+    pub height: i64,
+    /// The value of the output in satoshis
+    pub value: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// A transaction touching a scripthash, as returned by
+/// `blockchain.scripthash.get_history`
+pub struct HistoryEntry {
+    /// The txid of the transaction
+    pub tx_hash: String,
+    /// The height of the block containing the transaction, zero if it is
+    /// unconfirmed, or -1 if it is unconfirmed with an unconfirmed input
+    #[serde(deserialize_with = "deserialize_height")]
+    pub height: i64,
+    /// The fee paid, which servers only report for unconfirmed transactions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+}
+
+/// A history entry paired with the raw hex of its transaction, or the error
+/// the server gave when asked for it
+pub type HistoryWithTx = (HistoryEntry, Result<String, Error>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// What is known about one scripthash when a wallet opens
+pub struct ScripthashSnapshot {
+    /// The scripthash, in lowercase hex
+    pub scripthash: String,
+    /// Its balance
+    pub balance: Balance,
+    /// Its history, in the server's order
+    pub history: Vec<HistoryEntry>,
+    /// Its status from subscribing to it, or `None` if it has no history
+    pub status: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// What is known about a wallet's scripthashes when it opens, as fetched by
+/// `Client::prefetch`
+pub struct WalletSnapshot {
+    /// One snapshot per distinct scripthash, in the order first given
+    pub scripthashes: Vec<ScripthashSnapshot>,
+}
+
+impl WalletSnapshot {
+    /// The snapshot of `scripthash`, if it is part of the wallet
+    pub fn get(&self, scripthash: &str) -> Option<&ScripthashSnapshot> {
+        self.scripthashes.iter().find(|s| s.scripthash.eq_ignore_ascii_case(scripthash))
+    }
+
+    /// The combined balance of all the scripthashes
+    pub fn balance(&self) -> Balance {
+        self.scripthashes.iter().fold(Balance {
+                                          confirmed: 0,
+                                          unconfirmed: 0,
+                                      },
+                                      |total, s| {
+            Balance {
+                confirmed: total.confirmed + s.balance.confirmed,
+                unconfirmed: total.unconfirmed + s.balance.unconfirmed,
+            }
+        })
+    }
+}
+
+/// Reads a height given either as a number or as a numeric string
+fn deserialize_height<D: Deserializer>(deserializer: D) -> Result<i64, D::Error> {
+    let value = <Value as Deserialize>::deserialize(deserializer)?;
+    let height = match value {
+        Value::Number(ref n) => n.as_i64(),
+        Value::String(ref s) => s.trim().parse().ok(),
+        _ => None,
+    };
+    height.ok_or_else(|| D::Error::custom(format!("invalid height {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use error::Error;
+    use test_utils::{MockServer, reply};
+    use super::{Balance, HistoryEntry, Utxo};
+
+    const SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+    const UTXO_TXID: &str = "9f2c45a12db0144909b5db269415f7319179105982ac70ed80d76ea79d923ebf";
+
+    fn utxo_with_height(height: &str) -> Result<Utxo, serde_json::Error> {
+        serde_json::from_str(&format!(r#"{{"tx_hash":"{}","tx_pos":1,"height":{},"value":5}}"#,
+                                      UTXO_TXID,
+                                      height))
+    }
+
+    #[test]
+    fn test_balance_confirmed_only() {
+        let balance = Balance {
+            confirmed: 1000,
+            unconfirmed: 0,
+        };
+        assert_eq!(balance.total(), 1000);
+        assert_eq!(balance.spendable(), 1000);
+    }
+
+    #[test]
+    fn test_balance_pending_receive() {
+        let balance = Balance {
+            confirmed: 1000,
+            unconfirmed: 250,
+        };
+        assert_eq!(balance.total(), 1250);
+        assert_eq!(balance.spendable(), 1000);
+    }
+
+    #[test]
+    fn test_balance_pending_spend() {
+        let balance = Balance {
+            confirmed: 1000,
+            unconfirmed: -400,
+        };
+        assert_eq!(balance.total(), 600);
+        assert_eq!(balance.spendable(), 600);
+
+        // Should never be reported, but must not underflow
+        let balance = Balance {
+            confirmed: 100,
+            unconfirmed: -400,
+        };
+        assert_eq!(balance.total(), 0);
+        assert_eq!(balance.spendable(), 0);
+    }
+
+    #[test]
+    fn test_balance_deserialize() {
+        let balance: Balance = serde_json::from_str(r#"{"confirmed":5,"unconfirmed":-3}"#)
+            .unwrap();
+        assert_eq!(balance,
+                   Balance {
+                       confirmed: 5,
+                       unconfirmed: -3,
+                   });
+    }
+
+    #[test]
+    fn test_utxo_numeric_height() {
+        assert_eq!(utxo_with_height("437146").unwrap().height, 437146);
+        assert_eq!(utxo_with_height("0").unwrap().height, 0);
+        assert_eq!(utxo_with_height("-1").unwrap().height, -1);
+    }
+
+    #[test]
+    fn test_utxo_string_height() {
+        assert_eq!(utxo_with_height(r#""437146""#).unwrap().height, 437146);
+        assert_eq!(utxo_with_height(r#""-1""#).unwrap().height, -1);
+    }
+
+    #[test]
+    fn test_utxo_invalid_height() {
+        assert!(utxo_with_height(r#""tall""#).is_err());
+        assert!(utxo_with_height("1.5").is_err());
+        assert!(utxo_with_height("null").is_err());
+        assert!(utxo_with_height("[1]").is_err());
+    }
+
+    #[test]
+    fn test_history_entry_deserialize() {
+        let entry: HistoryEntry =
+            serde_json::from_str(&format!(r#"{{"tx_hash":"{}","height":-1,"fee":226}}"#,
+                                          UTXO_TXID))
+                .unwrap();
+        assert_eq!(entry.height, -1);
+        assert_eq!(entry.fee, Some(226));
+
+        let entry: HistoryEntry =
+            serde_json::from_str(&format!(r#"{{"tx_hash":"{}","height":"200004"}}"#, UTXO_TXID))
+                .unwrap();
+        assert_eq!(entry.height, 200004);
+        assert_eq!(entry.fee, None);
+    }
+
+    #[test]
+    fn test_scripthash_listunspent_invalid() {
+        let server = MockServer::new(|req| {
+            reply(req.id,
+                  json!([{"tx_pos": 0, "value": 1, "height": "soon", "tx_hash": UTXO_TXID}]))
+        });
+        match server.client().scripthash_listunspent(SCRIPTHASH) {
+            Err(Error::InvalidUtxo(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scripthash_listunspent() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.scripthash.listunspent");
+            reply(req.id,
+                  json!([{"tx_pos": 0, "value": 45318048, "height": 437146, "tx_hash": UTXO_TXID},
+                         {"tx_pos": 1, "value": 5, "height": "0", "tx_hash": UTXO_TXID}]))
+        });
+        let utxos = server.client().scripthash_listunspent(SCRIPTHASH).unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert_eq!(utxos[0].tx_pos, 0);
+        assert_eq!(utxos[0].value, 45318048);
+        assert_eq!(utxos[0].height, 437146);
+        assert_eq!(utxos[1].height, 0);
+    }
+
+    #[test]
+    fn test_scripthash_get_balance() {
+        let server = MockServer::new(|req| {
+            assert_eq!(req.method, "blockchain.scripthash.get_balance");
+            assert_eq!(req.params, vec![json!(SCRIPTHASH)]);
+            reply(req.id, json!({"confirmed": 103873966, "unconfirmed": 23684400}))
+        });
+        let balance = server.client().scripthash_get_balance(SCRIPTHASH).unwrap();
+        assert_eq!(balance.confirmed, 103873966);
+        assert_eq!(balance.unconfirmed, 23684400);
+    }
+}
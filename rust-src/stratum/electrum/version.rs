@@ -0,0 +1,139 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Protocol versions
+//!
+//! Parsing and comparison of Electrum protocol version strings
+//!
+
+use std::fmt;
+use std::str::FromStr;
+
+use error::Error;
+
+/// An Electrum protocol version of the form `major.minor[.patch]`
+///
+/// Versions compare numerically, so `1.10` is newer than `1.9`. A missing
+/// patch component is treated as zero, so `1.4` and `1.4.0` are equal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    /// The major version
+    pub major: u32,
+    /// The minor version
+    pub minor: u32,
+    /// The patch version
+    pub patch: u32,
+}
+
+impl ProtocolVersion {
+    /// Creates a new protocol version
+    pub fn new(major: u32, minor: u32, patch: u32) -> ProtocolVersion {
+        ProtocolVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// The minor version before this one, if there is one
+    ///
+    /// This is what a client falls back to when the server turns out not to
+    /// support this version.
+    pub fn previous_minor(&self) -> Option<ProtocolVersion> {
+        if self.minor == 0 {
+            None
+        } else {
+            Some(ProtocolVersion::new(self.major, self.minor - 1, 0))
+        }
+    }
+}
+
+/// A downgrade of a client's recorded protocol version, made because the
+/// server kept rejecting methods of the recorded version as not found; see
+/// `Client::with_adaptive_downgrade`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolDowngrade {
+    /// The version recorded before the downgrade
+    pub from: ProtocolVersion,
+    /// The version recorded after it
+    pub to: ProtocolVersion,
+    /// The method whose rejection triggered the downgrade
+    pub method: String,
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProtocolVersion, Error> {
+        let invalid = || Error::InvalidProtocolVersion(s.to_owned());
+        let parts = s.split('.')
+            .map(|part| part.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<u32>, Error>>()?;
+        match parts.len() {
+            2 => Ok(ProtocolVersion::new(parts[0], parts[1], 0)),
+            3 => Ok(ProtocolVersion::new(parts[0], parts[1], parts[2])),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.patch == 0 {
+            write!(f, "{}.{}", self.major, self.minor)
+        } else {
+            write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion;
+
+    fn version(s: &str) -> ProtocolVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(version("1.4"), ProtocolVersion::new(1, 4, 0));
+        assert_eq!(version("1.4.2"), ProtocolVersion::new(1, 4, 2));
+        assert!("1".parse::<ProtocolVersion>().is_err());
+        assert!("1.4.2.1".parse::<ProtocolVersion>().is_err());
+        assert!("1.x".parse::<ProtocolVersion>().is_err());
+        assert!("".parse::<ProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(version("1.4.2") > version("1.4"));
+        assert!(version("1.10") > version("1.9"));
+        assert!(version("2.0") > version("1.10.3"));
+        assert_eq!(version("1.4"), version("1.4.0"));
+    }
+
+    #[test]
+    fn test_previous_minor() {
+        assert_eq!(version("1.4.2").previous_minor(), Some(version("1.3")));
+        assert_eq!(version("1.1").previous_minor(), Some(version("1.0")));
+        assert_eq!(version("1.0").previous_minor(), None);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(version("1.4").to_string(), "1.4");
+        assert_eq!(version("1.4.2").to_string(), "1.4.2");
+    }
+}
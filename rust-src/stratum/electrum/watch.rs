@@ -0,0 +1,408 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Change detection
+//!
+//! Noticing when a server's advertised features or banner change, which
+//! usually means it restarted or was upgraded, when the chain tip moves or
+//! reorganizes, and when the balance of a scripthash changes
+//!
+
+use serde_json::value::Value;
+
+use client::Client;
+use error::Error;
+use super::header::ParsedHeader;
+use super::requests;
+use super::types::Balance;
+
+/// A change observed in a server's advertised details
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerEvent {
+    /// The result of `server.features` changed
+    FeaturesChanged {
+        /// The previously observed features
+        old: Value,
+        /// The newly observed features
+        new: Value,
+    },
+    /// The server banner changed
+    BannerChanged {
+        /// The previously observed banner
+        old: String,
+        /// The newly observed banner
+        new: String,
+    },
+}
+
+/// Re-fetches a server's features and banner on each `poll`, reporting
+/// how they differ from the last observed values
+///
+/// Call `poll` on whatever schedule suits; the first call only records the
+/// values to compare against.
+pub struct ServerWatcher<'a> {
+    client: &'a Client,
+    last: Option<(Value, String)>,
+}
+
+impl<'a> ServerWatcher<'a> {
+    /// Creates a watcher for the server behind `client`
+    pub fn new(client: &'a Client) -> ServerWatcher<'a> {
+        ServerWatcher {
+            client,
+            last: None,
+        }
+    }
+
+    /// Fetches the server's features and banner, returning any changes
+    /// since the previous poll
+    pub fn poll(&mut self) -> Result<Vec<ServerEvent>, Error> {
+        let features = self.client.server_features()?;
+        let banner = self.client.server_banner()?;
+
+        let mut events = vec![];
+        if let Some((ref old_features, ref old_banner)) = self.last {
+            if *old_features != features {
+                events.push(ServerEvent::FeaturesChanged {
+                    old: old_features.clone(),
+                    new: features.clone(),
+                });
+            }
+            if *old_banner != banner {
+                events.push(ServerEvent::BannerChanged {
+                    old: old_banner.clone(),
+                    new: banner.clone(),
+                });
+            }
+        }
+        self.last = Some((features, banner));
+        Ok(events)
+    }
+}
+
+/// A change observed in the server's chain tip
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TipEvent {
+    /// A new tip was seen which extends, or is higher than, the previous one
+    NewTip {
+        /// The height of the new tip
+        height: u32,
+    },
+    /// A new tip was seen which does not extend the previous one
+    ///
+    /// Transactions confirmed above the lower of the two heights, or at it,
+    /// may no longer be confirmed and should be re-verified.
+    Reorg {
+        /// The height of the previous tip
+        from_height: u32,
+        /// The height of the new tip
+        to_height: u32,
+    },
+}
+
+/// Follows the server's chain tip, reporting new tips and reorgs
+///
+/// Tips can be fetched with `poll`, or fed in with `observe` from wherever
+/// else they were obtained. A tip is a reorg if it is no higher than the
+/// previous one but has a different hash, or if it is directly above the
+/// previous one but does not build on it. The first tip seen is only
+/// recorded.
+pub struct TipTracker<'a> {
+    client: &'a Client,
+    last: Option<(u32, [u8; 32])>,
+}
+
+impl<'a> TipTracker<'a> {
+    /// Creates a tracker for the chain of the server behind `client`
+    pub fn new(client: &'a Client) -> TipTracker<'a> {
+        TipTracker {
+            client,
+            last: None,
+        }
+    }
+
+    /// Fetches the server's chain tip and checks it against the last one
+    pub fn poll(&mut self) -> Result<Option<TipEvent>, Error> {
+        let (height, header) = self.client.tip_header()?;
+        Ok(self.observe(height, &header))
+    }
+
+    /// Checks a tip at `height` against the last one, and records it
+    pub fn observe(&mut self, height: u32, header: &ParsedHeader) -> Option<TipEvent> {
+        let hash = header.hash();
+        let event = match self.last {
+            None => None,
+            Some((last_height, last_hash)) if height <= last_height => {
+                if height == last_height && hash == last_hash {
+                    None
+                } else {
+                    Some(TipEvent::Reorg {
+                        from_height: last_height,
+                        to_height: height,
+                    })
+                }
+            }
+            Some((last_height, last_hash)) if height == last_height + 1 &&
+                                              header.prev_hash != last_hash => {
+                Some(TipEvent::Reorg {
+                    from_height: last_height,
+                    to_height: height,
+                })
+            }
+            // Above a gap there is no way to check linkage without the
+            // headers in between
+            Some(_) => Some(TipEvent::NewTip { height }),
+        };
+        self.last = Some((height, hash));
+        event
+    }
+}
+
+/// Whether a scripthash's status moved from `old` to `new`, where `None` is
+/// the null status of a scripthash with no history
+///
+/// Statuses are opaque hashes, so any difference is a change: a null status
+/// becoming a hash means the first transaction arrived, and a hash becoming
+/// null means the history was emptied, e.g. by a reorg or mempool eviction.
+pub fn status_changed(old: Option<&str>, new: Option<&str>) -> bool {
+    old != new
+}
+
+/// Follows the balance of a scripthash, yielding a fresh `Balance` from
+/// `poll` whenever its status changes
+///
+/// Each poll re-subscribes to the scripthash, which the server answers with
+/// its current status hash, and only refetches the balance when that hash
+/// differs from the last one seen. The first poll always yields a balance.
+pub struct BalanceStream<'a> {
+    client: &'a Client,
+    scripthash: String,
+    // `None` before the first poll; the status is itself `None` while the
+    // scripthash has no history
+    status: Option<Option<String>>,
+}
+
+impl<'a> BalanceStream<'a> {
+    /// Creates a stream for `scripthash`, checking that it is well-formed
+    pub fn new(client: &'a Client, scripthash: &str) -> Result<BalanceStream<'a>, Error> {
+        Ok(BalanceStream {
+            client,
+            scripthash: requests::normalize_scripthash(scripthash)?,
+            status: None,
+        })
+    }
+
+    /// Checks the scripthash's status, returning its new balance if it
+    /// changed since the previous poll
+    pub fn poll(&mut self) -> Result<Option<Balance>, Error> {
+        let status = self.client.scripthash_subscribe(&self.scripthash)?;
+        if let Some(ref old) = self.status {
+            if !status_changed(old.as_deref(), status.as_deref()) {
+                return Ok(None);
+            }
+        }
+        let balance = self.client.scripthash_get_balance(&self.scripthash)?;
+        // Only recorded once the balance is in hand, so that a failed fetch
+        // is retried on the next poll
+        self.status = Some(status);
+        Ok(Some(balance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use test_utils::{MockServer, reply};
+    use electrum::Balance;
+    use electrum::header::tests::chain;
+    use hex;
+    use super::{BalanceStream, ServerEvent, ServerWatcher, TipEvent, TipTracker,
+                status_changed};
+
+    const SCRIPTHASH: &str = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+
+    #[test]
+    fn test_features_change() {
+        let polls = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "server.features" => {
+                let version = if polls.fetch_add(1, Ordering::SeqCst) < 2 {
+                    "ElectrumX 1.8.5"
+                } else {
+                    "ElectrumX 1.8.7"
+                };
+                reply(req.id, json!({"server_version": version, "protocol_max": "1.4"}))
+            }
+            "server.banner" => reply(req.id, json!("Welcome")),
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client();
+        let mut watcher = ServerWatcher::new(&client);
+
+        assert!(watcher.poll().unwrap().is_empty());
+        assert!(watcher.poll().unwrap().is_empty());
+        let events = watcher.poll().unwrap();
+        assert_eq!(events,
+                   vec![ServerEvent::FeaturesChanged {
+                            old: json!({"server_version": "ElectrumX 1.8.5", "protocol_max": "1.4"}),
+                            new: json!({"server_version": "ElectrumX 1.8.7", "protocol_max": "1.4"}),
+                        }]);
+        assert!(watcher.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_banner_change() {
+        let polls = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "server.features" => reply(req.id, json!({"protocol_max": "1.4"})),
+            "server.banner" => {
+                let banner = if polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    "Welcome"
+                } else {
+                    "Down for maintenance at 12:00"
+                };
+                reply(req.id, json!(banner))
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client();
+        let mut watcher = ServerWatcher::new(&client);
+
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.poll().unwrap(),
+                   vec![ServerEvent::BannerChanged {
+                            old: "Welcome".to_owned(),
+                            new: "Down for maintenance at 12:00".to_owned(),
+                        }]);
+    }
+
+    #[test]
+    fn test_tip_tracker_extends() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let client = server.client();
+        let mut tracker = TipTracker::new(&client);
+        let headers = chain(3);
+
+        assert_eq!(tracker.observe(0, &headers[0]), None);
+        assert_eq!(tracker.observe(0, &headers[0]), None);
+        assert_eq!(tracker.observe(1, &headers[1]),
+                   Some(TipEvent::NewTip { height: 1 }));
+        assert_eq!(tracker.observe(2, &headers[2]),
+                   Some(TipEvent::NewTip { height: 2 }));
+    }
+
+    #[test]
+    fn test_tip_tracker_reorg() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let client = server.client();
+        let mut tracker = TipTracker::new(&client);
+        let headers = chain(3);
+        let mut stale = headers[2].clone();
+        stale.nonce += 1;
+
+        tracker.observe(1, &headers[1]);
+        // Directly above the tip, but not built on it
+        let mut orphan = headers[2].clone();
+        orphan.prev_hash = [0xab; 32];
+        assert_eq!(tracker.observe(2, &orphan),
+                   Some(TipEvent::Reorg {
+                       from_height: 1,
+                       to_height: 2,
+                   }));
+        // A different block at the same height
+        assert_eq!(tracker.observe(2, &stale),
+                   Some(TipEvent::Reorg {
+                       from_height: 2,
+                       to_height: 2,
+                   }));
+        // A lower tip
+        assert_eq!(tracker.observe(1, &headers[1]),
+                   Some(TipEvent::Reorg {
+                       from_height: 2,
+                       to_height: 1,
+                   }));
+    }
+
+    #[test]
+    fn test_tip_tracker_poll() {
+        let headers = chain(3);
+        let tips: Vec<String> = headers.iter().map(|h| hex::encode(&h.to_bytes())).collect();
+        let polls = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| {
+            assert_eq!(req.method, "blockchain.headers.subscribe");
+            let height = [1, 2, 2][polls.fetch_add(1, Ordering::SeqCst)];
+            reply(req.id, json!({"height": height, "hex": tips[height]}))
+        });
+        let client = server.client();
+        let mut tracker = TipTracker::new(&client);
+
+        assert_eq!(tracker.poll().unwrap(), None);
+        assert_eq!(tracker.poll().unwrap(), Some(TipEvent::NewTip { height: 2 }));
+        assert_eq!(tracker.poll().unwrap(), None);
+    }
+
+    #[test]
+    fn test_status_changed() {
+        assert!(status_changed(None, Some("1f3a")));
+        assert!(status_changed(Some("1f3a"), None));
+        assert!(status_changed(Some("1f3a"), Some("9c04")));
+        assert!(!status_changed(Some("1f3a"), Some("1f3a")));
+        assert!(!status_changed(None, None));
+    }
+
+    #[test]
+    fn test_balance_stream() {
+        let subscribes = AtomicUsize::new(0);
+        let balances = AtomicUsize::new(0);
+        let server = MockServer::new(move |req| match req.method.as_str() {
+            "blockchain.scripthash.subscribe" => {
+                let status = match subscribes.fetch_add(1, Ordering::SeqCst) {
+                    0 => json!(null),
+                    1 | 2 => json!("1f3a"),
+                    _ => json!("9c04"),
+                };
+                reply(req.id, status)
+            }
+            "blockchain.scripthash.get_balance" => {
+                let confirmed = 1000 * balances.fetch_add(1, Ordering::SeqCst);
+                reply(req.id, json!({"confirmed": confirmed, "unconfirmed": 0}))
+            }
+            _ => panic!("unexpected method {}", req.method),
+        });
+        let client = server.client();
+        let mut stream = BalanceStream::new(&client, SCRIPTHASH).unwrap();
+
+        let balance = |confirmed| {
+            Some(Balance {
+                confirmed,
+                unconfirmed: 0,
+            })
+        };
+        assert_eq!(stream.poll().unwrap(), balance(0));
+        assert_eq!(stream.poll().unwrap(), balance(1000));
+        assert_eq!(stream.poll().unwrap(), None);
+        assert_eq!(stream.poll().unwrap(), balance(2000));
+        assert_eq!(stream.poll().unwrap(), None);
+        // 5 subscribes and 3 balance fetches
+        assert_eq!(server.hits(), 8);
+    }
+
+    #[test]
+    fn test_balance_stream_invalid_scripthash() {
+        let server = MockServer::new(|_| panic!("no request expected"));
+        let client = server.client();
+        assert!(BalanceStream::new(&client, "8b01").is_err());
+    }
+}
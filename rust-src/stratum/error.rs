@@ -19,6 +19,7 @@
 
 use std::{error, fmt};
 use std::io;
+use std::string::FromUtf8Error;
 
 use hyper;
 use serde_json;
@@ -26,6 +27,10 @@ use serde_json::value::Value;
 
 use Response;
 
+/// The most of a malformed response body kept in
+/// `Error::MalformedResponse`
+pub const MAX_MALFORMED_BODY: usize = 4 * 1024;
+
 /// A library error
 #[derive(Debug)]
 pub enum Error {
@@ -37,10 +42,90 @@ pub enum Error {
     Rpc(RpcError),
     /// IO Error
     Io(io::Error),
+    /// The result of the response with the given id could not be
+    /// deserialized into the type asked for
+    UnexpectedResult {
+        /// The id of the response
+        id: Value,
+        /// Why the result did not deserialize
+        error: serde_json::error::Error,
+    },
+    /// The server sent something which is not a JSONRPC response
+    MalformedResponse {
+        /// Why it did not parse
+        source: serde_json::error::Error,
+        /// What the server sent, cut to `MAX_MALFORMED_BODY` bytes
+        body: String,
+    },
+    /// The server sent a response body which is not UTF-8, as JSON must be
+    InvalidUtf8(FromUtf8Error),
     /// Response has neither error nor result
     NoErrorOrResult,
     /// Response to a request did not have the expected nonce
     NonceMismatch,
+    /// A protocol version string could not be parsed
+    InvalidProtocolVersion(String),
+    /// A block header could not be decoded
+    InvalidHeader(String),
+    /// The header at the given height does not extend the previous header
+    UnlinkedHeader(u32),
+    /// A broadcast transaction never became visible on the server
+    BroadcastNotSeen(String),
+    /// The server did not start responding in time
+    FirstByteTimeout,
+    /// The server started responding, but did not finish in time
+    ReadTimeout,
+    /// Connecting to the server, or waiting on it, took longer than the
+    /// client's timeout
+    Timeout,
+    /// An operation's retry budget ran out; this holds the error which
+    /// needed one more retry
+    RetryBudgetExhausted(Box<Error>),
+    /// The available outputs do not cover the amount to be spent
+    InsufficientFunds,
+    /// A read-only client was asked to broadcast a transaction
+    ReadOnly,
+    /// An unspent output returned by the server could not be decoded
+    InvalidUtxo(String),
+    /// A scripthash was not 32 bytes of hex
+    InvalidScripthash(String),
+    /// A string which should be hex had an odd length or a non-hex digit
+    InvalidHex,
+    /// A request was refused client-side for exceeding a limit the server
+    /// advertises
+    ExceedsServerLimit(String),
+    /// A request about the given scripthash failed, aborting a call which
+    /// covers several
+    ScripthashFailed(String, Box<Error>),
+    /// A response validator rejected a response, for the given reason
+    ResponseRejected(String),
+    /// A raw transaction could not be decoded
+    InvalidTransaction(String),
+    /// The merkle branch given for the transaction with this txid does not
+    /// lead to its block's merkle root
+    InvalidMerkleProof(String),
+    /// A TLS connection could not be set up, e.g. because the server's
+    /// certificate was not trusted
+    Tls(String),
+    /// A SOCKS5 proxy could not connect to the server, or did not speak
+    /// SOCKS5
+    Proxy(String),
+    /// A batch response carried an id which matched no request in the
+    /// batch, or one already answered
+    UnexpectedBatchId(Value),
+    /// The server answered with an HTTP error status and a body which is
+    /// not a JSONRPC response, as a proxy in front of it might
+    Http {
+        /// The HTTP status code
+        status: u16,
+        /// The response body
+        body: String,
+    },
+    /// The server left some requests of a batch unanswered
+    IncompleteBatch {
+        /// The ids of the unanswered requests
+        missing: Vec<Value>,
+    },
 }
 
 impl From<serde_json::error::Error> for Error {
@@ -72,30 +157,64 @@ impl fmt::Display for Error {
         match *self {
             Error::Json(ref e) => write!(f, "JSON decode error: {}", e),
             Error::Hyper(ref e) => write!(f, "Hyper error: {}", e),
-            Error::Rpc(ref r) => write!(f, "RPC error response: {:?}", r),
+            Error::Rpc(ref r) => write!(f, "RPC error response: {}", r),
             Error::Io(ref e) => write!(f, "IO error: {:?}", e),
-            _ => f.write_str(error::Error::description(self)),
+            Error::InvalidProtocolVersion(ref v) => write!(f, "Invalid protocol version: {}", v),
+            Error::InvalidHeader(ref m) => write!(f, "Invalid block header: {}", m),
+            Error::InvalidUtxo(ref m) => write!(f, "Invalid unspent output: {}", m),
+            Error::InvalidScripthash(ref s) => write!(f, "Invalid scripthash: {}", s),
+            Error::ExceedsServerLimit(ref s) => write!(f, "Exceeds server limit: {}", s),
+            Error::ResponseRejected(ref m) => write!(f, "Response rejected: {}", m),
+            Error::InvalidTransaction(ref m) => write!(f, "Invalid transaction: {}", m),
+            Error::InvalidMerkleProof(ref txid) => write!(f, "Invalid merkle proof for {}", txid),
+            Error::Tls(ref m) => write!(f, "TLS error: {}", m),
+            Error::Proxy(ref m) => write!(f, "Proxy error: {}", m),
+            Error::MalformedResponse { ref source, .. } => {
+                write!(f, "Malformed response: {}", source)
+            }
+            Error::InvalidUtf8(ref e) => write!(f, "Response is not valid UTF-8: {}", e),
+            Error::UnexpectedResult { ref id, ref error } => {
+                write!(f, "Unexpected result for request {}: {}", id, error)
+            }
+            Error::UnexpectedBatchId(ref id) => write!(f, "Unexpected id in batch response: {}", id),
+            Error::ScripthashFailed(ref s, ref e) => {
+                write!(f, "Request for scripthash {} failed: {}", s, e)
+            }
+            Error::RetryBudgetExhausted(ref e) => write!(f, "Retry budget exhausted: {}", e),
+            Error::Http { status, .. } => write!(f, "HTTP error status {}", status),
+            Error::IncompleteBatch { ref missing } => {
+                write!(f, "Batch response left {} requests unanswered", missing.len())
+            }
+            Error::UnlinkedHeader(h) => {
+                write!(f, "Header at height {} does not extend the previous header", h)
+            }
+            Error::BroadcastNotSeen(ref txid) => {
+                write!(f, "Broadcast transaction {} was not seen on the server", txid)
+            }
+            Error::NoErrorOrResult => f.write_str("Malformed RPC response"),
+            Error::NonceMismatch => f.write_str("Nonce of response did not match nonce of request"),
+            Error::FirstByteTimeout => f.write_str("Timed out waiting for the server to respond"),
+            Error::ReadTimeout => f.write_str("Timed out reading the response"),
+            Error::Timeout => f.write_str("Timed out"),
+            Error::InsufficientFunds => f.write_str("Insufficient funds"),
+            Error::ReadOnly => f.write_str("Broadcast refused by a read-only client"),
+            Error::InvalidHex => f.write_str("Invalid hex"),
         }
     }
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::Json(_) => "JSON decode error",
-            Error::Hyper(_) => "Hyper error",
-            Error::Rpc(_) => "RPC error response",
-            Error::Io(_) => "IO error",
-            Error::NoErrorOrResult => "Malformed RPC response",
-            Error::NonceMismatch => "Nonce of response did not match nonce of request",
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
             Error::Json(ref e) => Some(e),
             Error::Hyper(ref e) => Some(e),
+            Error::Rpc(ref e) => Some(e),
             Error::Io(ref e) => Some(e),
+            Error::MalformedResponse { ref source, .. } => Some(source),
+            Error::InvalidUtf8(ref e) => Some(e),
+            Error::UnexpectedResult { ref error, .. } => Some(error),
+            Error::ScripthashFailed(_, ref e) => Some(&**e),
+            Error::RetryBudgetExhausted(ref e) => Some(&**e),
             _ => None,
         }
     }
@@ -123,7 +242,7 @@ impl error::Error for Error {
 /// RIGHTS OR ANY IMPLIED WARRANTIES OF MERCHANTABILITY OR FITNESS FOR A
 /// PARTICULAR PURPOSE.
 ///
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StandardError {
     /// Invalid JSON was received by the server.
     /// An error occurred on the server while parsing the JSON text.
@@ -138,6 +257,86 @@ pub enum StandardError {
     InternalError,
 }
 
+/// Every standard error
+const STANDARD_ERRORS: [StandardError; 5] = [StandardError::ParseError,
+                                             StandardError::InvalidRequest,
+                                             StandardError::MethodNotFound,
+                                             StandardError::InvalidParams,
+                                             StandardError::InternalError];
+
+impl StandardError {
+    /// The error code the specification gives this error
+    pub fn code(self) -> i32 {
+        match self {
+            StandardError::ParseError => -32700,
+            StandardError::InvalidRequest => -32600,
+            StandardError::MethodNotFound => -32601,
+            StandardError::InvalidParams => -32602,
+            StandardError::InternalError => -32603,
+        }
+    }
+}
+
+/// Application error codes used by Electrum servers, as defined by
+/// ElectrumX and the aiorpcx library under it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElectrumError {
+    /// The request is invalid for its method, e.g. a malformed scripthash
+    /// or a transaction rejected by network rules
+    BadRequest,
+    /// The server's node failed the request, e.g. with "blockhash not
+    /// found" or while it is still loading
+    DaemonError,
+    /// The session has used more of the server's resources than it allows,
+    /// as when a rate limit is hit
+    ExcessiveResourceUsage,
+    /// The server is too busy to answer
+    ServerBusy,
+}
+
+/// Every Electrum error
+const ELECTRUM_ERRORS: [ElectrumError; 4] = [ElectrumError::BadRequest,
+                                             ElectrumError::DaemonError,
+                                             ElectrumError::ExcessiveResourceUsage,
+                                             ElectrumError::ServerBusy];
+
+impl ElectrumError {
+    /// The error code servers send for this error
+    pub fn code(self) -> i32 {
+        match self {
+            ElectrumError::BadRequest => 1,
+            ElectrumError::DaemonError => 2,
+            ElectrumError::ExcessiveResourceUsage => -102,
+            ElectrumError::ServerBusy => -103,
+        }
+    }
+}
+
+/// What an error code denotes, from `RpcError::classify`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// One of the errors predefined by the specification
+    Standard(StandardError),
+    /// One of the application errors Electrum servers use
+    Electrum(ElectrumError),
+    /// Any other code
+    Other(i32),
+}
+
+/// Which part of the JSONRPC code space an error code falls in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A code reserved by the specification for its predefined errors,
+    /// -32768 to -32000 apart from the server error range
+    Standard,
+    /// A code from -32099 to -32000, left by the specification for
+    /// implementation-defined server errors, which are often transient
+    ServerDefined,
+    /// Any other code, defined by the application, such as Electrum's
+    /// codes for rejected transactions
+    ApplicationDefined,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 /// A JSONRPC error object
 pub struct RpcError {
@@ -149,47 +348,57 @@ pub struct RpcError {
     pub data: Option<Value>,
 }
 
-/// Create a standard error responses
-pub fn standard_error(code: StandardError, data: Option<Value>) -> RpcError {
-    match code {
-        StandardError::ParseError => {
-            RpcError {
-                code: -32700,
-                message: "Parse error".to_string(),
-                data: data,
-            }
-        }
-        StandardError::InvalidRequest => {
-            RpcError {
-                code: -32600,
-                message: "Invalid Request".to_string(),
-                data: data,
-            }
-        }
-        StandardError::MethodNotFound => {
-            RpcError {
-                code: -32601,
-                message: "Method not found".to_string(),
-                data: data,
-            }
+impl RpcError {
+    /// The standard error this error's code denotes, if any
+    pub fn as_standard(&self) -> Option<StandardError> {
+        STANDARD_ERRORS.iter().cloned().find(|e| e.code() == self.code)
+    }
+
+    /// What this error's code denotes, for matching on known errors
+    pub fn classify(&self) -> ErrorCode {
+        if let Some(standard) = self.as_standard() {
+            return ErrorCode::Standard(standard);
         }
-        StandardError::InvalidParams => {
-            RpcError {
-                code: -32602,
-                message: "Invalid params".to_string(),
-                data: data,
-            }
+        match ELECTRUM_ERRORS.iter().cloned().find(|e| e.code() == self.code) {
+            Some(electrum) => ErrorCode::Electrum(electrum),
+            None => ErrorCode::Other(self.code),
         }
-        StandardError::InternalError => {
-            RpcError {
-                code: -32603,
-                message: "Internal error".to_string(),
-                data: data,
-            }
+    }
+
+    /// The part of the code space this error's code falls in
+    pub fn category(&self) -> ErrorCategory {
+        match self.code {
+            -32099..=-32000 => ErrorCategory::ServerDefined,
+            -32768..=-32100 => ErrorCategory::Standard,
+            _ => ErrorCategory::ApplicationDefined,
         }
     }
 }
 
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl error::Error for RpcError {}
+
+/// Create a standard error responses
+pub fn standard_error(code: StandardError, data: Option<Value>) -> RpcError {
+    let message = match code {
+        StandardError::ParseError => "Parse error",
+        StandardError::InvalidRequest => "Invalid Request",
+        StandardError::MethodNotFound => "Method not found",
+        StandardError::InvalidParams => "Invalid params",
+        StandardError::InternalError => "Internal error",
+    };
+    RpcError {
+        code: code.code(),
+        message: message.to_string(),
+        data,
+    }
+}
+
 /// Converts a Rust `Result` to a JSONRPC response object
 pub fn result_to_response(result: Result<Value, RpcError>, id: Value) -> Response {
     match result {
@@ -197,24 +406,56 @@ pub fn result_to_response(result: Result<Value, RpcError>, id: Value) -> Respons
             Response {
                 result: Some(data),
                 error: None,
-                id: id,
+                id,
+                jsonrpc: None,
             }
         }
         Err(err) => {
             Response {
                 result: None,
                 error: Some(err),
-                id: id,
+                id,
+                jsonrpc: None,
             }
         }
     }
 }
 
+/// Gives `Error::MalformedResponse` for a response body which failed to
+/// parse, keeping at most `MAX_MALFORMED_BODY` bytes of it
+pub(crate) fn malformed_response(source: serde_json::error::Error, body: &str) -> Error {
+    let mut end = body.len().min(MAX_MALFORMED_BODY);
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    Error::MalformedResponse {
+        source,
+        body: body[..end].to_owned(),
+    }
+}
+
+/// Converts any `Error` to a JSONRPC error response
+///
+/// RPC errors are passed through verbatim. Anything else failed on this
+/// side rather than in the request, so becomes an internal error, with the
+/// error's description as its data.
+pub fn error_to_response(err: &Error, id: Value) -> Response {
+    let rpc_error = match *err {
+        Error::Rpc(ref e) => e.clone(),
+        ref e => standard_error(StandardError::InternalError, Some(Value::String(e.to_string()))),
+    };
+    result_to_response(Err(rpc_error), id)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::error::Error as StdError;
+
     use super::StandardError::{ParseError, InvalidRequest, MethodNotFound, InvalidParams,
                                InternalError};
-    use super::{standard_error, result_to_response};
+    use super::{ElectrumError, Error, ErrorCategory, ErrorCode, MAX_MALFORMED_BODY, RpcError,
+                error_to_response, malformed_response, standard_error, result_to_response};
+    use serde_json;
     use serde_json::Value;
 
     #[test]
@@ -266,4 +507,125 @@ mod tests {
         assert_eq!(resp.id, Value::Number(From::from(-11)));
         assert_eq!(resp.error.unwrap().code, -32603);
     }
+
+    #[test]
+    fn test_error_to_response_json() {
+        let err = Error::from(serde_json::from_str::<Value>("{").unwrap_err());
+        let resp = error_to_response(&err, Value::Number(From::from(4)));
+        assert!(resp.result.is_none());
+        assert_eq!(resp.id, Value::Number(From::from(4)));
+        let error = resp.error.unwrap();
+        assert_eq!(error.as_standard(), Some(InternalError));
+        assert_eq!(error.data, Some(Value::String(err.to_string())));
+    }
+
+    #[test]
+    fn test_error_to_response_rpc() {
+        let rpc_error = RpcError {
+            code: 2,
+            message: "daemon error".to_owned(),
+            data: Some(Value::Bool(true)),
+        };
+        let resp = error_to_response(&Error::Rpc(rpc_error.clone()),
+                                     Value::String("a".to_owned()));
+        assert!(resp.result.is_none());
+        assert_eq!(resp.id, Value::String("a".to_owned()));
+        assert_eq!(resp.error, Some(rpc_error));
+    }
+
+    #[test]
+    fn test_source() {
+        let err = Error::Rpc(standard_error(MethodNotFound, None));
+        assert_eq!(err.source().unwrap().to_string(), "-32601: Method not found");
+        assert_eq!(err.to_string(), "RPC error response: -32601: Method not found");
+        assert!(Error::NonceMismatch.source().is_none());
+    }
+
+    #[test]
+    fn test_malformed_response_truncated() {
+        let parse = |body: &str| {
+            let source = serde_json::from_str::<Value>(body).unwrap_err();
+            malformed_response(source, body)
+        };
+        match parse("{\"result\": tru") {
+            Error::MalformedResponse { ref body, .. } => assert_eq!(body, "{\"result\": tru"),
+            other => panic!("unexpected error {:?}", other),
+        }
+        // Cut short of the limit rather than through a character
+        let long = format!("{{{}", "é".repeat(MAX_MALFORMED_BODY));
+        match parse(&long) {
+            Error::MalformedResponse { ref body, .. } => {
+                assert_eq!(body.len(), MAX_MALFORMED_BODY - 1);
+                assert!(long.starts_with(body.as_str()));
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_as_standard() {
+        for &code in &[ParseError, InvalidRequest, MethodNotFound, InvalidParams, InternalError] {
+            assert_eq!(standard_error(code, None).as_standard(), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_as_standard_nonstandard() {
+        let err = RpcError {
+            code: -32000,
+            message: "Server error".to_owned(),
+            data: None,
+        };
+        assert_eq!(err.as_standard(), None);
+
+        let err = RpcError {
+            code: 1,
+            message: "the transaction was rejected by network rules".to_owned(),
+            data: None,
+        };
+        assert_eq!(err.as_standard(), None);
+    }
+
+    #[test]
+    fn test_classify() {
+        let classify = |code| {
+            let err = RpcError {
+                code,
+                message: String::new(),
+                data: None,
+            };
+            err.classify()
+        };
+        for &code in &[ParseError, InvalidRequest, MethodNotFound, InvalidParams, InternalError] {
+            assert_eq!(standard_error(code, None).classify(), ErrorCode::Standard(code));
+        }
+        assert_eq!(classify(1), ErrorCode::Electrum(ElectrumError::BadRequest));
+        assert_eq!(classify(2), ErrorCode::Electrum(ElectrumError::DaemonError));
+        assert_eq!(classify(-102), ErrorCode::Electrum(ElectrumError::ExcessiveResourceUsage));
+        assert_eq!(classify(-103), ErrorCode::Electrum(ElectrumError::ServerBusy));
+        for &code in &[-32000, -28, 0, 3] {
+            assert_eq!(classify(code), ErrorCode::Other(code));
+        }
+    }
+
+    #[test]
+    fn test_category() {
+        let category = |code| {
+            let err = RpcError {
+                code,
+                message: String::new(),
+                data: None,
+            };
+            err.category()
+        };
+        for &code in &[-32700, -32600, -32601, -32602, -32603, -32768, -32100] {
+            assert_eq!(category(code), ErrorCategory::Standard, "code {}", code);
+        }
+        for &code in &[-32000, -32050, -32099] {
+            assert_eq!(category(code), ErrorCategory::ServerDefined, "code {}", code);
+        }
+        for &code in &[-32769, -31999, -26, -5, 0, 1, 2] {
+            assert_eq!(category(code), ErrorCategory::ApplicationDefined, "code {}", code);
+        }
+    }
 }
@@ -0,0 +1,156 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Failover
+//!
+//! A layer over several `Client`s for the same network, which moves on to
+//! the next server whenever the current one cannot be reached
+//!
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json::value::Value;
+
+use {Request, Response};
+use client::Client;
+use error::Error;
+use retry::is_transient;
+
+/// A client sending each request to its current server, and on a failure
+/// to reach that server, making the next one current and resending there
+///
+/// Only failures of the server itself, such as a refused connection or a
+/// timeout, move on to the next server. An error response is the server's
+/// answer to the request, so it is returned as it is.
+pub struct FailoverClient {
+    clients: Vec<Client>,
+    current: AtomicUsize,
+}
+
+impl FailoverClient {
+    /// Wraps clients for several servers, the first being current
+    ///
+    /// Panics if `clients` is empty.
+    pub fn new(clients: Vec<Client>) -> FailoverClient {
+        assert!(!clients.is_empty(), "a failover client needs at least one server");
+        FailoverClient {
+            clients,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// Creates a client for each of `urls`, using the default profile of
+    /// `Client::with_defaults`, the first being current
+    ///
+    /// Panics if `urls` is empty.
+    pub fn with_servers(urls: Vec<String>) -> FailoverClient {
+        let clients = urls.into_iter().map(|url| Client::with_defaults(url, None, None)).collect();
+        FailoverClient::new(clients)
+    }
+
+    /// Accessor for the client for the current server
+    pub fn client(&self) -> &Client {
+        &self.clients[self.current.load(Ordering::SeqCst)]
+    }
+
+    /// The URL of the current server
+    pub fn current_server(&self) -> &str {
+        self.client().url()
+    }
+
+    /// Sends a request to the current server, moving on through the others
+    /// in turn while each fails to answer
+    ///
+    /// Each server is tried at most once, and if none answers, the error
+    /// from the last is returned. A request resent to another server may
+    /// already have reached the one before it.
+    pub fn send_request(&self, request: &Request) -> Result<Response, Error> {
+        let start = self.current.load(Ordering::SeqCst);
+        let mut attempt = 0;
+        loop {
+            let index = (start + attempt) % self.clients.len();
+            match self.clients[index].send_request(request) {
+                Err(ref e) if is_transient(e) && attempt + 1 < self.clients.len() => {
+                    // Requests failing together move on only once
+                    let next = (index + 1) % self.clients.len();
+                    let _ = self.current
+                        .compare_exchange(index, next, Ordering::SeqCst, Ordering::SeqCst);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Builds a request, as with `Client::build_request` on the current
+    /// client
+    pub fn build_request(&self, name: String, params: Vec<Value>) -> Request {
+        self.client().build_request(name, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use serde_json::Value;
+
+    use error::Error;
+    use test_utils::{MockServer, reply, reply_error};
+    use super::FailoverClient;
+
+    /// The URL of a port nothing is listening on
+    fn refusing_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn test_failover() {
+        let dead = refusing_url();
+        let server = MockServer::new(|req| reply(req.id, Value::String(req.method)));
+        let client = FailoverClient::with_servers(vec![dead.clone(), server.url()]);
+        assert_eq!(client.current_server(), dead);
+
+        for _ in 0..2 {
+            let req = client.build_request("server.banner".to_owned(), vec![]);
+            let response = client.send_request(&req).unwrap();
+            assert_eq!(response.result, Some(json!("server.banner")));
+            assert_eq!(client.current_server(), server.url());
+        }
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[test]
+    fn test_no_failover_on_rpc_error() {
+        let first = MockServer::new(|req| reply_error(req.id, -32601, "unknown method"));
+        let second = MockServer::new(|req| reply(req.id, Value::Null));
+        let client = FailoverClient::with_servers(vec![first.url(), second.url()]);
+
+        let req = client.build_request("server.nonsense".to_owned(), vec![]);
+        match client.send_request(&req).unwrap().check_error() {
+            Err(Error::Rpc(ref e)) => assert_eq!(e.code, -32601),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(client.current_server(), first.url());
+        assert_eq!(second.hits(), 0);
+    }
+
+    #[test]
+    fn test_all_servers_down() {
+        let client = FailoverClient::with_servers(vec![refusing_url(), refusing_url()]);
+        let req = client.build_request("server.ping".to_owned(), vec![]);
+        assert!(client.send_request(&req).is_err());
+    }
+}
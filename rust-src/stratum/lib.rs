@@ -27,14 +27,46 @@
 #![deny(unused_mut)]
 #![warn(missing_docs)]
 
+// serde_derive 0.9 emits its impls inside a `const` block
+#![allow(non_local_definitions)]
+
 extern crate hyper;
+#[cfg(all(test, feature = "tls"))]
+extern crate rcgen;
+#[cfg(feature = "logging")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
 extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate webpki_roots;
 
 pub mod client;
+pub mod clock;
+pub mod coalesce;
+pub mod electrum;
+#[cfg(test)]
+mod test_utils;
 pub mod error;
+pub mod failover;
+mod hex;
+pub mod metrics;
+pub mod retry;
+pub mod server;
+
+use std::ops::Index;
+
+use serde_json::Map;
 
 pub use serde_json::value::Value;
 pub use error::Error;
@@ -45,9 +77,14 @@ pub struct Request {
     /// The name of the RPC call
     pub method: String,
     /// Parameters to the RPC call
-    pub params: Vec<Value>,
+    #[serde(default)]
+    pub params: Params,
     /// Identifier for this Request, which should appear in the response
     pub id: Value,
+    /// The JSONRPC version, which JSONRPC 2.0 servers require to be "2.0";
+    /// left out of 1.0 requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jsonrpc: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -59,6 +96,91 @@ pub struct Response {
     pub error: Option<error::RpcError>,
     /// Identifier for this Request, which should match that of the request
     pub id: Value,
+    /// The JSONRPC version, which JSONRPC 2.0 servers send as "2.0"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jsonrpc: Option<String>,
+}
+
+/// The parameters of a request, given by position as a JSON array or by
+/// name as a JSON object
+///
+/// Electrum methods take positional parameters, which `build_request`
+/// gives; some servers want named ones. Indexing by position gives null
+/// past the end, or for named parameters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Params {
+    /// Parameters by position
+    Positional(Vec<Value>),
+    /// Parameters by name
+    Named(Map<String, Value>),
+}
+
+impl Params {
+    /// The parameters by position, if they are given that way
+    pub fn as_positional(&self) -> Option<&[Value]> {
+        match *self {
+            Params::Positional(ref params) => Some(params),
+            Params::Named(_) => None,
+        }
+    }
+
+    /// How many parameters there are
+    pub fn len(&self) -> usize {
+        match *self {
+            Params::Positional(ref params) => params.len(),
+            Params::Named(ref params) => params.len(),
+        }
+    }
+
+    /// Whether there are no parameters
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params::Positional(vec![])
+    }
+}
+
+impl From<Vec<Value>> for Params {
+    fn from(params: Vec<Value>) -> Params {
+        Params::Positional(params)
+    }
+}
+
+impl From<Map<String, Value>> for Params {
+    fn from(params: Map<String, Value>) -> Params {
+        Params::Named(params)
+    }
+}
+
+impl PartialEq<Vec<Value>> for Params {
+    fn eq(&self, other: &Vec<Value>) -> bool {
+        self.as_positional() == Some(other.as_slice())
+    }
+}
+
+impl Index<usize> for Params {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        const NULL: Value = Value::Null;
+        self.as_positional().and_then(|params| params.get(index)).unwrap_or(&NULL)
+    }
+}
+
+/// A message the server sends unprompted, such as a subscription update
+///
+/// Unlike a response, a notification has no id.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    /// The method the notification is about, e.g. the subscription method
+    pub method: String,
+    /// Parameters of the notification
+    pub params: Vec<Value>,
 }
 
 impl Response {
@@ -73,6 +195,25 @@ impl Response {
         }
     }
 
+    /// Extract the result from a response as with `into_result`, but
+    /// report a result of the wrong shape along with the response's id
+    ///
+    /// This suits results deserialized into an enum covering the shapes of
+    /// several methods, where the id tells which request went wrong.
+    pub fn into_typed<T: serde::Deserialize>(self) -> Result<T, Error> {
+        if let Some(e) = self.error {
+            return Err(Error::Rpc(e));
+        }
+        let id = self.id;
+        match self.result {
+            Some(res) => {
+                serde_json::value::from_value(res)
+                    .map_err(|error| Error::UnexpectedResult { id, error })
+            }
+            None => Err(Error::NoErrorOrResult),
+        }
+    }
+
     /// Return the RPC error, if there was one, but do not check the result
     pub fn check_error(self) -> Result<(), Error> {
         if let Some(e) = self.error {
@@ -90,21 +231,111 @@ impl Response {
 
 #[cfg(test)]
 mod tests {
-    use super::Response;
+    use super::{Params, Request, Response};
+    use super::error::Error;
+    use super::serde_json;
     use super::serde_json::Value;
 
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Shape {
+        Height(u64),
+        Names(Vec<String>),
+    }
+
+    #[test]
+    fn response_into_typed() {
+        let height = Response {
+            result: Some(Value::from(800000)),
+            error: None,
+            id: From::from(1),
+            jsonrpc: None,
+        };
+        assert_eq!(height.into_typed::<Shape>().unwrap(), Shape::Height(800000));
+
+        let names = Response {
+            result: Some(Value::Array(vec![Value::String("electrs".to_owned())])),
+            error: None,
+            id: From::from(2),
+            jsonrpc: None,
+        };
+        assert_eq!(names.into_typed::<Shape>().unwrap(),
+                   Shape::Names(vec!["electrs".to_owned()]));
+
+        let neither = Response {
+            result: Some(Value::Bool(true)),
+            error: None,
+            id: From::from(3),
+            jsonrpc: None,
+        };
+        match neither.into_typed::<Shape>() {
+            Err(Error::UnexpectedResult { ref id, .. }) => assert_eq!(*id, Value::from(3)),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn request_jsonrpc_round_trip() {
+        let v1 = r#"{"method":"server.ping","params":[],"id":1}"#;
+        let request: Request = serde_json::from_str(v1).unwrap();
+        assert_eq!(request.jsonrpc, None);
+        assert_eq!(serde_json::to_string(&request).unwrap(), v1);
+
+        let v2 = r#"{"method":"server.ping","params":[],"id":1,"jsonrpc":"2.0"}"#;
+        let request: Request = serde_json::from_str(v2).unwrap();
+        assert_eq!(request.jsonrpc, Some("2.0".to_owned()));
+        assert_eq!(serde_json::to_string(&request).unwrap(), v2);
+    }
+
+    #[test]
+    fn request_params() {
+        let positional = r#"{"method":"blockchain.estimatefee","params":[6],"id":1}"#;
+        let request: Request = serde_json::from_str(positional).unwrap();
+        assert_eq!(request.params, vec![Value::from(6)]);
+        assert_eq!(request.params[0], Value::from(6));
+        assert_eq!(request.params[1], Value::Null);
+        assert_eq!(serde_json::to_string(&request).unwrap(), positional);
+
+        let named = r#"{"method":"blockchain.estimatefee","params":{"number":6},"id":1}"#;
+        let request: Request = serde_json::from_str(named).unwrap();
+        match request.params {
+            Params::Named(ref params) => assert_eq!(params["number"], Value::from(6)),
+            ref other => panic!("unexpected params {:?}", other),
+        }
+        assert_eq!(request.params[0], Value::Null);
+        assert_eq!(serde_json::to_string(&request).unwrap(), named);
+
+        let request: Request = serde_json::from_str(r#"{"method":"server.ping","id":1}"#).unwrap();
+        assert!(request.params.is_empty());
+    }
+
+    #[test]
+    fn response_jsonrpc_round_trip() {
+        let v1 = r#"{"result":true,"error":null,"id":1}"#;
+        let response: Response = serde_json::from_str(v1).unwrap();
+        assert_eq!(response.jsonrpc, None);
+        assert_eq!(serde_json::to_string(&response).unwrap(), v1);
+
+        let v2 = r#"{"result":true,"error":null,"id":1,"jsonrpc":"2.0"}"#;
+        let response: Response = serde_json::from_str(v2).unwrap();
+        assert_eq!(response.jsonrpc, Some("2.0".to_owned()));
+        assert_eq!(serde_json::to_string(&response).unwrap(), v2);
+    }
+
     #[test]
     fn response_is_none() {
         let joanna = Response {
             result: Some(Value::Bool(true)),
             error: None,
             id: From::from(81),
+            jsonrpc: None,
         };
 
         let bill = Response {
             result: None,
             error: None,
             id: From::from(66),
+            jsonrpc: None,
         };
 
         assert!(!joanna.is_none());
@@ -0,0 +1,42 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Metrics
+//!
+//! Per-request metrics, pushed to a user-provided sink as requests complete
+//!
+
+use std::time::Duration;
+
+/// A metric describing one completed request
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestMetric {
+    /// The RPC method called
+    pub method: String,
+    /// How long the request took, including reading the response
+    pub duration: Duration,
+    /// Whether the request got a response without an error
+    pub success: bool,
+}
+
+/// Receives a metric for each request a client sends
+///
+/// `record` is called on the thread that sent the request, before the
+/// response is returned to the caller, so it should be cheap and must not
+/// block; hand the metric off to a channel or an atomic counter rather than
+/// doing IO.
+pub trait MetricsSink: Send + Sync {
+    /// Records a metric for a completed request
+    fn record(&self, metric: &RequestMetric);
+}
@@ -0,0 +1,158 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Retries
+//!
+//! Retry policies, which say how a client resends a request whose
+//! connection dropped, and retry budgets, a cap on the retries made across
+//! a whole multi-request operation, so that a flaky server bounds how long
+//! the operation can take in total rather than per request
+//!
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use hyper;
+
+use error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How a client resends a request whose connection dropped
+///
+/// Only failures of the connection itself are retried, never a response
+/// from the server, which would execute the request twice, nor a timeout.
+/// The default policy retries once, straight away, which covers a
+/// connection the server closed while it sat idle.
+pub struct RetryPolicy {
+    /// How many times a request is resent
+    pub max_retries: u32,
+    /// How long to wait before each resend
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Creates a policy resending up to `max_retries` times, waiting
+    /// `backoff` before each
+    pub fn new(max_retries: u32, backoff: Duration) -> RetryPolicy {
+        RetryPolicy { max_retries, backoff }
+    }
+
+    /// A policy which never resends
+    pub fn never() -> RetryPolicy {
+        RetryPolicy::new(0, Duration::from_secs(0))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(1, Duration::from_secs(0))
+    }
+}
+
+/// Whether `error` means the connection is gone, rather than that the
+/// request failed on it
+pub(crate) fn is_connection_error(error: &Error) -> bool {
+    let e = match *error {
+        Error::Io(ref e) | Error::Hyper(hyper::Error::Io(ref e)) => e,
+        _ => return false,
+    };
+    matches!(e.kind(),
+             io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset |
+             io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe |
+             io::ErrorKind::NotConnected)
+}
+
+/// A number of retries shared by every request of an operation
+///
+/// Helpers taking a budget retry a request which fails at the transport
+/// level, such as a dropped connection or a timeout, taking one retry from
+/// the budget each time. Once the budget is spent, the next such failure
+/// aborts the operation with `Error::RetryBudgetExhausted`. Error responses
+/// from the server are never retried, since the request was executed.
+pub struct RetryBudget {
+    remaining: AtomicUsize,
+}
+
+impl RetryBudget {
+    /// Creates a budget allowing `retries` retries in total
+    pub fn new(retries: usize) -> RetryBudget {
+        RetryBudget { remaining: AtomicUsize::new(retries) }
+    }
+
+    /// The number of retries left
+    pub fn remaining(&self) -> usize {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    /// Takes a retry from the budget after a request failed with `error`,
+    /// giving `error` back if it is not worth retrying, or
+    /// `Error::RetryBudgetExhausted` wrapping it if the budget is spent
+    pub fn retry(&self, error: Error) -> Result<(), Error> {
+        if !is_transient(&error) {
+            return Err(error);
+        }
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .map(|_| ())
+            .map_err(|_| Error::RetryBudgetExhausted(Box::new(error)))
+    }
+}
+
+/// Whether a request failing with `error` might succeed if sent again, to
+/// the same server or, on failover, another
+pub(crate) fn is_transient(error: &Error) -> bool {
+    matches!(*error,
+             Error::Io(_) | Error::Hyper(hyper::Error::Io(_)) | Error::Timeout |
+             Error::FirstByteTimeout | Error::ReadTimeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use error::Error;
+    use super::RetryBudget;
+
+    fn dropped() -> Error {
+        Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset"))
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(2);
+        assert!(budget.retry(dropped()).is_ok());
+        assert!(budget.retry(Error::Timeout).is_ok());
+        assert_eq!(budget.remaining(), 0);
+        match budget.retry(dropped()) {
+            Err(Error::RetryBudgetExhausted(ref e)) => {
+                match **e {
+                    Error::Io(_) => {}
+                    ref other => panic!("unexpected cause {:?}", other),
+                }
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retry_budget_ignores_server_errors() {
+        let budget = RetryBudget::new(2);
+        match budget.retry(Error::NoErrorOrResult) {
+            Err(Error::NoErrorOrResult) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(budget.remaining(), 2);
+    }
+}
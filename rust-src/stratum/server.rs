@@ -0,0 +1,174 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Server support
+//!
+//! Support for dispatching incoming requests, singly or in batches, to
+//! handlers registered by method name
+//!
+
+use std::collections::HashMap;
+
+use serde_json;
+use serde_json::value::Value;
+
+use {Params, Request, Response};
+use error::{Error, RpcError, StandardError, result_to_response, standard_error};
+
+/// A handler for a single RPC method, called with the request parameters
+pub type Handler = Box<dyn Fn(Vec<Value>) -> Result<Value, RpcError> + Send + Sync>;
+
+/// Routes requests to handlers by method name
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Dispatcher {
+    /// Creates a new dispatcher with no handlers
+    pub fn new() -> Dispatcher {
+        Dispatcher { handlers: HashMap::new() }
+    }
+
+    /// Registers a handler for `method`, replacing any previous one
+    pub fn register<F>(&mut self, method: &str, handler: F)
+        where F: Fn(Vec<Value>) -> Result<Value, RpcError> + Send + Sync + 'static
+    {
+        self.handlers.insert(method.to_owned(), Box::new(handler));
+    }
+
+    /// Invokes the handler for a request, answering `MethodNotFound` if
+    /// there is none, and `InvalidParams` for named parameters, since
+    /// handlers take theirs by position
+    pub fn handle(&self, request: Request) -> Response {
+        let result = match (self.handlers.get(&request.method), request.params) {
+            (Some(handler), Params::Positional(params)) => handler(params),
+            (Some(_), Params::Named(_)) => {
+                let data = Value::String("named parameters are not supported".to_owned());
+                Err(standard_error(StandardError::InvalidParams, Some(data)))
+            }
+            (None, _) => Err(standard_error(StandardError::MethodNotFound, None)),
+        };
+        result_to_response(result, request.id)
+    }
+
+    /// Handles a batch of requests, returning the responses in request order
+    pub fn handle_batch(&self, requests: Vec<Request>) -> Vec<Response> {
+        requests.into_iter().map(|r| self.handle(r)).collect()
+    }
+
+    /// Handles a JSON-encoded request or batch of requests
+    ///
+    /// A JSON object is treated as a single request and a JSON array as a
+    /// batch, whose responses are returned as an array in the same order.
+    /// An empty batch gives an empty array.
+    pub fn handle_json(&self, json: &str) -> Result<String, Error> {
+        let value: Value = match serde_json::from_str(json) {
+            Ok(value) => value,
+            Err(_) => {
+                let response = result_to_response(Err(standard_error(StandardError::ParseError,
+                                                                     None)),
+                                                  Value::Null);
+                return Ok(serde_json::to_string(&response)?);
+            }
+        };
+
+        match value {
+            Value::Array(values) => {
+                let responses: Vec<Response> =
+                    values.into_iter().map(|v| self.handle_value(v)).collect();
+                Ok(serde_json::to_string(&responses)?)
+            }
+            value => Ok(serde_json::to_string(&self.handle_value(value))?),
+        }
+    }
+
+    fn handle_value(&self, value: Value) -> Response {
+        match serde_json::value::from_value::<Request>(value) {
+            Ok(request) => self.handle(request),
+            Err(_) => {
+                result_to_response(Err(standard_error(StandardError::InvalidRequest, None)),
+                                   Value::Null)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use serde_json::Value;
+
+    use Response;
+    use super::Dispatcher;
+
+    fn dispatcher() -> Dispatcher {
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register("server.ping", |_| Ok(Value::Null));
+        dispatcher.register("echo", |params| Ok(Value::Array(params)));
+        dispatcher
+    }
+
+    #[test]
+    fn test_single() {
+        let json = r#"{"method":"echo","params":[1],"id":7}"#;
+        let reply = dispatcher().handle_json(json).unwrap();
+        let response: Response = serde_json::from_str(&reply).unwrap();
+        assert_eq!(response.id, Value::Number(From::from(7)));
+        assert_eq!(response.result,
+                   Some(Value::Array(vec![Value::Number(From::from(1))])));
+    }
+
+    #[test]
+    fn test_named_params() {
+        let json = r#"{"method":"echo","params":{"value":1},"id":7}"#;
+        let reply = dispatcher().handle_json(json).unwrap();
+        let response: Response = serde_json::from_str(&reply).unwrap();
+        assert_eq!(response.error.unwrap().code, -32602);
+    }
+
+    #[test]
+    fn test_batch() {
+        let json = r#"[{"method":"server.ping","params":[],"id":1},
+                       {"method":"server.nonsense","params":[],"id":2}]"#;
+        let reply = dispatcher().handle_json(json).unwrap();
+        let responses: Vec<Response> = serde_json::from_str(&reply).unwrap();
+        assert_eq!(responses.len(), 2);
+
+        assert_eq!(responses[0].id, Value::Number(From::from(1)));
+        assert!(responses[0].error.is_none());
+
+        assert_eq!(responses[1].id, Value::Number(From::from(2)));
+        assert_eq!(responses[1].error.as_ref().unwrap().code, -32601);
+    }
+
+    #[test]
+    fn test_empty_batch() {
+        assert_eq!(dispatcher().handle_json("[]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let reply = dispatcher().handle_json("{").unwrap();
+        let response: Response = serde_json::from_str(&reply).unwrap();
+        assert_eq!(response.error.unwrap().code, -32700);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let reply = dispatcher().handle_json(r#"[{"params":[]}]"#).unwrap();
+        let responses: Vec<Response> = serde_json::from_str(&reply).unwrap();
+        assert_eq!(responses[0].error.as_ref().unwrap().code, -32600);
+    }
+}
@@ -0,0 +1,300 @@
+// Rust Stratum Library
+// Written in 2017 by
+//   The Rust Stratum developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Test utilities
+//!
+//! Minimal HTTP and line-delimited TCP servers which answer JSONRPC
+//! requests from a handler, used to exercise the clients without a real
+//! Electrum server
+//!
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use serde_json;
+use serde_json::value::Value;
+
+use {Request, Response};
+use client::Client;
+use error::RpcError;
+
+/// A successful response
+pub fn reply(id: Value, result: Value) -> Response {
+    Response {
+        result: Some(result),
+        error: None,
+        id,
+        jsonrpc: None,
+    }
+}
+
+/// An error response
+pub fn reply_error(id: Value, code: i32, message: &str) -> Response {
+    Response {
+        result: None,
+        error: Some(RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        }),
+        id,
+        jsonrpc: None,
+    }
+}
+
+/// A local HTTP server answering each request, including each request in a
+/// batch, with its handler
+pub struct MockServer {
+    url: String,
+    hits: Arc<AtomicUsize>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    /// Starts a server on an ephemeral port
+    pub fn new<F>(handler: F) -> MockServer
+        where F: Fn(Request) -> Response + Send + Sync + 'static
+    {
+        MockServer::start(move |request| Some(handler(request)), true)
+    }
+
+    /// Starts a server whose handler may drop the connection instead of
+    /// answering, by returning `None`
+    ///
+    /// Connections are closed after each response, so that a dropped
+    /// connection is never one the client expected to reuse.
+    pub fn flaky<F>(handler: F) -> MockServer
+        where F: Fn(Request) -> Option<Response> + Send + Sync + 'static
+    {
+        MockServer::start(handler, false)
+    }
+
+    fn start<F>(handler: F, keep_alive: bool) -> MockServer
+        where F: Fn(Request) -> Option<Response> + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let handler = Arc::new(handler);
+
+        let server_hits = hits.clone();
+        let server_connections = connections.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                server_connections.fetch_add(1, Ordering::SeqCst);
+                let handler = handler.clone();
+                let hits = server_hits.clone();
+                thread::spawn(move || serve(stream, &*handler, &hits, keep_alive));
+            }
+        });
+
+        MockServer {
+            url,
+            hits,
+            connections,
+        }
+    }
+
+    /// The URL the server is listening on
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// A client pointed at this server
+    pub fn client(&self) -> Client {
+        Client::new(self.url(), None, None)
+    }
+
+    /// The number of requests the server has answered
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections the server has accepted
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Starts a server on an ephemeral port which reads each HTTP request and
+/// then hands the connection to `handler` to write whatever it likes
+pub fn raw_server<F>(handler: F) -> String
+    where F: Fn(TcpStream) + Send + Sync + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => break,
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                if read_body(&mut reader).is_some() {
+                    handler(stream);
+                }
+            });
+        }
+    });
+    url
+}
+
+/// Starts a server on an ephemeral port speaking newline-delimited JSON,
+/// answering each request line with its handler, and returns its address
+pub fn line_server<F>(handler: F) -> String
+    where F: Fn(Request) -> Response + Send + Sync + 'static
+{
+    tcp_server(move |stream| {
+        let mut writer = stream.try_clone().unwrap();
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let request: Request = serde_json::from_str(&line).unwrap();
+            let response = serde_json::to_string(&handler(request)).unwrap();
+            if writeln!(writer, "{}", response).is_err() {
+                return;
+            }
+        }
+    })
+}
+
+/// Starts a server on an ephemeral port which hands each connection to
+/// `handler`, and returns its address
+pub fn tcp_server<F>(handler: F) -> String
+    where F: Fn(TcpStream) + Send + Sync + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => break,
+            };
+            let handler = handler.clone();
+            thread::spawn(move || handler(stream));
+        }
+    });
+    addr
+}
+
+/// Starts a SOCKS5 proxy on an ephemeral port which connects to whatever
+/// address it is asked for and relays between the two connections
+pub fn socks5_proxy() -> SocketAddr {
+    let addr = tcp_server(|mut stream| {
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        stream.write_all(&[5, 0]).unwrap();
+
+        let mut request = [0; 5];
+        stream.read_exact(&mut request).unwrap();
+        let mut target = vec![0; request[4] as usize + 2];
+        stream.read_exact(&mut target).unwrap();
+        let (host, port) = target.split_at(target.len() - 2);
+        let host = String::from_utf8(host.to_vec()).unwrap();
+        let port = u16::from(port[0]) << 8 | u16::from(port[1]);
+
+        let mut upstream = match TcpStream::connect((host.as_str(), port)) {
+            Ok(upstream) => upstream,
+            Err(_) => {
+                // Connection refused
+                let _ = stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+                return;
+            }
+        };
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        let mut downstream = stream.try_clone().unwrap();
+        let mut upstream_reader = upstream.try_clone().unwrap();
+        thread::spawn(move || io::copy(&mut upstream_reader, &mut downstream));
+        let _ = io::copy(&mut stream, &mut upstream);
+    });
+    addr.parse().unwrap()
+}
+
+fn serve<F>(stream: TcpStream, handler: &F, hits: &AtomicUsize, keep_alive: bool)
+    where F: Fn(Request) -> Option<Response>
+{
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    loop {
+        let body = match read_body(&mut reader) {
+            Some(body) => body,
+            None => return,
+        };
+        // A batch is answered request by request, in order
+        let response = match serde_json::from_slice::<Vec<Request>>(&body) {
+            Ok(batch) => {
+                match batch.into_iter().map(handler).collect::<Option<Vec<Response>>>() {
+                    Some(responses) => serde_json::to_string(&responses).unwrap(),
+                    None => return,
+                }
+            }
+            Err(_) => {
+                let request: Request = serde_json::from_slice(&body).unwrap();
+                match handler(request) {
+                    Some(response) => serde_json::to_string(&response).unwrap(),
+                    None => return,
+                }
+            }
+        };
+        hits.fetch_add(1, Ordering::SeqCst);
+        let written = write!(writer,
+                             "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                              Connection: {}\r\nContent-Length: {}\r\n\r\n{}",
+                             if keep_alive { "keep-alive" } else { "close" },
+                             response.len(),
+                             response);
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+fn read_body<R: BufRead>(reader: &mut R) -> Option<Vec<u8>> {
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = parts.next().unwrap_or("").trim().parse().unwrap_or(0);
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).ok()?;
+    Some(body)
+}